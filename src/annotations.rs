@@ -0,0 +1,88 @@
+// Highlighted passages of the book with optional notes, and their
+// export for the `annotations export` subcommand.
+
+use serde::{
+    Serialize, Deserialize
+};
+use crate::{
+    BookState, meta, scan
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Annotation {
+    // The highlighted text is [start, end).
+    pub start: BookState,
+    pub end: BookState,
+    #[serde(default)]
+    pub text_offset: usize,
+    // The highlighted text, so that we don't have to parse the book
+    // to show it.
+    pub text: String,
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+// Add the annotation keeping the list sorted by the position.
+pub fn add(list: &mut Vec<Annotation>, a: Annotation) {
+    let i = list.partition_point(|x| x.start < a.start
+                                 || (x.start == a.start && x.end < a.end));
+    list.insert(i, a);
+}
+
+// One annotation as it is exported.
+#[derive(Serialize)]
+struct Exported<'a> {
+    chapter: &'a str,
+    percent: usize,
+    position: String,
+    text: &'a str,
+    note: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct ExportedBook<'a> {
+    author: String,
+    title: &'a str,
+    annotations: Vec<Exported<'a>>,
+}
+
+fn exported<'a>(scan: &'a scan::BookScan, list: &'a [Annotation]) -> Vec<Exported<'a>> {
+    list.iter().map(|a| Exported {
+        chapter: scan.chapter_at(a.start).map_or("", |c| &c.title),
+        percent: scan.percent(a.text_offset),
+        position: format!("{}.{}.{}", a.start.tag_count,
+                          a.start.word_offset, a.start.char_offset),
+        text: &a.text,
+        note: a.note.as_deref(),
+    }).collect()
+}
+
+pub fn to_json(desc: &meta::Description, scan: &scan::BookScan,
+               list: &[Annotation]) -> anyhow::Result<String> {
+    let b = ExportedBook { author: desc.authors.join(", "),
+                           title: desc.title.as_deref().unwrap_or(""),
+                           annotations: exported(scan, list) };
+    Ok(serde_json::to_string_pretty(&b)?)
+}
+
+// The annotations grouped by chapters, each one is a quote followed
+// by the note.
+pub fn to_markdown(desc: &meta::Description, scan: &scan::BookScan,
+                   list: &[Annotation]) -> String {
+    let mut s = format!("# {}\n", desc.short_name());
+    let mut chapter = None;
+    for a in exported(scan, list) {
+        if chapter != Some(a.chapter) {
+            if !a.chapter.is_empty() {
+                s.push_str(&format!("\n## {}\n", a.chapter));
+            }
+            chapter = Some(a.chapter);
+        }
+        s.push_str(&format!("\n> {}\n", a.text));
+        s.push_str(&format!("\n*{}%, {}*\n", a.percent, a.position));
+        if let Some(n) = a.note {
+            s.push_str(&format!("\n{}\n", n));
+        }
+    }
+    s
+}
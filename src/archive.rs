@@ -0,0 +1,198 @@
+// Books in zip archives and compressed files.  Most of the books are
+// distributed as `.fb2.zip`, but an archive may as well contain several
+// books, or a book together with a readme.  A particular entry of the
+// archive can be given as `archive.zip:path/inside.fb2`.  Collections
+// of books often keep them as `.fb2.gz`, `.fb2.bz2`, `.fb2.xz` or
+// `.fb2.zst`.
+
+use std::{
+    io::{
+        Read, Seek
+    }, path::{
+        Path, PathBuf
+    }
+};
+use anyhow::Context;
+
+// Extensions of the files that we can read, the first one is preferred.
+const BOOKS: &[&str] = &["fb2", "fb3", "txt", "md", "markdown", "html", "htm", "xhtml"];
+
+// Zip files start with the header of the first entry.
+pub fn is_zip(data: &[u8]) -> bool {
+    data.starts_with(b"PK\x03\x04")
+}
+
+// Whether the zip is an OPC package (i.e. an FB3 book) rather than an
+// archive with books.
+pub fn is_package<R: Read + Seek>(za: &zip::ZipArchive<R>) -> bool {
+    za.file_names().any(|n| n == "_rels/.rels")
+}
+
+fn extension(name: &str) -> Option<String> {
+    Path::new(name).extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase())
+}
+
+// The entries of the archive that are books: FB2 files if there are
+// any, otherwise the files in the other formats that we can read.
+pub fn books<R: Read + Seek>(za: &zip::ZipArchive<R>) -> Vec<String> {
+    let mut names: Vec<(usize, String)> = za.file_names().filter_map(|n| {
+        let e = extension(&uncompressed_name(Path::new(n)).to_string_lossy());
+        let i = BOOKS.iter().position(|b| e.as_deref() == Some(*b))?;
+        Some((i.min(1), n.to_string()))
+    }).collect();
+    names.sort();
+    let best = names.first().map_or(0, |(i, _)| *i);
+    names.into_iter().filter(|(i, _)| *i == best).map(|(_, n)| n).collect()
+}
+
+// Whether the file with this name may be a book or an archive with
+// books, judging by the extension.
+pub fn may_have_books(name: &Path) -> bool {
+    let e = extension(&uncompressed_name(name).to_string_lossy());
+    e.as_deref() == Some("zip") || BOOKS.iter().any(|b| e.as_deref() == Some(*b))
+}
+
+// The name of the file without the extension of the compressed file,
+// e.g. `book.fb2` for `book.fb2.gz`, so that we know the format of
+// the book.
+pub fn uncompressed_name(name: &Path) -> PathBuf {
+    match name.extension().and_then(|e| e.to_str()) {
+        Some(e) if ["gz", "bz2", "xz", "zst"].contains(&e.to_lowercase().as_str()) => {
+            name.with_extension("")
+        }
+        _ => name.to_path_buf(),
+    }
+}
+
+// Decompress the data if it is compressed with gzip, bzip2, xz or zstd,
+// which we recognise by the magic bytes at the start.  Returns None if
+// the data is not compressed.
+pub fn decompress(data: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut out = Vec::new();
+    if data.starts_with(&[0x1f, 0x8b]) {
+        flate2::read::MultiGzDecoder::new(data).read_to_end(&mut out)
+            .context("cannot decompress gzip data")?;
+    } else if data.starts_with(b"BZh") && data.get(3).is_some_and(|b| (b'1'..=b'9').contains(b)) {
+        bzip2::read::MultiBzDecoder::new(data).read_to_end(&mut out)
+            .context("cannot decompress bzip2 data")?;
+    } else if data.starts_with(b"\xfd7zXZ\x00") {
+        lzma_rs::xz_decompress(&mut &data[..], &mut out)
+            .context("cannot decompress xz data")?;
+    } else if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        ruzstd::StreamingDecoder::new(data)
+            .context("cannot decompress zstd data")?
+            .read_to_end(&mut out).context("cannot decompress zstd data")?;
+    } else {
+        return Ok(None);
+    }
+    Ok(Some(out))
+}
+
+pub fn read<R: Read + Seek>(za: &mut zip::ZipArchive<R>, name: &str) -> anyhow::Result<Vec<u8>> {
+    let mut f = za.by_name(name)
+                  .with_context(|| format!("cannot find `{}' in the archive", name))?;
+    let mut data = Vec::new();
+    f.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+// Split `archive.zip:path/inside.fb2` into the archive and the entry.
+// Paths of the existing files are never split, so that the names with
+// colons work.
+pub fn split_path(input: &str) -> (&str, Option<&str>) {
+    if Path::new(input).exists() {
+        return (input, None);
+    }
+    match input.rsplit_once(':') {
+        Some((a, e)) if !e.is_empty() && (a == "-" || Path::new(a).is_file()) => (a, Some(e)),
+        _ => (input, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{
+        Cursor, Write
+    };
+
+    fn zip(names: &[&str]) -> zip::ZipArchive<Cursor<Vec<u8>>> {
+        let mut w = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for n in names {
+            w.start_file(*n, zip::write::FileOptions::default()).unwrap();
+            w.write_all(b"<FictionBook/>").unwrap();
+        }
+        zip::ZipArchive::new(w.finish().unwrap()).unwrap()
+    }
+
+    // FB2 books are preferred to the other formats, and the files that
+    // are not books are left out.
+    #[test]
+    fn entries() {
+        let za = zip(&["readme.txt", "b/book.fb2", "a.fb2.gz", "cover.jpg"]);
+        assert_eq!(books(&za), ["a.fb2.gz", "b/book.fb2"]);
+        let za = zip(&["notes.md", "book.txt", "cover.jpg"]);
+        assert_eq!(books(&za), ["book.txt", "notes.md"]);
+        assert!(books(&zip(&["cover.jpg"])).is_empty());
+        assert!(!is_package(&za));
+        assert!(is_package(&zip(&["_rels/.rels", "fb3/body.xml"])));
+    }
+
+    #[test]
+    fn entry_paths() {
+        let dir = std::env::temp_dir().join(format!("termbook-archive-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let a = dir.join("books.zip");
+        std::fs::write(&a, b"").unwrap();
+        let a = a.to_str().unwrap();
+        assert_eq!(split_path(a), (a, None));
+        let p = format!("{}:fb2/sub/book.fb2", a);
+        assert_eq!(split_path(&p), (a, Some("fb2/sub/book.fb2")));
+        let p = format!("{}:", a);
+        assert_eq!(split_path(&p), (p.as_str(), None));
+        // Not an archive that exists, so the colon is a part of the name.
+        let p = format!("{}/missing.zip:book.fb2", dir.display());
+        assert_eq!(split_path(&p), (p.as_str(), None));
+        assert_eq!(split_path("-:book.fb2"), ("-", Some("book.fb2")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    const BOOK: &[u8] = "<FictionBook><body><p>Книга.</p></body></FictionBook>".as_bytes();
+
+    // A zstd frame with the data in a single raw block, as ruzstd can
+    // only decode.
+    fn zstd_raw(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![0x28, 0xb5, 0x2f, 0xfd];
+        // Single segment, the size of the content in one byte.
+        out.extend([0x20, data.len() as u8]);
+        // The last block, raw, and its size.
+        let header = 1 | (data.len() << 3);
+        out.extend(&header.to_le_bytes()[..3]);
+        out.extend(data);
+        out
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(BOOK).unwrap();
+        let mut bz = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        bz.write_all(BOOK).unwrap();
+        let mut xz = Vec::new();
+        lzma_rs::xz_compress(&mut &BOOK[..], &mut xz).unwrap();
+        for c in [gz.finish().unwrap(), bz.finish().unwrap(), xz, zstd_raw(BOOK)] {
+            assert_eq!(decompress(&c).unwrap().as_deref(), Some(BOOK));
+        }
+        assert_eq!(decompress(BOOK).unwrap(), None);
+        // Broken data with the magic bytes is an error.
+        assert!(decompress(&[0x1f, 0x8b, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(uncompressed_name(Path::new("a/book.fb2.GZ")), Path::new("a/book.fb2"));
+        assert_eq!(uncompressed_name(Path::new("book.fb2.zip")), Path::new("book.fb2.zip"));
+        assert!(may_have_books(Path::new("book.fb2.zst")));
+        assert!(!may_have_books(Path::new("cover.jpg.gz")));
+    }
+}
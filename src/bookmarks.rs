@@ -0,0 +1,48 @@
+// Bookmarks and vi-style marks.  Both are kept per book in the
+// settings file and use the same positions as the saved reading
+// position, so they don't depend on the width of the terminal.
+
+use serde::{
+    Serialize, Deserialize
+};
+use std::collections::BTreeMap;
+use crate::BookState;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Bookmark {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub pos: BookState,
+    // We keep the text offset to show the percentage in the list.
+    #[serde(default)]
+    pub text_offset: usize,
+    // The beginning of the text at the bookmark.
+    #[serde(default)]
+    pub snippet: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct BookMarks {
+    // Sorted by the position.
+    #[serde(default)]
+    pub bookmarks: Vec<Bookmark>,
+    // Marks set with `m{a-z}`.
+    #[serde(default)]
+    pub marks: BTreeMap<char, BookState>,
+}
+
+impl BookMarks {
+    pub fn is_empty(&self) -> bool {
+        self.bookmarks.is_empty() && self.marks.is_empty()
+    }
+
+    // Add the bookmark keeping the list sorted.  A bookmark at the
+    // same position is replaced.
+    pub fn add(&mut self, b: Bookmark) {
+        match self.bookmarks.binary_search_by(|x| x.pos.partial_cmp(&b.pos)
+                                                     .unwrap_or(std::cmp::Ordering::Less)) {
+            Ok(i) => self.bookmarks[i] = b,
+            Err(i) => self.bookmarks.insert(i, b),
+        }
+    }
+}
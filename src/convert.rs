@@ -0,0 +1,1083 @@
+// Converting books into other formats.  The plain text is laid out the
+// same way the reader shows it (see `convert_cmd`), while Markdown, HTML
+// and EPUB keep the structure of the book: we read the FB2 into a tree
+// and write the elements out in the other format.  The notes become
+// endnotes, and the pictures from `<binary>` are written next to the
+// text or put into the EPUB container.
+
+use std::{
+    collections::{
+        HashMap, HashSet
+    }, io::Write, path::{
+        Path, PathBuf
+    }
+};
+use anyhow::Context;
+use quick_xml::{
+    Reader, events::{
+        BytesStart, Event
+    }
+};
+use crate::{
+    fb2::escape, meta
+};
+
+struct Element {
+    name: String,
+    attrs: Vec<(String, String)>,
+    children: Vec<Node>,
+}
+
+enum Node {
+    Element(Element),
+    Text(String),
+}
+
+impl Element {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+    }
+
+    fn id(&self) -> Option<&str> {
+        self.attr("id").filter(|id| !id.is_empty())
+    }
+
+    fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|n| match n {
+            Node::Element(e) => Some(e),
+            Node::Text(_) => None,
+        })
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.elements().find(|e| e.name == name)
+    }
+
+    // All the text in the element, e.g. the lines of the title, with
+    // the whitespaces collapsed.
+    fn text(&self) -> String {
+        fn collect(e: &Element, s: &mut String) {
+            for n in &e.children {
+                match n {
+                    Node::Text(t) => s.push_str(t),
+                    Node::Element(e) => {
+                        s.push(' ');
+                        collect(e, s);
+                    }
+                }
+            }
+        }
+        let mut s = String::new();
+        collect(self, &mut s);
+        s.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    // The text as is, for the preformatted blocks.
+    fn text_raw(&self) -> String {
+        let mut s = String::new();
+        for n in &self.children {
+            match n {
+                Node::Text(t) => s.push_str(t),
+                Node::Element(e) => s.push_str(&e.text_raw()),
+            }
+        }
+        s
+    }
+
+    // The target of the link or the image.
+    fn href(&self) -> Option<&str> {
+        self.attr("href")
+    }
+}
+
+// The name without the namespace prefix, so that `l:href` and
+// `xlink:href` are both `href`.
+fn local(name: &[u8]) -> String {
+    let n = name.rsplit(|&c| c == b':').next().unwrap_or(name);
+    String::from_utf8_lossy(n).into_owned()
+}
+
+fn element<B: std::io::BufRead>(reader: &Reader<B>, e: &BytesStart) -> Element {
+    Element {
+        name: local(e.name()),
+        attrs: e.attributes().flatten()
+                .filter_map(|a| Some((local(a.key), a.unescape_and_decode_value(reader).ok()?)))
+                .collect(),
+        children: Vec::new(),
+    }
+}
+
+// Read the whole document into a tree and return `<FictionBook>`.
+fn parse(data: &[u8]) -> anyhow::Result<Element> {
+    let mut reader = Reader::from_reader(data);
+    let mut buf = Vec::new();
+    let mut stack = vec![Element { name: String::new(), attrs: Vec::new(), children: Vec::new() }];
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) => stack.push(element(&reader, e)),
+            Ok(Event::Empty(ref e)) => {
+                let e = element(&reader, e);
+                if let Some(p) = stack.last_mut() {
+                    p.children.push(Node::Element(e));
+                }
+            }
+            Ok(Event::End(_)) if stack.len() > 1 => {
+                if let Some(e) = stack.pop() {
+                    if let Some(p) = stack.last_mut() {
+                        p.children.push(Node::Element(e));
+                    }
+                }
+            }
+            Ok(Event::Text(e)) | Ok(Event::CData(e)) => {
+                let t = e.unescape_and_decode(&reader)?;
+                if let Some(p) = stack.last_mut() {
+                    p.children.push(Node::Text(t));
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                return Err(anyhow::anyhow!("Error at position {}: {:?}",
+                           reader.buffer_position(), e))
+            }
+            _ => (),
+        }
+        buf.clear();
+    }
+    let mut root = stack.swap_remove(0);
+    let i = root.children.iter().position(|n| matches!(n, Node::Element(e) if e.name == "FictionBook"))
+                .ok_or_else(|| anyhow::anyhow!("the book has no `<FictionBook>' element"))?;
+    match root.children.swap_remove(i) {
+        Node::Element(e) => Ok(e),
+        Node::Text(_) => unreachable!(),
+    }
+}
+
+struct Image {
+    // The name of the file, based on the id of the `<binary>`.
+    file: String,
+    media_type: String,
+    data: Vec<u8>,
+}
+
+// The book ready to be written out.
+struct Doc {
+    title: String,
+    authors: Vec<String>,
+    id: String,
+    lang: String,
+    // The id of the cover image.
+    cover: Option<String>,
+    // The main body, the rest of the bodies are notes.
+    body: Element,
+    notes: Vec<Element>,
+    // Images by the ids of the `<binary>` elements.
+    images: HashMap<String, Image>,
+    // Ids of the sections in the notes, the links to them are the
+    // references to the notes.
+    note_ids: HashSet<String>,
+}
+
+// The type of the image by its first bytes, for the binaries that
+// don't say it.
+fn image_type(data: &[u8]) -> &'static str {
+    if data.starts_with(b"\x89PNG") {
+        "image/png"
+    } else if data.starts_with(b"GIF8") {
+        "image/gif"
+    } else if data.starts_with(b"<") {
+        "image/svg+xml"
+    } else {
+        "image/jpeg"
+    }
+}
+
+// The name of the file for the image with the `id`, which we also use
+// in the URLs, so we keep it simple.
+fn image_file(id: &str, media_type: &str) -> String {
+    let mut f: String = id.chars().map(|c| if c.is_ascii_alphanumeric() || "._-".contains(c) {
+        c
+    } else {
+        '_'
+    }).collect();
+    if !f.contains('.') {
+        f.push_str(match media_type {
+            "image/png" => ".png",
+            "image/gif" => ".gif",
+            "image/svg+xml" => ".svg",
+            _ => ".jpg",
+        });
+    }
+    f
+}
+
+fn collect_ids(e: &Element, ids: &mut HashSet<String>) {
+    if let Some(id) = e.id() {
+        ids.insert(id.to_string());
+    }
+    for c in e.elements() {
+        collect_ids(c, ids);
+    }
+}
+
+// Give ids to the sections that don't have them, so that the table of
+// contents can point to every section.
+fn number_sections(e: &mut Element, ids: &mut HashSet<String>) {
+    for n in &mut e.children {
+        if let Node::Element(c) = n {
+            if c.name == "section" && c.id().is_none() {
+                let mut k = ids.len();
+                while ids.contains(&format!("section{}", k)) {
+                    k += 1;
+                }
+                let id = format!("section{}", k);
+                ids.insert(id.clone());
+                c.attrs.retain(|(k, _)| k != "id");
+                c.attrs.push((String::from("id"), id));
+            }
+            number_sections(c, ids);
+        }
+    }
+}
+
+impl Doc {
+    fn load(data: &[u8]) -> anyhow::Result<Doc> {
+        let desc = meta::parse_description(data)?;
+        let id = meta::book_id(data, &desc);
+        let mut fb = parse(data)?;
+
+        let mut images = HashMap::new();
+        let mut files = HashSet::new();
+        for b in fb.elements().filter(|e| e.name == "binary") {
+            let id = match b.id() {
+                Some(id) => id,
+                None => continue,
+            };
+            let text: String = b.text().split_whitespace().collect();
+            // A broken picture is not a reason to give up on the book.
+            let data = match base64::decode(&text) {
+                Ok(d) => d,
+                Err(_) => continue,
+            };
+            let media_type = b.attr("content-type").filter(|t| t.starts_with("image/"))
+                              .unwrap_or_else(|| image_type(&data)).to_string();
+            let mut file = image_file(id, &media_type);
+            while !files.insert(file.clone()) {
+                file = format!("_{}", file);
+            }
+            images.insert(id.to_string(), Image { file, media_type, data });
+        }
+
+        let mut ids = HashSet::new();
+        collect_ids(&fb, &mut ids);
+        number_sections(&mut fb, &mut ids);
+        let mut bodies = fb.children.into_iter().filter_map(|n| match n {
+            Node::Element(e) if e.name == "body" => Some(e),
+            _ => None,
+        });
+        let body = bodies.next().ok_or_else(|| anyhow::anyhow!("the book has no `<body>'"))?;
+        let notes: Vec<Element> = bodies.collect();
+        let mut note_ids = HashSet::new();
+        for b in &notes {
+            for s in b.elements().filter(|e| e.name == "section") {
+                collect_ids(s, &mut note_ids);
+            }
+        }
+
+        Ok(Doc {
+            title: desc.title.clone().unwrap_or_default(),
+            lang: desc.lang.unwrap_or_else(|| String::from("und")),
+            cover: desc.cover.filter(|c| images.contains_key(c)),
+            authors: desc.authors, id,
+            body, notes, images, note_ids,
+        })
+    }
+
+    // Write the images into the directory `dir`.
+    fn write_images(&self, dir: &Path) -> anyhow::Result<()> {
+        if self.images.is_empty() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("cannot create directory `{}'", dir.display()))?;
+        for i in self.images.values() {
+            let p = dir.join(&i.file);
+            std::fs::write(&p, &i.data)
+                .with_context(|| format!("cannot write `{}'", p.display()))?;
+        }
+        Ok(())
+    }
+
+    // The URLs of the images relative to the text.
+    fn image_urls(&self, dir: &str) -> HashMap<String, String> {
+        self.images.iter().map(|(id, i)| (id.clone(), format!("{}/{}", dir, i.file))).collect()
+    }
+}
+
+// The directory with the images of the book converted to `out`, e.g.
+// `book_files` for `book.html`.
+fn images_dir(out: &Path) -> (PathBuf, String) {
+    let name = format!("{}_files", out.file_stem().map_or(String::from("book"),
+                                                          |s| s.to_string_lossy().into_owned()));
+    (out.with_file_name(&name), name)
+}
+
+// Collapse the runs of whitespaces into single spaces, keeping a space
+// at the ends if there was one.
+fn collapse(t: &str) -> String {
+    let mut s = String::new();
+    if t.starts_with(char::is_whitespace) {
+        s.push(' ');
+    }
+    s.push_str(&t.split_whitespace().collect::<Vec<_>>().join(" "));
+    if t.ends_with(char::is_whitespace) && !t.trim().is_empty() {
+        s.push(' ');
+    }
+    s
+}
+
+const CSS: &str = "\
+body { font-family: serif; line-height: 1.4; }
+p { margin: 0; text-indent: 1.5em; text-align: justify; }
+h1, h2, h3, h4, h5, h6, .title, .subtitle { text-align: center; text-indent: 0; }
+.title, .subtitle { font-weight: bold; margin: 1em 0; }
+blockquote { margin: 1em 2em; }
+.epigraph { margin: 1em 0 1em 40%; font-style: italic; }
+.text-author { text-align: right; font-style: italic; }
+.poem { margin: 1em 0 1em 2em; }
+.stanza { margin: 1em 0; }
+.v { text-indent: 0; text-align: left; }
+.image, .cover { text-align: center; text-indent: 0; }
+img { max-width: 100%; }
+.noteref { vertical-align: super; font-size: smaller; }
+.notes { margin-top: 2em; }
+pre { white-space: pre-wrap; }
+";
+
+// Writing the elements of the book as (X)HTML.
+struct Html<'a> {
+    doc: &'a Doc,
+    out: String,
+    images: HashMap<String, String>,
+    // Files of the EPUB with the ids, a single HTML file doesn't need
+    // them.
+    files: HashMap<String, String>,
+    // Mark the notes for the EPUB readers, so that they show them in
+    // popups.
+    epub: bool,
+}
+
+impl<'a> Html<'a> {
+    fn new(doc: &'a Doc, images: HashMap<String, String>) -> Html<'a> {
+        Html { doc, out: String::new(), images, files: HashMap::new(), epub: false }
+    }
+
+    fn href(&self, href: &str) -> String {
+        match href.strip_prefix('#') {
+            Some(id) => format!("{}#{}", self.files.get(id).map_or("", |f| f.as_str()), id),
+            None => href.to_string(),
+        }
+    }
+
+    // The opening tag with the id of the element, the `class` and the
+    // other `attrs` (e.g. `epub:type`).
+    fn open(&mut self, tag: &str, e: &Element, class: Option<&str>, attrs: &[(&str, &str)]) {
+        self.out.push('<');
+        self.out.push_str(tag);
+        if let Some(id) = e.id() {
+            self.out.push_str(&format!(" id=\"{}\"", escape(id)));
+        }
+        if let Some(c) = class {
+            self.out.push_str(&format!(" class=\"{}\"", c));
+        }
+        for (name, value) in attrs {
+            self.out.push_str(&format!(" {}=\"{}\"", name, escape(value)));
+        }
+        self.out.push('>');
+    }
+
+    fn image(&mut self, e: &Element) {
+        let id = e.href().unwrap_or("").trim_start_matches('#');
+        if let Some(src) = self.images.get(id) {
+            let alt = e.attr("alt").or_else(|| e.attr("title")).unwrap_or("");
+            self.out.push_str(&format!("<img src=\"{}\" alt=\"{}\"/>", escape(src), escape(alt)));
+        }
+    }
+
+    fn inline(&mut self, e: &Element) {
+        for n in &e.children {
+            let c = match n {
+                Node::Text(t) => {
+                    self.out.push_str(&escape(t));
+                    continue;
+                }
+                Node::Element(c) => c,
+            };
+            let tag = match c.name.as_str() {
+                "emphasis" => "em",
+                "strong" => "strong",
+                "strikethrough" => "del",
+                "sub" => "sub",
+                "sup" => "sup",
+                "code" => "code",
+                "style" => "span",
+                "image" => {
+                    self.image(c);
+                    continue;
+                }
+                "a" => {
+                    let href = c.href().unwrap_or("");
+                    let note = c.attr("type") == Some("note")
+                               || self.doc.note_ids.contains(href.trim_start_matches('#'));
+                    self.out.push_str(&format!("<a href=\"{}\"", escape(&self.href(href))));
+                    if note {
+                        self.out.push_str(" class=\"noteref\"");
+                        if self.epub {
+                            self.out.push_str(" epub:type=\"noteref\"");
+                        }
+                    }
+                    self.out.push('>');
+                    self.inline(c);
+                    self.out.push_str("</a>");
+                    continue;
+                }
+                _ => {
+                    self.inline(c);
+                    continue;
+                }
+            };
+            self.open(tag, c, None, &[]);
+            self.inline(c);
+            self.out.push_str(&format!("</{}>", tag));
+        }
+    }
+
+    // A paragraph-like element: `<tag class="class">inline</tag>`.
+    fn para(&mut self, tag: &str, e: &Element, class: Option<&str>) {
+        self.open(tag, e, class, &[]);
+        self.inline(e);
+        self.out.push_str(&format!("</{}>\n", tag));
+    }
+
+    fn blocks(&mut self, e: &Element, depth: usize) {
+        for c in e.elements() {
+            self.block(c, depth);
+        }
+    }
+
+    fn wrap(&mut self, tag: &str, e: &Element, class: Option<&str>, depth: usize) {
+        self.open(tag, e, class, &[]);
+        self.out.push('\n');
+        self.blocks(e, depth);
+        self.out.push_str(&format!("</{}>\n", tag));
+    }
+
+    // The block element of the body.  The `depth` is the level of the
+    // section we are in, the titles of the sections become headings of
+    // that level; it is zero in the poems, epigraphs and the like, where
+    // the titles are just bold paragraphs.
+    fn block(&mut self, e: &Element, depth: usize) {
+        match e.name.as_str() {
+            "section" => {
+                if self.epub && self.doc.note_ids.contains(e.id().unwrap_or("")) {
+                    self.open("section", e, Some("note"), &[("epub:type", "endnote")]);
+                } else {
+                    self.open("section", e, None, &[]);
+                }
+                self.out.push('\n');
+                for c in e.elements() {
+                    self.block(c, if c.name == "section" { depth + 1 } else { depth });
+                }
+                self.out.push_str("</section>\n");
+            }
+            "title" => {
+                let (tag, class) = if depth == 0 {
+                    (String::from("div"), Some("title"))
+                } else {
+                    (format!("h{}", depth.min(6)), None)
+                };
+                self.open(&tag, e, class, &[]);
+                for (i, p) in e.elements().filter(|p| p.name == "p").enumerate() {
+                    if i > 0 {
+                        self.out.push_str("<br/>");
+                    }
+                    self.inline(p);
+                }
+                self.out.push_str(&format!("</{}>\n", tag));
+            }
+            "p" => self.para("p", e, None),
+            "subtitle" => self.para("p", e, Some("subtitle")),
+            "text-author" => self.para("p", e, Some("text-author")),
+            "v" => self.para("p", e, Some("v")),
+            "epigraph" => self.wrap("blockquote", e, Some("epigraph"), 0),
+            "cite" => self.wrap("blockquote", e, None, 0),
+            "annotation" => self.wrap("div", e, Some("annotation"), 0),
+            "poem" => self.wrap("div", e, Some("poem"), 0),
+            "stanza" => self.wrap("div", e, Some("stanza"), 0),
+            "empty-line" => self.out.push_str("<br/>\n"),
+            "image" => {
+                self.open("div", e, Some("image"), &[]);
+                self.image(e);
+                self.out.push_str("</div>\n");
+            }
+            "pre" => {
+                self.open("pre", e, None, &[]);
+                self.out.push_str(&escape(&e.text_raw()));
+                self.out.push_str("</pre>\n");
+            }
+            "table" => {
+                self.open("table", e, None, &[]);
+                self.out.push('\n');
+                for r in e.elements() {
+                    self.open("tr", r, None, &[]);
+                    for c in r.elements() {
+                        let tag = if c.name == "th" { "th" } else { "td" };
+                        self.open(tag, c, None, &[]);
+                        self.inline(c);
+                        self.out.push_str(&format!("</{}>", tag));
+                    }
+                    self.out.push_str("</tr>\n");
+                }
+                self.out.push_str("</table>\n");
+            }
+            _ => self.blocks(e, depth),
+        }
+    }
+
+    // The bodies with the notes after the text of the book.
+    fn notes(&mut self) {
+        for b in &self.doc.notes {
+            let attrs: &[(&str, &str)] = if self.epub { &[("epub:type", "endnotes")] } else { &[] };
+            self.open("section", b, Some("notes"), attrs);
+            self.out.push('\n');
+            for c in b.elements() {
+                self.block(c, if c.name == "section" { 2 } else { 1 });
+            }
+            self.out.push_str("</section>\n");
+        }
+    }
+}
+
+// Write the book `data` (FB2) into the HTML file `out`, the images go
+// into the directory next to it.
+pub fn html(data: &[u8], out: &Path) -> anyhow::Result<()> {
+    let doc = Doc::load(data)?;
+    let (dir, dir_name) = images_dir(out);
+    doc.write_images(&dir)?;
+    let mut h = Html::new(&doc, doc.image_urls(&dir_name));
+    h.out.push_str(&format!("<!DOCTYPE html>\n<html lang=\"{}\">\n<head>\n\
+                             <meta charset=\"utf-8\"/>\n<title>{}</title>\n",
+                            escape(&doc.lang), escape(&doc.title)));
+    for a in &doc.authors {
+        h.out.push_str(&format!("<meta name=\"author\" content=\"{}\"/>\n", escape(a)));
+    }
+    h.out.push_str(&format!("<style>\n{}body {{ max-width: 40em; margin: 0 auto; }}\n\
+                             </style>\n</head>\n<body>\n", CSS));
+    if let Some(src) = doc.cover.as_ref().and_then(|c| h.images.get(c)).cloned() {
+        h.out.push_str(&format!("<div class=\"cover\"><img src=\"{}\" alt=\"\"/></div>\n",
+                                escape(&src)));
+    }
+    for c in doc.body.elements() {
+        h.block(c, 1);
+    }
+    h.notes();
+    h.out.push_str("</body>\n</html>\n");
+    std::fs::write(out, h.out).with_context(|| format!("cannot write `{}'", out.display()))
+}
+
+// Escape the characters that mean something in Markdown.
+fn md_escape(t: &str) -> String {
+    let mut s = String::with_capacity(t.len());
+    for c in t.chars() {
+        if "\\`*_[]<>|".contains(c) {
+            s.push('\\');
+        }
+        s.push(c);
+    }
+    s
+}
+
+// Make sure that the paragraph doesn't start with something that looks
+// like a heading, a list item or a quote.
+fn md_escape_start(s: &str) -> String {
+    let digits = s.chars().take_while(char::is_ascii_digit).count();
+    let numbered = digits > 0 && s[digits..].starts_with(['.', ')']);
+    if numbered {
+        format!("{}\\{}", &s[..digits], &s[digits..])
+    } else if s.starts_with(['#', '-', '+', '=', '>']) {
+        format!("\\{}", s)
+    } else {
+        s.to_string()
+    }
+}
+
+// Put the markers around the text, leaving the spaces at its ends
+// outside, as otherwise Markdown doesn't see the markers.
+fn md_wrap(t: &str, open: &str, close: &str) -> String {
+    if t.trim().is_empty() {
+        return t.to_string();
+    }
+    let lead = if t.starts_with(' ') { " " } else { "" };
+    let trail = if t.ends_with(' ') { " " } else { "" };
+    format!("{}{}{}{}{}", lead, open, t.trim(), close, trail)
+}
+
+fn md_url(u: &str) -> String {
+    if u.contains([' ', '(', ')']) {
+        format!("<{}>", u)
+    } else {
+        u.to_string()
+    }
+}
+
+// Writing the elements of the book as Markdown.
+struct Markdown<'a> {
+    doc: &'a Doc,
+    out: String,
+    images: &'a HashMap<String, String>,
+    // Prefix of the lines, `> ` for the quotes, and that of the last
+    // block.
+    prefix: String,
+    last: String,
+}
+
+impl<'a> Markdown<'a> {
+    fn new(doc: &'a Doc, images: &'a HashMap<String, String>) -> Markdown<'a> {
+        Markdown { doc, out: String::new(), images, prefix: String::new(), last: String::new() }
+    }
+
+    // The block of `text`, separated from the previous one with an
+    // empty line.
+    fn para(&mut self, text: &str) {
+        let text = text.trim();
+        if text.is_empty() {
+            return;
+        }
+        // The empty line between the blocks belongs to the quotes that
+        // both of them are in.
+        if !self.out.is_empty() {
+            let common = self.prefix.len().min(self.last.len());
+            let common = if self.prefix[..common] == self.last[..common] { common } else { 0 };
+            self.out.push_str(self.prefix[..common].trim_end());
+            self.out.push('\n');
+        }
+        self.last = self.prefix.clone();
+        for l in text.lines() {
+            self.out.push_str(&self.prefix);
+            self.out.push_str(l);
+            self.out.push('\n');
+        }
+    }
+
+    fn anchor(e: &Element) -> String {
+        e.id().map_or(String::new(), |id| format!("<a id=\"{}\"></a>", escape(id)))
+    }
+
+    fn image(&self, e: &Element) -> String {
+        let id = e.href().unwrap_or("").trim_start_matches('#');
+        self.images.get(id).map_or(String::new(), |src| {
+            format!("![{}]({})", md_escape(e.attr("alt").unwrap_or("")), md_url(src))
+        })
+    }
+
+    fn inline(&self, e: &Element) -> String {
+        let mut s = String::new();
+        for n in &e.children {
+            let c = match n {
+                Node::Text(t) => {
+                    s.push_str(&md_escape(&collapse(t)));
+                    continue;
+                }
+                Node::Element(c) => c,
+            };
+            s.push_str(&Markdown::anchor(c));
+            match c.name.as_str() {
+                "emphasis" => s.push_str(&md_wrap(&self.inline(c), "*", "*")),
+                "strong" => s.push_str(&md_wrap(&self.inline(c), "**", "**")),
+                "strikethrough" => s.push_str(&md_wrap(&self.inline(c), "~~", "~~")),
+                "sub" | "sup" => s.push_str(&md_wrap(&self.inline(c), &format!("<{}>", c.name),
+                                                     &format!("</{}>", c.name))),
+                "code" => s.push_str(&md_wrap(&c.text(), "`", "`")),
+                "image" => s.push_str(&self.image(c)),
+                "a" => {
+                    let href = c.href().unwrap_or("");
+                    let target = href.trim_start_matches('#');
+                    if href.starts_with('#') && self.doc.note_ids.contains(target) {
+                        s.push_str(&format!("[^{}]", target));
+                    } else {
+                        s.push_str(&format!("[{}]({})", self.inline(c).trim(), md_url(href)));
+                    }
+                }
+                _ => s.push_str(&self.inline(c)),
+            }
+        }
+        s
+    }
+
+    fn blocks(&mut self, e: &Element, depth: usize) {
+        for c in e.elements() {
+            self.block(c, depth);
+        }
+    }
+
+    fn quoted(&mut self, e: &Element) {
+        self.prefix.push_str("> ");
+        self.blocks(e, 0);
+        self.prefix.truncate(self.prefix.len() - 2);
+    }
+
+    fn title(&mut self, e: &Element, depth: usize, anchor: &str) {
+        let text = e.elements().filter(|p| p.name == "p")
+                    .map(|p| self.inline(p).trim().to_string())
+                    .collect::<Vec<_>>().join(" ");
+        if text.is_empty() {
+            self.para(anchor);
+        } else if depth == 0 {
+            self.para(&format!("{}{}", anchor, md_wrap(&text, "**", "**")));
+        } else {
+            self.para(&format!("{} {}{}", "#".repeat(depth.min(6)), anchor, text));
+        }
+    }
+
+    // See `Html::block` about the `depth`.
+    fn block(&mut self, e: &Element, depth: usize) {
+        match e.name.as_str() {
+            "section" => {
+                // The id of the section goes to its heading or, if it is
+                // untitled, to its own line.
+                if e.child("title").is_none() {
+                    self.para(&Markdown::anchor(e));
+                }
+                for c in e.elements() {
+                    match c.name.as_str() {
+                        "section" => self.block(c, depth + 1),
+                        "title" => self.title(c, depth, &Markdown::anchor(e)),
+                        _ => self.block(c, depth),
+                    }
+                }
+            }
+            "title" => self.title(e, depth, &Markdown::anchor(e)),
+            "p" | "v" => {
+                let t = self.inline(e);
+                self.para(&format!("{}{}", Markdown::anchor(e), md_escape_start(t.trim())));
+            }
+            "subtitle" => {
+                let t = md_wrap(self.inline(e).trim(), "**", "**");
+                self.para(&format!("{}{}", Markdown::anchor(e), t));
+            }
+            "text-author" => {
+                let t = self.inline(e);
+                self.para(&format!("{}— {}", Markdown::anchor(e), t.trim()));
+            }
+            "epigraph" | "cite" => self.quoted(e),
+            "poem" | "annotation" => self.blocks(e, 0),
+            "stanza" => {
+                if let Some(t) = e.child("title") {
+                    self.block(t, 0);
+                }
+                // The lines of the stanza are kept with the hard breaks.
+                let lines: Vec<String> = e.elements().filter(|v| v.name == "v")
+                    .map(|v| format!("{}{}", Markdown::anchor(v), md_escape_start(self.inline(v).trim())))
+                    .collect();
+                self.para(&lines.join("\\\n"));
+            }
+            "image" => {
+                let i = self.image(e);
+                self.para(&i);
+            }
+            "pre" => {
+                let t = e.text_raw();
+                let mut fence = String::from("```");
+                while t.contains(&fence) {
+                    fence.push('`');
+                }
+                self.para(&format!("{}\n{}\n{}", fence, t.trim_matches('\n'), fence));
+            }
+            "table" => {
+                let mut rows = Vec::new();
+                for (i, r) in e.elements().enumerate() {
+                    let cells: Vec<String> = r.elements().map(|c| self.inline(c).trim().to_string())
+                                              .collect();
+                    rows.push(format!("| {} |", cells.join(" | ")));
+                    if i == 0 {
+                        rows.push(format!("|{}", "---|".repeat(cells.len().max(1))));
+                    }
+                }
+                self.para(&rows.join("\n"));
+            }
+            "empty-line" => (),
+            _ => self.blocks(e, depth),
+        }
+    }
+
+    // The notes become the footnotes at the end of the text.
+    fn notes(&mut self) {
+        for b in &self.doc.notes {
+            for s in b.elements() {
+                let id = match s.id() {
+                    Some(id) if s.name == "section" => id,
+                    // The title of the notes is of no use without the
+                    // notes around it.
+                    _ if s.name == "title" => continue,
+                    _ => {
+                        self.block(s, 1);
+                        continue;
+                    }
+                };
+                let mut m = Markdown::new(self.doc, self.images);
+                for c in s.elements().filter(|c| c.name != "title") {
+                    m.block(c, 0);
+                }
+                let text = m.out.trim().replace('\n', "\n    ").replace("\n    \n", "\n\n");
+                self.para(&format!("[^{}]: {}", id, text));
+            }
+        }
+    }
+}
+
+// A YAML string in double quotes.
+fn yaml_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Write the book `data` (FB2) into the Markdown file `out`, the images
+// go into the directory next to it.
+pub fn markdown(data: &[u8], out: &Path) -> anyhow::Result<()> {
+    let doc = Doc::load(data)?;
+    let (dir, dir_name) = images_dir(out);
+    doc.write_images(&dir)?;
+    let images = doc.image_urls(&dir_name);
+    let mut m = Markdown::new(&doc, &images);
+    for c in doc.body.elements() {
+        m.block(c, 1);
+    }
+    m.notes();
+    let mut s = format!("---\ntitle: {}\n", yaml_string(&doc.title));
+    if !doc.authors.is_empty() {
+        s.push_str(&format!("author: {}\n", yaml_string(&doc.authors.join(", "))));
+    }
+    s.push_str(&format!("lang: {}\n---\n\n", doc.lang));
+    s.push_str(&m.out);
+    std::fs::write(out, s).with_context(|| format!("cannot write `{}'", out.display()))
+}
+
+// An entry of the table of contents of the EPUB.
+struct TocEntry {
+    title: String,
+    href: String,
+    children: Vec<TocEntry>,
+}
+
+// The entries for the titled sections among the children of `e`, the
+// untitled ones don't get an entry, but their subsections do.
+fn toc(e: &Element, files: &HashMap<String, String>) -> Vec<TocEntry> {
+    let mut res = Vec::new();
+    for s in e.elements().filter(|s| s.name == "section") {
+        let children = toc(s, files);
+        let title = s.child("title").map(|t| t.text()).unwrap_or_default();
+        match s.id() {
+            Some(id) if !title.is_empty() => res.push(TocEntry {
+                title,
+                href: format!("{}#{}", files.get(id).map_or("", |f| f.as_str()), id),
+                children,
+            }),
+            _ => res.extend(children),
+        }
+    }
+    res
+}
+
+fn nav_list(entries: &[TocEntry], s: &mut String) {
+    s.push_str("<ol>\n");
+    for e in entries {
+        s.push_str(&format!("<li><a href=\"{}\">{}</a>", escape(&e.href), escape(&e.title)));
+        if !e.children.is_empty() {
+            s.push('\n');
+            nav_list(&e.children, s);
+        }
+        s.push_str("</li>\n");
+    }
+    s.push_str("</ol>\n");
+}
+
+fn ncx_points(entries: &[TocEntry], n: &mut usize, s: &mut String) {
+    for e in entries {
+        *n += 1;
+        s.push_str(&format!("<navPoint id=\"nav{0}\" playOrder=\"{0}\"><navLabel><text>{1}</text>\
+                             </navLabel><content src=\"{2}\"/>\n",
+                            n, escape(&e.title), escape(&e.href)));
+        ncx_points(&e.children, n, s);
+        s.push_str("</navPoint>\n");
+    }
+}
+
+fn xhtml(doc: &Doc, title: &str, body: &str) -> String {
+    format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<!DOCTYPE html>\n\
+             <html xmlns=\"http://www.w3.org/1999/xhtml\" \
+             xmlns:epub=\"http://www.idpf.org/2007/ops\" xml:lang=\"{0}\" lang=\"{0}\">\n\
+             <head>\n<title>{1}</title>\n\
+             <link rel=\"stylesheet\" type=\"text/css\" href=\"style.css\"/>\n\
+             </head>\n<body>\n{2}</body>\n</html>\n",
+            escape(&doc.lang), escape(title), body)
+}
+
+// Write the book `data` (FB2) into the EPUB file `out`.  Every top-level
+// section of the book goes into a file of its own, as the readers are
+// slow with large files, and the notes go into the last one.
+pub fn epub(data: &[u8], out: &Path) -> anyhow::Result<()> {
+    let doc = Doc::load(data)?;
+
+    // The files of the text with the elements of the body they have:
+    // whatever comes before the first section (the title, epigraphs)
+    // and then a file per section.
+    let mut parts: Vec<(String, Vec<&Element>)> = Vec::new();
+    for e in doc.body.elements() {
+        if e.name == "section" || parts.is_empty() {
+            parts.push((format!("text{:03}.xhtml", parts.len()), Vec::new()));
+        }
+        if let Some(p) = parts.last_mut() {
+            p.1.push(e);
+        }
+    }
+    let notes_file = String::from("notes.xhtml");
+
+    let mut files = HashMap::new();
+    for (f, elements) in &parts {
+        for e in elements {
+            let mut ids = HashSet::new();
+            collect_ids(e, &mut ids);
+            files.extend(ids.into_iter().map(|id| (id, f.clone())));
+        }
+    }
+    for b in &doc.notes {
+        let mut ids = HashSet::new();
+        collect_ids(b, &mut ids);
+        files.extend(ids.into_iter().map(|id| (id, notes_file.clone())));
+    }
+
+    let mut entries = toc(&doc.body, &files);
+    if !doc.notes.is_empty() {
+        let title = doc.notes[0].child("title").map(|t| t.text())
+                       .filter(|t| !t.is_empty()).unwrap_or_else(|| String::from("Notes"));
+        entries.push(TocEntry { title, href: notes_file.clone(), children: Vec::new() });
+    }
+    if entries.is_empty() {
+        entries.push(TocEntry { title: doc.title.clone(),
+                                href: parts.first().map_or(String::new(), |p| p.0.clone()),
+                                children: Vec::new() });
+    }
+
+    let f = std::fs::File::create(out)
+            .with_context(|| format!("cannot create `{}'", out.display()))?;
+    let mut zw = zip::ZipWriter::new(std::io::BufWriter::new(f));
+    let stored = zip::write::FileOptions::default()
+                 .compression_method(zip::CompressionMethod::Stored);
+    let deflated = zip::write::FileOptions::default();
+
+    // The `mimetype` has to be the first one and uncompressed, so that
+    // the format can be recognised by the first bytes of the file.
+    zw.start_file("mimetype", stored)?;
+    zw.write_all(b"application/epub+zip")?;
+    zw.start_file("META-INF/container.xml", deflated)?;
+    zw.write_all(b"<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+        <container version=\"1.0\" xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\">\n\
+        <rootfiles><rootfile full-path=\"OEBPS/content.opf\" \
+        media-type=\"application/oebps-package+xml\"/></rootfiles>\n</container>\n")?;
+    zw.start_file("OEBPS/style.css", deflated)?;
+    zw.write_all(CSS.as_bytes())?;
+
+    let images = doc.image_urls("images");
+    let mut manifest = String::new();
+    let mut spine = String::new();
+    if let Some(src) = doc.cover.as_ref().and_then(|c| images.get(c)) {
+        zw.start_file("OEBPS/cover.xhtml", deflated)?;
+        zw.write_all(xhtml(&doc, &doc.title, &format!(
+            "<div class=\"cover\"><img src=\"{}\" alt=\"\"/></div>\n", escape(src))).as_bytes())?;
+        manifest.push_str("<item id=\"cover\" href=\"cover.xhtml\" \
+                           media-type=\"application/xhtml+xml\"/>\n");
+        spine.push_str("<itemref idref=\"cover\"/>\n");
+    }
+    for (f, elements) in &parts {
+        let mut h = Html::new(&doc, images.clone());
+        h.files = files.clone();
+        h.epub = true;
+        for e in elements {
+            h.block(e, 1);
+        }
+        zw.start_file(format!("OEBPS/{}", f), deflated)?;
+        zw.write_all(xhtml(&doc, &doc.title, &h.out).as_bytes())?;
+        let id = f.trim_end_matches(".xhtml");
+        manifest.push_str(&format!("<item id=\"{}\" href=\"{}\" \
+                                    media-type=\"application/xhtml+xml\"/>\n", id, f));
+        spine.push_str(&format!("<itemref idref=\"{}\"/>\n", id));
+    }
+    if !doc.notes.is_empty() {
+        let mut h = Html::new(&doc, images.clone());
+        h.files = files.clone();
+        h.epub = true;
+        h.notes();
+        zw.start_file(format!("OEBPS/{}", notes_file), deflated)?;
+        zw.write_all(xhtml(&doc, &doc.title, &h.out).as_bytes())?;
+        manifest.push_str(&format!("<item id=\"notes\" href=\"{}\" \
+                                    media-type=\"application/xhtml+xml\"/>\n", notes_file));
+        spine.push_str("<itemref idref=\"notes\"/>\n");
+    }
+    let mut ids: Vec<&String> = doc.images.keys().collect();
+    ids.sort();
+    for (n, id) in ids.into_iter().enumerate() {
+        let i = &doc.images[id];
+        zw.start_file(format!("OEBPS/images/{}", i.file), deflated)?;
+        zw.write_all(&i.data)?;
+        if doc.cover.as_ref() == Some(id) {
+            manifest.push_str(&format!("<item id=\"cover-image\" href=\"images/{}\" \
+                                        media-type=\"{}\" properties=\"cover-image\"/>\n",
+                                       escape(&i.file), i.media_type));
+        } else {
+            manifest.push_str(&format!("<item id=\"image{}\" href=\"images/{}\" \
+                                        media-type=\"{}\"/>\n",
+                                       n, escape(&i.file), i.media_type));
+        }
+    }
+
+    // The table of contents for EPUB 3 and the older one for EPUB 2, as
+    // a lot of e-readers still use it.
+    let mut nav = String::from("<nav epub:type=\"toc\" id=\"toc\">\n<h1>Contents</h1>\n");
+    nav_list(&entries, &mut nav);
+    nav.push_str("</nav>\n");
+    zw.start_file("OEBPS/nav.xhtml", deflated)?;
+    zw.write_all(xhtml(&doc, &doc.title, &nav).as_bytes())?;
+
+    let mut points = String::new();
+    ncx_points(&entries, &mut 0, &mut points);
+    zw.start_file("OEBPS/toc.ncx", deflated)?;
+    zw.write_all(format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+        <ncx xmlns=\"http://www.daisy.org/z3986/2005/ncx/\" version=\"2005-1\">\n\
+        <head><meta name=\"dtb:uid\" content=\"{}\"/></head>\n\
+        <docTitle><text>{}</text></docTitle>\n<navMap>\n{}</navMap>\n</ncx>\n",
+        escape(&doc.id), escape(&doc.title), points).as_bytes())?;
+
+    let mut metadata = format!("<dc:identifier id=\"book-id\">{}</dc:identifier>\n\
+                                <dc:title>{}</dc:title>\n<dc:language>{}</dc:language>\n",
+                               escape(&doc.id), escape(&doc.title), escape(&doc.lang));
+    for a in &doc.authors {
+        metadata.push_str(&format!("<dc:creator>{}</dc:creator>\n", escape(a)));
+    }
+    let modified = time::strftime("%Y-%m-%dT%H:%M:%SZ", &time::now_utc()).unwrap_or_default();
+    metadata.push_str(&format!("<meta property=\"dcterms:modified\">{}</meta>\n", modified));
+    if doc.cover.is_some() {
+        metadata.push_str("<meta name=\"cover\" content=\"cover-image\"/>\n");
+    }
+    zw.start_file("OEBPS/content.opf", deflated)?;
+    zw.write_all(format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+        <package xmlns=\"http://www.idpf.org/2007/opf\" version=\"3.0\" \
+        unique-identifier=\"book-id\">\n\
+        <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n{}</metadata>\n\
+        <manifest>\n\
+        <item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" \
+        properties=\"nav\"/>\n\
+        <item id=\"ncx\" href=\"toc.ncx\" media-type=\"application/x-dtbncx+xml\"/>\n\
+        <item id=\"css\" href=\"style.css\" media-type=\"text/css\"/>\n{}</manifest>\n\
+        <spine toc=\"ncx\">\n{}</spine>\n</package>\n",
+        metadata, manifest, spine).as_bytes())?;
+    zw.finish()?.flush()?;
+    Ok(())
+}
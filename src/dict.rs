@@ -0,0 +1,342 @@
+// Offline dictionaries in StarDict (.ifo, .idx, .dict[.dz]) and dictd
+// (.index, .dict[.dz]) formats.  The dictionaries are listed in the
+// settings file by the path to the .ifo or the .index file, and we
+// load them when the first word is looked up.
+
+use std::{
+    collections::HashMap, io::Read, path::Path
+};
+use anyhow::Context;
+use crate::WORD_RE;
+
+enum Format {
+    // The types of the fields of every entry if they are the same
+    // for all the entries (the `sametypesequence` in the .ifo file).
+    StarDict(Option<String>),
+    Dictd,
+}
+
+pub struct Dictionary {
+    pub name: String,
+    format: Format,
+    // Offsets and sizes of the articles keyed by the lowercase headword.
+    index: HashMap<String, Vec<(usize, usize)>>,
+    data: Vec<u8>,
+}
+
+// Read the file, decompressing it if it is a dictzip (which is gzip
+// with an extra header that we don't need).
+fn read_data(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let f = std::fs::File::open(path)
+            .with_context(|| format!("cannot open `{}'", path.display()))?;
+    let mut data = Vec::new();
+    if path.extension().is_some_and(|e| e == "dz") {
+        flate2::read::MultiGzDecoder::new(f).read_to_end(&mut data)?;
+    } else {
+        std::io::BufReader::new(f).read_to_end(&mut data)?;
+    }
+    Ok(data)
+}
+
+// The .dict file that goes with the index file, compressed or not.
+fn dict_path(base: &Path) -> anyhow::Result<std::path::PathBuf> {
+    for ext in &["dict.dz", "dict"] {
+        let p = base.with_extension(ext);
+        if p.exists() {
+            return Ok(p);
+        }
+    }
+    Err(anyhow::anyhow!("cannot find the .dict file for `{}'", base.display()))
+}
+
+fn open_stardict(ifo: &Path) -> anyhow::Result<Dictionary> {
+    let info = std::fs::read_to_string(ifo)
+               .with_context(|| format!("cannot open `{}'", ifo.display()))?;
+    let mut opts = HashMap::new();
+    for l in info.lines() {
+        if let Some((k, v)) = l.split_once('=') {
+            opts.insert(k.trim(), v.trim());
+        }
+    }
+    let off_size = if opts.get("idxoffsetbits") == Some(&"64") { 8 } else { 4 };
+
+    let mut idx = ifo.with_extension("idx");
+    if !idx.exists() {
+        idx = ifo.with_extension("idx.gz");
+    }
+    let idx = if idx.extension().is_some_and(|e| e == "gz") {
+        let mut d = Vec::new();
+        flate2::read::GzDecoder::new(std::fs::File::open(&idx)?).read_to_end(&mut d)?;
+        d
+    } else {
+        std::fs::read(&idx).with_context(|| format!("cannot open `{}'", idx.display()))?
+    };
+
+    Ok(Dictionary {
+        name: opts.get("bookname").map_or_else(|| ifo.display().to_string(),
+                                               |n| n.to_string()),
+        format: Format::StarDict(opts.get("sametypesequence").map(|s| s.to_string())),
+        index: stardict_index(&idx, off_size),
+        data: read_data(&dict_path(ifo)?)?,
+    })
+}
+
+// Read the .idx file with the offsets of `off_size` bytes.  Every entry
+// is the headword ending with 0, and the offset and the size of the
+// article as big-endian numbers.
+fn stardict_index(idx: &[u8], off_size: usize) -> HashMap<String, Vec<(usize, usize)>> {
+    let mut index: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+    let mut i = 0;
+    while let Some(n) = idx[i..].iter().position(|&b| b == 0) {
+        let word = String::from_utf8_lossy(&idx[i..i + n]).to_lowercase();
+        i += n + 1;
+        if i + off_size + 4 > idx.len() {
+            break;
+        }
+        let num = |b: &[u8]| b.iter().fold(0usize, |a, &x| (a << 8) | x as usize);
+        let off = num(&idx[i..i + off_size]);
+        let size = num(&idx[i + off_size..i + off_size + 4]);
+        i += off_size + 4;
+        index.entry(word).or_default().push((off, size));
+    }
+    index
+}
+
+// Decode the numbers in the dictd index, which use the base64 alphabet.
+fn dictd_number(s: &str) -> Option<usize> {
+    s.bytes().try_fold(0usize, |a, c| {
+        let d = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        Some(a * 64 + d as usize)
+    })
+}
+
+fn open_dictd(path: &Path) -> anyhow::Result<Dictionary> {
+    let text = std::fs::read_to_string(path)
+               .with_context(|| format!("cannot open `{}'", path.display()))?;
+    let mut index: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+    let mut name = None;
+    for l in text.lines() {
+        let mut f = l.split('\t');
+        if let (Some(w), Some(Some(off)), Some(Some(size))) =
+               (f.next(), f.next().map(dictd_number), f.next().map(dictd_number)) {
+            // The service entries describe the dictionary itself.
+            if w == "00databaseshort" || w == "00-database-short" {
+                name = Some((off, size));
+            } else if !w.starts_with("00database") && !w.starts_with("00-database") {
+                index.entry(w.to_lowercase()).or_default().push((off, size));
+            }
+        }
+    }
+    let data = read_data(&dict_path(path)?)?;
+    let name = name.and_then(|(o, s)| data.get(o..o + s))
+                   .and_then(|d| String::from_utf8_lossy(d).lines().nth(1)
+                                 .map(|l| l.trim().to_string()))
+                   .unwrap_or_else(|| path.display().to_string());
+    Ok(Dictionary { name, format: Format::Dictd, index, data })
+}
+
+pub fn open(path: &str) -> anyhow::Result<Dictionary> {
+    let p = Path::new(path);
+    match p.extension().and_then(|e| e.to_str()) {
+        Some("ifo") => open_stardict(p),
+        Some("index") => open_dictd(p),
+        _ => Err(anyhow::anyhow!("unknown dictionary format of `{}', \
+                                  expected .ifo or .index file", path)),
+    }
+}
+
+// Turn the markup of the articles into plain text.
+fn strip_markup(s: &str) -> String {
+    lazy_static! {
+        static ref BR: regex::Regex = regex::Regex::new(r"(?i)<br\s*/?>|</p>|</div>").unwrap();
+        static ref TAG: regex::Regex = regex::Regex::new(r"<[^>]*>").unwrap();
+    }
+    let s = BR.replace_all(s, "\n");
+    TAG.replace_all(&s, "")
+       .replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"")
+       .replace("&apos;", "'").replace("&nbsp;", " ").replace("&amp;", "&")
+}
+
+// The text of the StarDict article, only the textual fields are kept.
+fn stardict_text(data: &[u8], types: Option<&str>) -> String {
+    let mut res = Vec::new();
+    let mut rest = data;
+    let mut types = types.map(|t| t.bytes().collect::<Vec<_>>());
+    loop {
+        // With `sametypesequence` the types are not stored, and the
+        // last field doesn't have the size or the terminating 0.
+        let (t, last) = match &mut types {
+            Some(ts) if ts.is_empty() => break,
+            Some(ts) => (ts.remove(0), ts.is_empty()),
+            None => match rest.split_first() {
+                Some((t, r)) => { rest = r; (*t, false) }
+                None => break,
+            },
+        };
+        let field = if last {
+            mem_take(&mut rest, usize::MAX)
+        } else if t.is_ascii_lowercase() {
+            let n = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+            let f = mem_take(&mut rest, n);
+            rest = rest.get(1..).unwrap_or_default();
+            f
+        } else {
+            if rest.len() < 4 {
+                break;
+            }
+            let n = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            rest = &rest[4..];
+            mem_take(&mut rest, n)
+        };
+        match t {
+            b'm' | b'l' | b't' | b'y' => res.push(String::from_utf8_lossy(field).into_owned()),
+            b'g' | b'h' | b'x' => res.push(strip_markup(&String::from_utf8_lossy(field))),
+            _ => (),
+        }
+    }
+    res.join("\n")
+}
+
+// Split off the first `n` bytes of `s`.
+fn mem_take<'a>(s: &mut &'a [u8], n: usize) -> &'a [u8] {
+    let (a, b) = s.split_at(n.min(s.len()));
+    *s = b;
+    a
+}
+
+impl Dictionary {
+    // Articles for the word, the lookup is case-insensitive.
+    pub fn lookup(&self, word: &str) -> Vec<String> {
+        let ls = match self.index.get(&word.to_lowercase()) {
+            Some(ls) => ls,
+            None => return Vec::new(),
+        };
+        ls.iter().filter_map(|&(off, size)| {
+            let d = self.data.get(off..off + size)?;
+            Some(match &self.format {
+                Format::StarDict(t) => stardict_text(d, t.as_deref()),
+                Format::Dictd => String::from_utf8_lossy(d).into_owned(),
+            }.trim().to_string())
+        }).collect()
+    }
+}
+
+// Endings that we strip off the words to get their stems, the longest
+// ones go first.
+const SUFFIXES: &[&str] = &[
+    "иями", "ями", "ами", "ого", "его", "ому", "ему", "ыми", "ими", "ешь",
+    "ете", "ите", "ала", "ало", "али", "ила", "ило", "или", "ела", "ели",
+    "ась", "ось", "ись", "ой", "ей", "ом", "ем", "ах", "ях", "ов", "ев", "ую",
+    "юю", "ая", "яя", "ое", "ее", "ые", "ие", "ых", "их", "ым", "им", "ет",
+    "ут", "ют", "ит", "ат", "ят", "ал", "ил", "ел", "ся", "сь", "а", "я", "ы",
+    "и", "у", "ю", "е", "о", "ь", "й",
+    "ies", "ing", "est", "es", "ed", "ly", "er", "s",
+];
+// Endings of the dictionary forms that we try after the stem.
+const ENDINGS: &[&str] = &[
+    "", "а", "я", "о", "е", "ь", "й", "ый", "ий", "ой", "ть", "ать", "ять",
+    "ить", "еть", "ться", "e", "y",
+];
+
+// The forms of the word that we look up in the dictionaries, in the
+// order of preference: the word itself, the word without punctuation
+// (peeled off the same way as for the hyphenation), in lower case,
+// with "ё" replaced by "е", and finally the guesses of the dictionary
+// form based on the stem of the word.
+pub fn candidates(word: &str) -> Vec<String> {
+    let mut res: Vec<String> = Vec::new();
+    let mut add = |w: String| {
+        if !w.is_empty() && !res.contains(&w) {
+            res.push(w);
+        }
+    };
+    add(word.to_string());
+    let core = WORD_RE.captures(word).and_then(|c| c.get(2))
+                      .map_or(word, |m| m.as_str());
+    add(core.to_string());
+    let lower = core.to_lowercase();
+    add(lower.clone());
+    let lower = crate::search::fold(&lower);
+    add(lower.clone());
+    for s in SUFFIXES {
+        if let Some(stem) = lower.strip_suffix(s) {
+            if stem.chars().count() >= 2 {
+                for e in ENDINGS {
+                    add(format!("{}{}", stem, e));
+                }
+            }
+        }
+    }
+    res
+}
+
+// Look up the word in all the dictionaries trying the `candidates`
+// until we find something.  Returns the form that was found and the
+// articles along with the names of the dictionaries.
+pub fn lookup(dicts: &[Dictionary], word: &str) -> Option<(String, Vec<(String, String)>)> {
+    candidates(word).into_iter().find_map(|w| {
+        let res: Vec<(String, String)> = dicts.iter().flat_map(|d| {
+            d.lookup(&w).into_iter().map(move |a| (d.name.clone(), a))
+        }).collect();
+        if res.is_empty() { None } else { Some((w, res)) }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The .idx entry of the `word` with the article at `off`.
+    fn entry(idx: &mut Vec<u8>, word: &str, off: u64, size: u32, off_size: usize) {
+        idx.extend(word.as_bytes());
+        idx.push(0);
+        idx.extend(&off.to_be_bytes()[8 - off_size..]);
+        idx.extend(size.to_be_bytes());
+    }
+
+    #[test]
+    fn stardict() {
+        let data = "кошка, -и, ж.\0mcat\0".as_bytes().to_vec();
+        let n = "кошка, -и, ж.".len();
+        for off_size in [4, 8] {
+            let mut idx = Vec::new();
+            entry(&mut idx, "Кошка", 0, n as u32, off_size);
+            entry(&mut idx, "cat", n as u64 + 1, 5, off_size);
+            // A truncated entry at the end is ignored.
+            idx.extend(b"dog\0\0");
+            let index = stardict_index(&idx, off_size);
+            assert_eq!(index.len(), 2);
+            assert_eq!(index["кошка"], [(0, n)]);
+            assert_eq!(index["cat"], [(n + 1, 5)]);
+        }
+        let mut idx = Vec::new();
+        entry(&mut idx, "Кошка", 0, n as u32, 4);
+        let d = Dictionary { name: "test".to_string(), format: Format::StarDict(Some("m".into())),
+                             index: stardict_index(&idx, 4), data: data.clone() };
+        assert_eq!(d.lookup("КОШКА"), ["кошка, -и, ж."]);
+        assert!(d.lookup("кот").is_empty());
+        // Without `sametypesequence` the articles have the types.
+        let mut idx = Vec::new();
+        entry(&mut idx, "cat", n as u64 + 1, 5, 4);
+        let d = Dictionary { name: "test".to_string(), format: Format::StarDict(None),
+                             index: stardict_index(&idx, 4), data };
+        assert_eq!(d.lookup("cat"), ["cat"]);
+    }
+
+    #[test]
+    fn forms() {
+        let c = candidates("«Кошками,");
+        assert_eq!(c[..3], ["«Кошками,", "Кошками", "кошками"]);
+        assert!(c.contains(&"кошка".to_string()));
+        let c = candidates("ЕЩЁ");
+        assert_eq!(c[..3], ["ЕЩЁ", "ещё", "еще"]);
+        assert!(candidates("walked").contains(&"walk".to_string()));
+    }
+}
@@ -0,0 +1,129 @@
+// Generating FB2 from the other formats.  Books in other formats are
+// converted into FB2 when we load them, so that the layout, the saved
+// positions, the search and the rest of the reader deal with FB2 only.
+
+use crate::meta::Description;
+
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+     .replace('"', "&quot;")
+}
+
+pub struct Writer {
+    out: String,
+    // Number of open sections.
+    depth: usize,
+    // Sections of the body with the notes.
+    notes: String,
+}
+
+impl Writer {
+    // Start the book with the given meta information.  The `id` ends up
+    // in `<document-info>`, so it becomes the identity of the book; the
+    // converters derive it from the original content, so that it doesn't
+    // change when the conversion does.
+    pub fn new(title: &str, authors: &[String], id: &str) -> Writer {
+        Writer::describe(&Description {
+            id: Some(id.to_string()),
+            title: Some(title.to_string()),
+            authors: authors.to_vec(),
+            ..Description::default()
+        })
+    }
+
+    // Start the book with the meta information of the `desc`: the
+    // title, the authors, the series, the language and the genres.
+    pub fn describe(desc: &Description) -> Writer {
+        let mut out = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+            <FictionBook xmlns=\"http://www.gribuser.ru/xml/fictionbook/2.0\" \
+            xmlns:l=\"http://www.w3.org/1999/xlink\">\n<description><title-info>");
+        for g in &desc.genres {
+            out.push_str(&format!("<genre>{}</genre>", escape(g)));
+        }
+        for a in &desc.authors {
+            // Everything but the last word is the first name.
+            let (first, last) = match a.trim().rsplit_once(' ') {
+                Some((f, l)) => (f, l),
+                None => ("", a.trim()),
+            };
+            out.push_str("<author>");
+            if !first.is_empty() {
+                out.push_str(&format!("<first-name>{}</first-name>", escape(first)));
+            }
+            out.push_str(&format!("<last-name>{}</last-name></author>", escape(last)));
+        }
+        out.push_str(&format!("<book-title>{}</book-title>",
+                              escape(desc.title.as_deref().unwrap_or_default())));
+        if let Some(l) = &desc.lang {
+            out.push_str(&format!("<lang>{}</lang>", escape(l)));
+        }
+        if let Some(s) = &desc.series {
+            let number = desc.series_number.map_or(String::new(), |n| format!(" number=\"{}\"", n));
+            out.push_str(&format!("<sequence name=\"{}\"{}/>", escape(s), number));
+        }
+        out.push_str(&format!("</title-info><document-info><id>{}</id></document-info>\
+                               </description>\n<body>\n",
+                              escape(desc.id.as_deref().unwrap_or_default())));
+        Writer { out, depth: 0, notes: String::new() }
+    }
+
+    // Start a section of the nesting `level` (starting with 1) with the
+    // lines of the `title`, closing the sections of the same or deeper
+    // levels.  The `id` makes the section a target of the links.
+    pub fn section(&mut self, level: usize, id: Option<&str>, title: &[String]) {
+        while self.depth >= level.max(1) {
+            self.end_section();
+        }
+        while self.depth + 1 < level {
+            self.out.push_str("<section>\n");
+            self.depth += 1;
+        }
+        match id {
+            Some(id) => self.out.push_str(&format!("<section id=\"{}\">\n", escape(id))),
+            None => self.out.push_str("<section>\n"),
+        }
+        self.depth += 1;
+        if !title.is_empty() {
+            self.out.push_str("<title>");
+            for t in title {
+                self.out.push_str(&format!("<p>{}</p>", escape(t)));
+            }
+            self.out.push_str("</title>\n");
+        }
+    }
+
+    fn end_section(&mut self) {
+        self.out.push_str("</section>\n");
+        self.depth -= 1;
+    }
+
+    // A paragraph of plain text.
+    pub fn p(&mut self, text: &str) {
+        self.out.push_str(&format!("<p>{}</p>\n", escape(text)));
+    }
+
+    // FB2 markup as is, e.g. a paragraph with emphasis.
+    pub fn raw(&mut self, xml: &str) {
+        self.out.push_str(xml);
+    }
+
+    // A section of the notes (the markup of the section as is), which
+    // go into a separate body after the text of the book.
+    pub fn note(&mut self, xml: &str) {
+        self.notes.push_str(xml);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        while self.depth > 0 {
+            self.end_section();
+        }
+        self.out.push_str("</body>\n");
+        if !self.notes.is_empty() {
+            self.out.push_str("<body name=\"notes\">\n");
+            self.out.push_str(&self.notes);
+            self.out.push_str("</body>\n");
+        }
+        self.out.push_str("</FictionBook>\n");
+        self.out.into_bytes()
+    }
+}
@@ -0,0 +1,419 @@
+// FB3 books, the successor of FB2.  The book is a zip container (an
+// OPC package, the same as the office documents use): the relationships
+// of the package in `_rels/.rels` point to the description of the book,
+// and the relationships of the description point to its body.  The body
+// is very close to FB2, so we convert it element by element.
+
+use std::io::Read;
+use anyhow::Context;
+use quick_xml::{
+    Reader, events::{
+        BytesStart, Event
+    }
+};
+use crate::{
+    fb2::{
+        self, escape
+    }, meta
+};
+
+type Zip<'a> = zip::ZipArchive<std::io::Cursor<&'a [u8]>>;
+
+fn read_entry(za: &mut Zip, name: &str) -> anyhow::Result<Vec<u8>> {
+    let mut f = za.by_name(name)
+                  .with_context(|| format!("cannot find `{}' in the FB3 book", name))?;
+    let mut data = Vec::new();
+    f.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+// The name without the namespace prefix.
+fn local(name: &[u8]) -> &[u8] {
+    name.rsplit(|&c| c == b':').next().unwrap_or(name)
+}
+
+// The value of the attribute with the local name `name`, so that both
+// `href` and `l:href` match `href`.
+fn attr<B: std::io::BufRead>(reader: &Reader<B>, e: &BytesStart, name: &[u8]) -> Option<String> {
+    e.attributes().flatten()
+     .find(|a| local(a.key) == name)
+     .and_then(|a| a.unescape_and_decode_value(reader).ok())
+}
+
+// The name of the entry that the `target` of the relationship refers
+// to, relative to the directory `dir`.
+fn resolve(dir: &str, target: &str) -> String {
+    let mut parts: Vec<&str> = if target.starts_with('/') {
+        Vec::new()
+    } else {
+        dir.split('/').filter(|p| !p.is_empty()).collect()
+    };
+    for p in target.split('/') {
+        match p {
+            "" | "." => (),
+            ".." => { parts.pop(); }
+            p => parts.push(p),
+        }
+    }
+    parts.join("/")
+}
+
+// The entries that the `part` of the package (the package itself if
+// it is empty) has relationships of the `kind` with.
+fn related(za: &mut Zip, part: &str, kind: &str) -> anyhow::Result<Vec<String>> {
+    let (dir, file) = part.rsplit_once('/').unwrap_or(("", part));
+    let rels = if dir.is_empty() {
+        format!("_rels/{}.rels", file)
+    } else {
+        format!("{}/_rels/{}.rels", dir, file)
+    };
+    let data = match read_entry(za, &rels) {
+        Ok(d) => d,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let mut reader = Reader::from_reader(&data[..]);
+    let mut buf = Vec::new();
+    let mut res = Vec::new();
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e))
+                    if local(e.name()) == b"Relationship" => {
+                let t = attr(&reader, e, b"Type").unwrap_or_default().to_lowercase();
+                if t.ends_with(kind) {
+                    if let Some(target) = attr(&reader, e, b"Target") {
+                        res.push(resolve(dir, &target));
+                    }
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                return Err(anyhow::anyhow!("Error in `{}' at position {}: {:?}",
+                                           rels, reader.buffer_position(), e))
+            }
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(res)
+}
+
+// The part of the package that the relationships point to, or if
+// they don't, the entry whose name ends with `suffix`.
+fn find_part(za: &mut Zip, part: &str, kind: &str, suffix: &str) -> anyhow::Result<String> {
+    if let Some(p) = related(za, part, kind)?.into_iter().next() {
+        return Ok(p);
+    }
+    za.file_names().find(|n| n.ends_with(suffix)).map(str::to_string)
+      .ok_or_else(|| anyhow::anyhow!("cannot find `{}' in the FB3 book", suffix))
+}
+
+// The title, the authors, the series, the language, the subjects (as
+// the genres) and the id from the description.
+fn parse_description(data: &[u8]) -> anyhow::Result<meta::Description> {
+    let mut reader = Reader::from_reader(data);
+    reader.trim_text(true);
+    let mut buf = Vec::new();
+    let mut path: Vec<Vec<u8>> = Vec::new();
+    let mut desc = meta::Description::default();
+    // Parts of the name of the author that we are collecting, and the
+    // name as a whole from its `<title>`.
+    let mut author: Option<(Vec<String>, String)> = None;
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) => {
+                let name = local(e.name()).to_vec();
+                if path.is_empty() {
+                    desc.id = attr(&reader, e, b"id");
+                } else if path.len() == 1 && name == b"sequence" && desc.series.is_none() {
+                    desc.series_number = attr(&reader, e, b"number")
+                                         .and_then(|n| n.trim().parse().ok());
+                } else if name == b"subject"
+                          && attr(&reader, e, b"link").is_some_and(|l| l == "author") {
+                    author = Some((Vec::new(), String::new()));
+                }
+                path.push(name);
+            }
+            Ok(Event::End(_)) => {
+                if path.last().is_some_and(|n| n == b"subject") {
+                    if let Some((parts, full)) = author.take() {
+                        let name = if parts.is_empty() { full } else { parts.join(" ") };
+                        if !name.is_empty() {
+                            desc.authors.push(name);
+                        }
+                    }
+                }
+                path.pop();
+            }
+            Ok(Event::Text(e)) => {
+                let t = e.unescape_and_decode(&reader)?;
+                let p: Vec<&[u8]> = path.iter().map(|n| n.as_slice()).collect();
+                match (&p[..], &mut author) {
+                    ([_, b"title", b"main"], _) => desc.title = Some(t),
+                    // The books of a series that is a part of a larger
+                    // one have the nested sequences, we take the outer.
+                    ([_, b"sequence", b"title", b"main"], _) if desc.series.is_none() => {
+                        desc.series = Some(t)
+                    }
+                    ([_, b"lang"], _) => desc.lang = Some(t),
+                    ([_, b"fb3-classification", b"subject"], _) => desc.genres.push(t),
+                    ([.., b"subject", b"first-name" | b"middle-name" | b"last-name"],
+                     Some((parts, _))) => parts.push(t),
+                    ([.., b"subject", b"title", b"main"], Some((_, full))) => *full = t,
+                    _ => (),
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                return Err(anyhow::anyhow!("Error in the FB3 description at position {}: {:?}",
+                                           reader.buffer_position(), e))
+            }
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(desc)
+}
+
+// The body converted into FB2: the markup of the text, and of the
+// notes that go into a separate body.
+#[derive(Default)]
+struct Body {
+    text: String,
+    notes: String,
+    in_notes: bool,
+    // Elements that we are in: their name and opening markup in FB2, or
+    // None if we drop the element keeping its content.
+    open: Vec<Option<(String, String)>>,
+    // Lists we are in, with the number of the next item for the
+    // numbered ones.
+    lists: Vec<Option<u64>>,
+    // Number of stanzas we are in, their paragraphs are verses.
+    stanzas: usize,
+    // Whether the next cell of the table row is the first one.
+    first_cell: bool,
+    // Number of the note references so far, the text of the references
+    // that don't have it is their number.
+    note_count: usize,
+}
+
+impl Body {
+    fn out(&mut self, s: &str) {
+        if self.in_notes {
+            self.notes.push_str(s);
+        } else {
+            self.text.push_str(s);
+        }
+    }
+
+    fn in_paragraph(&self) -> bool {
+        self.open.iter().flatten().any(|(n, _)| n == "p" || n == "v")
+    }
+
+    fn open(&mut self, name: &str, attrs: &str) {
+        let o = format!("<{}{}>", name, attrs);
+        self.out(&o);
+        self.open.push(Some((name.to_string(), o)));
+    }
+
+    // A line break within the paragraph: FB2 doesn't have them, so we
+    // start a new paragraph, and open the inline elements again.
+    fn line_break(&mut self) {
+        let i = match self.open.iter().rposition(|o| {
+            o.as_ref().is_some_and(|(n, _)| n == "p" || n == "v")
+        }) {
+            Some(i) => i,
+            None => return,
+        };
+        let tail: Vec<(String, String)> = self.open[i..].iter().flatten().cloned().collect();
+        let mut s: String = tail.iter().rev().map(|(n, _)| format!("</{}>", n)).collect();
+        s.extend(tail.iter().map(|(n, o)| if n == "p" || n == "v" {
+            format!("<{}>", n)
+        } else {
+            o.clone()
+        }));
+        self.out(&s);
+    }
+
+    fn start<B: std::io::BufRead>(&mut self, reader: &Reader<B>, e: &BytesStart) {
+        let name = local(e.name());
+        let id = attr(reader, e, b"id").map_or(String::new(),
+                                               |id| format!(" id=\"{}\"", escape(&id)));
+        let href = || attr(reader, e, b"href").map(|h| {
+            format!("#{}", h.trim_start_matches('#'))
+        });
+        match name {
+            b"section" | b"title" | b"epigraph" | b"subtitle" | b"poem" | b"text-author"
+            | b"strong" | b"strikethrough" | b"pre" | b"table" => {
+                self.open(std::str::from_utf8(name).unwrap_or("section"), &id);
+            }
+            b"stanza" => {
+                self.open("stanza", &id);
+                self.stanzas += 1;
+            }
+            b"p" if self.in_paragraph() => self.open.push(None),
+            b"p" => self.open(if self.stanzas > 0 { "v" } else { "p" }, &id),
+            b"em" => self.open("emphasis", ""),
+            b"blockquote" => self.open("cite", &id),
+            b"notes" => {
+                self.in_notes = true;
+                self.open.push(None);
+            }
+            b"notebody" => self.open("section", &id),
+            b"a" => match href() {
+                Some(h) => self.open("a", &format!(" l:href=\"{}\"", escape(&h))),
+                None => self.open.push(None),
+            },
+            b"note" => match href() {
+                Some(h) => {
+                    self.note_count += 1;
+                    self.open("a", &format!(" l:href=\"{}\" type=\"note\"", escape(&h)));
+                }
+                None => self.open.push(None),
+            },
+            b"ul" | b"ol" => {
+                self.lists.push(if name == b"ol" { Some(1) } else { None });
+                self.open.push(None);
+            }
+            b"li" => {
+                let bullet = match self.lists.last_mut() {
+                    Some(Some(n)) => {
+                        *n += 1;
+                        format!("{}. ", *n - 1)
+                    }
+                    _ => String::from("• "),
+                };
+                self.open("p", &id);
+                self.out(&bullet);
+            }
+            b"tr" => {
+                self.open("p", &id);
+                self.first_cell = true;
+            }
+            b"td" | b"th" => {
+                if !self.first_cell {
+                    self.out(" | ");
+                }
+                self.first_cell = false;
+                self.open.push(None);
+            }
+            _ => self.open.push(None),
+        }
+    }
+
+    fn end(&mut self, name: &[u8]) {
+        match name {
+            b"notes" => self.in_notes = false,
+            b"ul" | b"ol" => { self.lists.pop(); }
+            b"stanza" => self.stanzas = self.stanzas.saturating_sub(1),
+            _ => (),
+        }
+        if let Some(Some((n, _))) = self.open.pop() {
+            self.out(&format!("</{}>", n));
+        }
+    }
+
+    fn empty<B: std::io::BufRead>(&mut self, reader: &Reader<B>, e: &BytesStart) {
+        match local(e.name()) {
+            b"br" => self.line_break(),
+            b"p" if !self.in_paragraph() => self.out("<empty-line/>"),
+            b"note" => {
+                if let Some(h) = attr(reader, e, b"href") {
+                    self.note_count += 1;
+                    let s = format!("<a l:href=\"#{}\" type=\"note\">[{}]</a>",
+                                    escape(h.trim_start_matches('#')), self.note_count);
+                    self.out(&s);
+                }
+            }
+            b"td" | b"th" => {
+                self.start(reader, e);
+                self.end(b"td");
+            }
+            _ => (),
+        }
+    }
+}
+
+fn convert_body(data: &[u8]) -> anyhow::Result<Body> {
+    let mut reader = Reader::from_reader(data);
+    let mut buf = Vec::new();
+    let mut body = Body::default();
+    let mut depth = 0;
+    loop {
+        match reader.read_event(&mut buf) {
+            // The root element is the body itself.
+            Ok(Event::Start(_)) if depth == 0 => depth += 1,
+            Ok(Event::Start(ref e)) => {
+                depth += 1;
+                body.start(&reader, e);
+            }
+            Ok(Event::End(ref e)) => {
+                depth -= 1;
+                if depth > 0 {
+                    body.end(local(e.name()));
+                }
+            }
+            Ok(Event::Empty(ref e)) => body.empty(&reader, e),
+            Ok(Event::Text(e)) if depth > 0 => {
+                let t = e.unescape_and_decode(&reader)?;
+                body.out(&escape(&t));
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                return Err(anyhow::anyhow!("Error in the FB3 body at position {}: {:?}",
+                                           reader.buffer_position(), e))
+            }
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(body)
+}
+
+// Convert the FB3 book into FB2.  The `name` (e.g. the name of the
+// file) is the title of the book if the description doesn't have one.
+pub fn to_fb2(data: &[u8], name: &str) -> anyhow::Result<Vec<u8>> {
+    let mut za = zip::ZipArchive::new(std::io::Cursor::new(data))
+                 .context("the FB3 book is not a zip archive")?;
+    let desc_name = find_part(&mut za, "", "/book", "description.xml")?;
+    let body_name = find_part(&mut za, &desc_name, "/body", "body.xml")?;
+    let mut desc = parse_description(&read_entry(&mut za, &desc_name)?)?;
+    let body = convert_body(&read_entry(&mut za, &body_name)?)?;
+
+    desc.id = desc.id.or_else(|| Some(format!("fb3:{:016x}", meta::content_hash(data))));
+    desc.title = desc.title.map(|t| t.trim().to_string()).filter(|t| !t.is_empty())
+                     .or_else(|| Some(name.to_string()));
+    let mut w = fb2::Writer::describe(&desc);
+    w.raw(&body.text);
+    w.note(&body.notes);
+    Ok(w.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The series, the language and the subjects of the description get
+    // into the `<title-info>` of the FB2.
+    #[test]
+    fn description() {
+        let xml = r#"<fb3-description id="b1" version="1.0">
+            <title><main>Сказка</main></title>
+            <sequence number="2"><title><main>Серия</main></title>
+              <sequence number="5"><title><main>Подсерия</main></title></sequence>
+            </sequence>
+            <fb3-classification><subject>Фантастика</subject></fb3-classification>
+            <fb3-relations><subject link="author" id="a1"><first-name>Иван</first-name>
+              <last-name>Петров</last-name></subject></fb3-relations>
+            <lang>ru</lang></fb3-description>"#;
+        let d = parse_description(xml.as_bytes()).unwrap();
+        let mut w = fb2::Writer::describe(&d);
+        w.p("Текст.");
+        let d = meta::parse_description(&w.finish()).unwrap();
+        assert_eq!(d.id.as_deref(), Some("b1"));
+        assert_eq!(d.title.as_deref(), Some("Сказка"));
+        assert_eq!(d.authors, ["Иван Петров"]);
+        assert_eq!((d.series.as_deref(), d.series_number), (Some("Серия"), Some(2)));
+        assert_eq!(d.lang.as_deref(), Some("ru"));
+        assert_eq!(d.genres, ["Фантастика"]);
+    }
+}
@@ -0,0 +1,127 @@
+// Fuzzy search of the books in the library by their titles, authors
+// and series, the way fzf does it: the letters of the query have to
+// appear in this order, but not necessarily one after another, and the
+// closer they are (and the more of them start words) the better the
+// match.  The words of the query are matched separately, so the title
+// and the author can be given in any order.  Both the query and the
+// books are transliterated into Latin letters, so that `voina` finds
+// `Война и мир`.
+
+use fuzzy_matcher::{
+    FuzzyMatcher, skim::SkimMatcherV2
+};
+use crate::library::Entry;
+
+// The text as lowercase ASCII.
+pub fn fold(s: &str) -> String {
+    deunicode::deunicode(s).to_lowercase()
+}
+
+// What we search in for every book.
+fn haystack(e: &Entry) -> String {
+    let mut s = e.title.clone();
+    for a in &e.authors {
+        s.push(' ');
+        s.push_str(a);
+    }
+    if let Some(series) = &e.series {
+        s.push(' ');
+        s.push_str(series);
+    }
+    fold(&s)
+}
+
+pub struct Finder<'a> {
+    books: Vec<(&'a Entry, String)>,
+    matcher: SkimMatcherV2,
+    // The last query and its matches (indices into `books`), the
+    // query that extends it only has to look at these.
+    last: Option<(String, Vec<usize>)>,
+}
+
+impl<'a> Finder<'a> {
+    pub fn new(books: impl Iterator<Item = &'a Entry>) -> Finder<'a> {
+        let mut books: Vec<(&Entry, String)> = books.map(|e| (e, haystack(e))).collect();
+        books.sort_by_cached_key(|(e, _)| e.title.to_lowercase());
+        Finder {
+            books,
+            matcher: SkimMatcherV2::default(),
+            last: None,
+        }
+    }
+
+    fn score(&self, hay: &str, words: &[&str]) -> Option<i64> {
+        words.iter().map(|w| self.matcher.fuzzy_match(hay, w)).sum()
+    }
+
+    // The books that match the `query`, the best matches first.  The
+    // books that match equally well are sorted by the title.
+    pub fn find(&mut self, query: &str) -> Vec<&'a Entry> {
+        let q = fold(query);
+        let words: Vec<&str> = q.split_whitespace().collect();
+        let candidates: Vec<usize> = match self.last.take() {
+            Some((last, m)) if !last.is_empty() && q.starts_with(&last) => m,
+            _ => (0..self.books.len()).collect(),
+        };
+        let mut found: Vec<(i64, usize)> = candidates.into_iter().filter_map(|i| {
+            Some((self.score(&self.books[i].1, &words)?, i))
+        }).collect();
+        // The books are sorted by the title.
+        found.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        let res = found.iter().map(|(_, i)| self.books[*i].0).collect();
+        self.last = Some((q, found.into_iter().map(|(_, i)| i).collect()));
+        res
+    }
+
+    // The book that the `query` names, if there is no doubt: the only
+    // match, or the only book whose title is the query.
+    pub fn best(&mut self, query: &str) -> Option<&'a Entry> {
+        let found = self.find(query);
+        if found.len() == 1 {
+            return found.first().copied();
+        }
+        let q = fold(query);
+        let mut exact = found.iter().filter(|e| fold(&e.title) == q);
+        match (exact.next(), exact.next()) {
+            (Some(e), None) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(title: &str, author: &str) -> Entry {
+        Entry { path: format!("{}.fb2", title), id: String::new(), title: title.to_string(),
+                authors: vec![author.to_string()], series: None, series_number: None,
+                genres: Vec::new(), lang: None }
+    }
+
+    fn titles<'a>(found: &[&'a Entry]) -> Vec<&'a str> {
+        found.iter().map(|e| e.title.as_str()).collect()
+    }
+
+    #[test]
+    fn transliterated() {
+        let books = [book("Война и мир", "Лев Толстой"), book("Воскресение", "Лев Толстой"),
+                     book("Anna Karenina", "Leo Tolstoy")];
+        let mut f = Finder::new(books.iter());
+        assert_eq!(titles(&f.find("voina")), ["Война и мир"]);
+        // The words of the query match separately, in any order.
+        assert_eq!(titles(&f.find("tolstoi mir")), ["Война и мир"]);
+        assert_eq!(f.best("voina").map(|e| e.title.as_str()), Some("Война и мир"));
+        assert!(f.find("dostoevskii").is_empty());
+    }
+
+    // The letters of the query that are close together and start the
+    // words rank higher, the title only decides between the equal.
+    #[test]
+    fn ranked() {
+        let books = [book("Dolgij marsh", "X"), book("Sudomojka", "X"),
+                     book("Dom u ozera", "X"), book("Dom", "X")];
+        let mut f = Finder::new(books.iter());
+        assert_eq!(titles(&f.find("dom")), ["Dom", "Dom u ozera", "Dolgij marsh", "Sudomojka"]);
+    }
+}
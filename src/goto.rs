@@ -0,0 +1,71 @@
+// Targets of the go-to command (the `:` key and the `--goto` option).
+
+use crate::BookState;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    // Percentage of the text of the book.
+    Percent(usize),
+    // Chapter number as in the list of chapters, starting with 1.
+    Chapter(usize),
+    // Exact position, e.g. the one stored in the settings.
+    Position(BookState),
+}
+
+// Parse the target, which is one of:
+//    - "50%" --- percentage of the book;
+//    - "c5" --- chapter number 5;
+//    - "123.4.2" --- text node, word and character offsets, where the
+//      last two can be omitted.
+pub fn parse_target(s: &str) -> Option<Target> {
+    let s = s.trim();
+    if let Some(p) = s.strip_suffix('%') {
+        let p = p.trim().parse::<usize>().ok()?;
+        return if p <= 100 { Some(Target::Percent(p)) } else { None };
+    }
+    if let Some(c) = s.strip_prefix('c') {
+        return match c.trim().parse::<usize>() {
+            Ok(n) if n > 0 => Some(Target::Chapter(n)),
+            _ => None,
+        };
+    }
+
+    let parts = s.split('.')
+                 .map(|x| x.parse::<usize>())
+                 .collect::<Result<Vec<_>, _>>().ok()?;
+    match parts[..] {
+        [t] => Some(Target::Position(BookState { tag_count: t,
+                                                  ..BookState::default() })),
+        [t, w] => Some(Target::Position(BookState { tag_count: t,
+                                                     word_offset: w,
+                                                     char_offset: 0 })),
+        [t, w, c] => Some(Target::Position(BookState { tag_count: t,
+                                                        word_offset: w,
+                                                        char_offset: c })),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forms() {
+        assert_eq!(parse_target("50%"), Some(Target::Percent(50)));
+        assert_eq!(parse_target(" 100 % "), Some(Target::Percent(100)));
+        assert_eq!(parse_target("c5"), Some(Target::Chapter(5)));
+        let pos = |t, w, c| Some(Target::Position(BookState { tag_count: t, word_offset: w,
+                                                             char_offset: c }));
+        assert_eq!(parse_target("123"), pos(123, 0, 0));
+        assert_eq!(parse_target("123.4"), pos(123, 4, 0));
+        assert_eq!(parse_target("123.4.2"), pos(123, 4, 2));
+    }
+
+    #[test]
+    fn rejected() {
+        for s in ["", "101%", "x%", "c0", "c", "1.2.3.4", "1..2", "chapter"] {
+            assert_eq!(parse_target(s), None, "{:?}", s);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+// History of jumps (following links, going to a chapter, search, etc.)
+// that works like in a browser: we can go back to the position before
+// the jump and then forward again.  The history is kept per book in
+// the settings file.
+
+use serde::{
+    Serialize, Deserialize
+};
+use crate::BookState;
+
+// We don't need to remember every jump ever made.
+const MAX_LEN: usize = 100;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct History {
+    #[serde(default)]
+    back: Vec<BookState>,
+    #[serde(default)]
+    forward: Vec<BookState>,
+}
+
+impl History {
+    // We are about to jump away from the position `from`.
+    pub fn push(&mut self, from: BookState) {
+        if self.back.last() != Some(&from) {
+            self.back.push(from);
+        }
+        if self.back.len() > MAX_LEN {
+            self.back.remove(0);
+        }
+        self.forward.clear();
+    }
+
+    // The position to go back to from the position `cur`.
+    pub fn back(&mut self, cur: BookState) -> Option<BookState> {
+        let s = self.back.pop()?;
+        self.forward.push(cur);
+        Some(s)
+    }
+
+    // The position to go forward to from the position `cur`.
+    pub fn forward(&mut self, cur: BookState) -> Option<BookState> {
+        let s = self.forward.pop()?;
+        self.back.push(cur);
+        Some(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(tag_count: usize) -> BookState {
+        BookState { tag_count, ..BookState::default() }
+    }
+
+    #[test]
+    fn back_and_forward() {
+        let mut h = History::default();
+        // Jump 1 → 2 → 3.
+        h.push(at(1));
+        h.push(at(2));
+        assert_eq!(h.back(at(3)), Some(at(2)));
+        assert_eq!(h.back(at(2)), Some(at(1)));
+        assert_eq!(h.back(at(1)), None);
+        assert_eq!(h.forward(at(1)), Some(at(2)));
+        // A jump from 2 to 5 drops the way forward to 3.
+        h.push(at(2));
+        assert_eq!(h.forward(at(5)), None);
+        assert_eq!(h.back(at(5)), Some(at(2)));
+        assert_eq!(h.back(at(2)), Some(at(1)));
+    }
+
+    #[test]
+    fn max_len() {
+        let mut h = History::default();
+        for i in 0..MAX_LEN + 10 {
+            h.push(at(i));
+        }
+        let mut back = Vec::new();
+        let mut cur = at(MAX_LEN + 10);
+        while let Some(s) = h.back(cur) {
+            back.push(s.tag_count);
+            cur = s;
+        }
+        assert_eq!(back.len(), MAX_LEN);
+        assert_eq!(back.last(), Some(&10));
+    }
+}
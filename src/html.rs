@@ -0,0 +1,237 @@
+// HTML books (and XHTML, e.g. the chapters of EPUB files).  We don't
+// need a real HTML parser for reading: we go through the tags and the
+// text between them and tell `markup::Builder` about the elements that
+// matter for the structure of the text, ignoring the rest.
+
+use regex::{
+    Captures, Regex
+};
+use crate::{
+    markup, meta, txt
+};
+
+lazy_static! {
+    // Comments, doctypes and the like, or a tag: the slash of the closing
+    // tag, the name and the attributes.
+    static ref TOKEN: Regex = Regex::new(
+        r#"(?s)<!--.*?-->|<!\[CDATA\[.*?\]\]>|<[!?][^>]*>|<(/?)([A-Za-z][A-Za-z0-9:-]*)((?:[^>"']|"[^"]*"|'[^']*')*)>"#).unwrap();
+    static ref ATTR: Regex = Regex::new(
+        r#"([A-Za-z_:][-A-Za-z0-9_:.]*)(?:\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+)))?"#).unwrap();
+    static ref ENTITY: Regex = Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[A-Za-z][A-Za-z0-9]*);").unwrap();
+    // HTML whitespace, unlike `\s` it doesn't include the no-break space.
+    static ref SPACE: Regex = Regex::new(r"[ \t\r\n\x0c]+").unwrap();
+}
+
+fn named_entity(e: &str) -> Option<&'static str> {
+    Some(match e {
+        "amp" => "&", "lt" => "<", "gt" => ">", "quot" => "\"", "apos" => "'",
+        "nbsp" => "\u{a0}", "shy" => "", "thinsp" => "\u{2009}", "ensp" => "\u{2002}",
+        "emsp" => "\u{2003}", "mdash" => "—", "ndash" => "–", "minus" => "−",
+        "hellip" => "…", "laquo" => "«", "raquo" => "»", "ldquo" => "“",
+        "rdquo" => "”", "bdquo" => "„", "lsquo" => "‘", "rsquo" => "’",
+        "sbquo" => "‚", "bull" => "•", "middot" => "·", "copy" => "©",
+        "reg" => "®", "deg" => "°", "times" => "×", "sect" => "§",
+        "para" => "¶", "prime" => "′", "Prime" => "″", "numero" => "№",
+        _ => return None,
+    })
+}
+
+// Replace the character references with the characters.
+fn decode_entities(s: &str) -> String {
+    ENTITY.replace_all(s, |c: &Captures| {
+        let e = &c[1];
+        let n = if let Some(h) = e.strip_prefix("#x").or_else(|| e.strip_prefix("#X")) {
+            u32::from_str_radix(h, 16).ok()
+        } else {
+            e.strip_prefix('#').and_then(|d| d.parse().ok())
+        };
+        match n {
+            Some(n) => char::from_u32(n).map_or_else(|| c[0].to_string(), |c| c.to_string()),
+            None => named_entity(e).map_or_else(|| c[0].to_string(), str::to_string),
+        }
+    }).into_owned()
+}
+
+// The value of the attribute `name`.
+fn attr(attrs: &str, name: &str) -> Option<String> {
+    ATTR.captures_iter(attrs)
+        .find(|c| c[1].eq_ignore_ascii_case(name))
+        .map(|c| decode_entities(c.get(2).or_else(|| c.get(3)).or_else(|| c.get(4))
+                                  .map_or("", |v| v.as_str())))
+}
+
+fn collapse(s: &str) -> String {
+    SPACE.replace_all(s, " ").trim().to_string()
+}
+
+// Elements that don't have the closing tag.
+const VOID: &[&str] = &["area", "base", "br", "col", "embed", "hr", "img", "input",
+                        "link", "meta", "source", "wbr"];
+// Elements whose content we don't show.
+const SKIP: &[&str] = &["head", "title", "script", "style", "template", "svg", "math"];
+// Elements that separate paragraphs.
+const BLOCKS: &[&str] = &["p", "div", "body", "section", "article", "header", "footer",
+                          "main", "aside", "nav", "figure", "figcaption", "address",
+                          "center", "dl", "dt", "dd", "table", "caption", "tr", "br"];
+
+struct Converter {
+    b: markup::Builder,
+    // Names of the inline elements we are in, so that we can close them
+    // properly when the document doesn't.
+    inline: Vec<String>,
+    pre: bool,
+    // The element whose content we skip.
+    skip: Option<String>,
+}
+
+impl Converter {
+    fn start(&mut self, tag: &str, attrs: &str) {
+        let id = attr(attrs, "id").or_else(|| if tag == "a" { attr(attrs, "name") } else { None })
+                                  .filter(|id| !id.is_empty());
+        let heading = match tag.as_bytes() {
+            [b'h', l @ b'1'..=b'6'] => Some((l - b'0') as usize),
+            _ => None,
+        };
+        if let Some(l) = heading {
+            self.b.heading(l, id.as_deref());
+            return;
+        }
+        // The anchors of the blocks point to the text that follows, the
+        // anchors of the links go before the link.
+        if !BLOCKS.contains(&tag) {
+            if let Some(id) = &id {
+                self.b.anchor(id);
+            }
+        }
+        match tag {
+            _ if SKIP.contains(&tag) => self.skip = Some(tag.to_string()),
+            _ if BLOCKS.contains(&tag) => {
+                self.b.end_paragraph();
+                if let Some(id) = &id {
+                    self.b.anchor(id);
+                }
+            }
+            "em" | "i" | "cite" | "dfn" | "var" | "strong" | "b" | "s" | "strike" | "del" => {
+                self.b.inline(match tag {
+                    "strong" | "b" => "strong",
+                    "s" | "strike" | "del" => "strikethrough",
+                    _ => "emphasis",
+                });
+                self.inline.push(tag.to_string());
+            }
+            "a" => {
+                self.b.link(&attr(attrs, "href").unwrap_or_default());
+                self.inline.push(tag.to_string());
+            }
+            "td" | "th" => self.b.text(" "),
+            "hr" => self.b.rule(),
+            "blockquote" => self.b.quote(),
+            "ul" => self.b.list(None),
+            "ol" => self.b.list(Some(attr(attrs, "start").and_then(|s| s.trim().parse().ok())
+                                     .unwrap_or(1))),
+            "li" => self.b.item(),
+            "pre" => {
+                self.b.pre();
+                self.pre = true;
+            }
+            _ => (),
+        }
+    }
+
+    fn end(&mut self, tag: &str) {
+        match tag {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => self.b.end_heading(),
+            _ if BLOCKS.contains(&tag) => self.b.end_paragraph(),
+            "blockquote" => self.b.end_quote(),
+            "ul" | "ol" => self.b.end_list(),
+            "li" => self.b.end_item(),
+            "pre" => {
+                self.b.end_pre();
+                self.pre = false;
+            }
+            _ => {
+                // Close everything up to the matching inline element.
+                if let Some(i) = self.inline.iter().rposition(|t| t == tag) {
+                    while self.inline.len() > i {
+                        self.inline.pop();
+                        self.b.end_inline();
+                    }
+                }
+            }
+        }
+    }
+
+    fn text(&mut self, t: &str) {
+        if self.skip.is_some() || t.is_empty() {
+            return;
+        }
+        let t = decode_entities(t);
+        if self.pre {
+            self.b.text(&t);
+        } else {
+            self.b.text(&SPACE.replace_all(&t, " "));
+        }
+    }
+}
+
+// Convert the document into FB2.  The `name` (e.g. the name of the
+// file) is the title of the book unless the document says otherwise.
+pub fn to_fb2(data: &[u8], name: &str) -> Vec<u8> {
+    lazy_static! {
+        static ref TITLE: Regex = Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap();
+        static ref H1: Regex = Regex::new(r"(?is)<h1[^>]*>(.*?)</h1>").unwrap();
+        static ref HEADING: Regex = Regex::new(r"(?i)<h([1-6])[\s>]").unwrap();
+        static ref TAG: Regex = Regex::new(r"<[^>]*>").unwrap();
+    }
+    let text = txt::decode(data);
+    let title = [&*TITLE, &*H1].iter()
+                .filter_map(|r| r.captures(&text))
+                .map(|c| collapse(&decode_entities(&TAG.replace_all(&c[1], ""))))
+                .find(|t| !t.is_empty())
+                .unwrap_or_else(|| name.to_string());
+    let authors: Vec<String> = TOKEN.captures_iter(&text)
+        .filter(|c| c.get(2).is_some_and(|t| t.as_str().eq_ignore_ascii_case("meta")))
+        .filter(|c| attr(&c[3], "name").is_some_and(|n| n.eq_ignore_ascii_case("author")))
+        .filter_map(|c| attr(&c[3], "content")).map(|a| collapse(&a))
+        .filter(|a| !a.is_empty()).collect();
+    let top = HEADING.captures_iter(&text).filter_map(|c| c[1].parse().ok()).min().unwrap_or(1);
+
+    let mut c = Converter {
+        b: markup::Builder::new(&title, &authors,
+                                &format!("html:{:016x}", meta::content_hash(data)), top),
+        inline: Vec::new(), pre: false, skip: None,
+    };
+    let mut last = 0;
+    for t in TOKEN.captures_iter(&text) {
+        let m = t.get(0).unwrap();
+        c.text(&text[last..m.start()]);
+        last = m.end();
+        let tag = match t.get(2) {
+            Some(tag) => tag.as_str().to_lowercase(),
+            None => continue,
+        };
+        let closing = !t[1].is_empty();
+        if let Some(s) = &c.skip {
+            if closing && *s == tag {
+                c.skip = None;
+            }
+            continue;
+        }
+        let attrs = &t[3];
+        if closing {
+            c.end(&tag);
+        } else {
+            c.start(&tag, attrs);
+            // XHTML closes the empty elements right away.
+            if attrs.trim_end().ends_with('/') && !VOID.contains(&tag.as_str()) {
+                if c.skip.as_deref() == Some(tag.as_str()) {
+                    c.skip = None;
+                } else {
+                    c.end(&tag);
+                }
+            }
+        }
+    }
+    c.text(&text[last..]);
+    c.b.finish()
+}
@@ -0,0 +1,222 @@
+// The `info` subcommand: the meta information of the book from its
+// `<description>`, and, if asked, what is in the book.  Reading the
+// description is cheap, as we stop at `</description>`, so that `info`
+// can go through thousands of files; counting the words and measuring
+// the cover takes reading the whole book.
+
+use quick_xml::{
+    Reader, events::Event
+};
+use serde::Serialize;
+use crate::meta;
+
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct Size {
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Counts {
+    pub words: usize,
+    // Sections of the main body, the notes are not counted.
+    pub sections: usize,
+    // Pictures in `<binary>`.
+    pub images: usize,
+    pub cover: Option<Size>,
+}
+
+fn be16(d: &[u8], i: usize) -> Option<u32> {
+    Some(u16::from_be_bytes([*d.get(i)?, *d.get(i + 1)?]) as u32)
+}
+
+// The size of the PNG, GIF or JPEG image from its header.
+pub fn image_size(d: &[u8]) -> Option<Size> {
+    if d.starts_with(b"\x89PNG\r\n\x1a\n") && d.len() >= 24 {
+        let n = |i: usize| u32::from_be_bytes([d[i], d[i + 1], d[i + 2], d[i + 3]]);
+        return Some(Size { width: n(16), height: n(20) });
+    }
+    if d.starts_with(b"GIF8") && d.len() >= 10 {
+        return Some(Size { width: u16::from_le_bytes([d[6], d[7]]) as u32,
+                           height: u16::from_le_bytes([d[8], d[9]]) as u32 });
+    }
+    if !d.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+    // Go through the segments of the JPEG until the start of the frame,
+    // which has the size.
+    let mut i = 2;
+    while i + 3 < d.len() {
+        if d[i] != 0xff {
+            return None;
+        }
+        let marker = d[i + 1];
+        match marker {
+            // Padding.
+            0xff => i += 1,
+            // Markers without the length.
+            0x01 | 0xd0..=0xd7 => i += 2,
+            0xc0..=0xcf if ![0xc4, 0xc8, 0xcc].contains(&marker) => {
+                return Some(Size { width: be16(d, i + 7)?, height: be16(d, i + 5)? });
+            }
+            _ => i += 2 + be16(d, i + 2)? as usize,
+        }
+    }
+    None
+}
+
+// Count the words, the sections and the images of the book, and
+// measure the cover (the `<binary>` with the id `cover`).
+pub fn count(data: &[u8], cover: Option<&str>) -> anyhow::Result<Counts> {
+    let mut reader = Reader::from_reader(data);
+    let mut buf = Vec::new();
+    let mut counts = Counts::default();
+    let mut skip = false;
+    let mut in_notes = false;
+    // The base64 text of the cover that we are reading.
+    let mut cover_data: Option<String> = None;
+
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) => {
+                match e.name() {
+                    b"description" => skip = true,
+                    b"binary" => {
+                        skip = true;
+                        let attr = |name: &[u8]| e.attributes().flatten().find(|a| a.key == name)
+                            .and_then(|a| a.unescape_and_decode_value(&reader).ok());
+                        if attr(b"content-type").is_none_or(|t| t.starts_with("image/")) {
+                            counts.images += 1;
+                        }
+                        if cover.is_some() && attr(b"id").as_deref() == cover {
+                            cover_data = Some(String::new());
+                        }
+                    }
+                    b"body" => {
+                        in_notes = e.attributes().flatten().any(|a| a.key == b"name");
+                    }
+                    b"section" if !in_notes => counts.sections += 1,
+                    _ => (),
+                }
+            }
+            Ok(Event::End(ref e)) => {
+                match e.name() {
+                    b"description" => skip = false,
+                    b"binary" => {
+                        skip = false;
+                        if let Some(c) = cover_data.take() {
+                            let c: String = c.split_whitespace().collect();
+                            counts.cover = base64::decode(&c).ok()
+                                           .and_then(|d| image_size(&d));
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            Ok(Event::Text(e)) => {
+                if let Some(c) = &mut cover_data {
+                    c.push_str(&e.unescape_and_decode(&reader)?);
+                } else if !skip {
+                    counts.words += e.unescape_and_decode(&reader)?.split_whitespace().count();
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                return Err(anyhow::anyhow!("Error at position {}: {:?}",
+                           reader.buffer_position(), e))
+            }
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(counts)
+}
+
+#[derive(Serialize)]
+struct Info<'a> {
+    file: &'a str,
+    title: Option<&'a str>,
+    authors: &'a [String],
+    series: Option<&'a str>,
+    series_number: Option<u32>,
+    lang: Option<&'a str>,
+    genres: &'a [String],
+    publisher: Option<&'a str>,
+    year: Option<&'a str>,
+    #[serde(flatten)]
+    counts: Option<&'a Counts>,
+}
+
+// The information about the book as a single line of JSON, so that
+// the output for several books is one book per line.
+pub fn to_json(file: &str, desc: &meta::Description,
+               counts: Option<&Counts>) -> anyhow::Result<String> {
+    Ok(serde_json::to_string(&Info {
+        file,
+        title: desc.title.as_deref(),
+        authors: &desc.authors,
+        series: desc.series.as_deref(),
+        series_number: desc.series_number,
+        lang: desc.lang.as_deref(),
+        genres: &desc.genres,
+        publisher: desc.publisher.as_deref(),
+        year: desc.year.as_deref(),
+        counts,
+    })?)
+}
+
+// The information about the book as `Field: value` lines, the fields
+// that the book doesn't have are left out.
+pub fn to_text(desc: &meta::Description, counts: Option<&Counts>) -> String {
+    let mut fields: Vec<(&str, String)> = Vec::new();
+    fields.extend(desc.title.clone().map(|t| ("Title", t)));
+    if !desc.authors.is_empty() {
+        fields.push(("Authors", desc.authors.join(", ")));
+    }
+    fields.extend(desc.series.as_ref().map(|s| ("Series", match desc.series_number {
+        Some(n) => format!("{} #{}", s, n),
+        None => s.clone(),
+    })));
+    fields.extend(desc.lang.clone().map(|l| ("Language", l)));
+    if !desc.genres.is_empty() {
+        fields.push(("Genres", desc.genres.join(", ")));
+    }
+    fields.extend(desc.publisher.clone().map(|p| ("Publisher", p)));
+    fields.extend(desc.year.clone().map(|y| ("Year", y)));
+    if let Some(c) = counts {
+        fields.push(("Words", c.words.to_string()));
+        fields.push(("Sections", c.sections.to_string()));
+        fields.push(("Images", c.images.to_string()));
+        fields.extend(c.cover.map(|s| ("Cover", format!("{}x{}", s.width, s.height))));
+    }
+    fields.iter().map(|(k, v)| format!("{:<11}{}\n", format!("{}:", k), v)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(d: &[u8]) -> Option<(u32, u32)> {
+        image_size(d).map(|s| (s.width, s.height))
+    }
+
+    #[test]
+    fn headers() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend(640u32.to_be_bytes());
+        png.extend(480u32.to_be_bytes());
+        assert_eq!(size(&png), Some((640, 480)));
+        assert_eq!(size(b"GIF89a\x40\x01\xc8\x00"), Some((320, 200)));
+
+        // SOI, APP0 (JFIF) and SOF0 with the height 600 and the width 800.
+        let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe0, 0x00, 0x10];
+        jpeg.extend(b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+        jpeg.extend([0xff, 0xc0, 0x00, 0x11, 0x08, 0x02, 0x58, 0x03, 0x20, 0x03]);
+        assert_eq!(size(&jpeg), Some((800, 600)));
+        // Cut in the middle of the frame header.
+        assert_eq!(size(&jpeg[..jpeg.len() - 4]), None);
+        // Cut in the middle of APP0.
+        assert_eq!(size(&jpeg[..12]), None);
+        assert_eq!(size(b"not an image"), None);
+    }
+}
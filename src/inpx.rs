@@ -0,0 +1,137 @@
+// INPX catalogues of the large collections of books: a zip archive with
+// an `.inp` file for every archive of books in the collection, each
+// line of which describes a book in that archive.  The fields of the
+// line are separated by 0x04, and their order is given in the
+// `structure.info` file of the catalogue, or is the default one.
+// With the catalogue we don't have to read the archives to know what
+// books they have.
+
+use std::{
+    io::{
+        Cursor, Read
+    }, path::Path
+};
+use anyhow::Context;
+use crate::library::Entry;
+
+const DEFAULT_FIELDS: &[&str] = &["AUTHOR", "GENRE", "TITLE", "SERIES", "SERNO", "FILE",
+                                  "SIZE", "LIBID", "DEL", "EXT", "DATE", "LANG"];
+
+// The books of an archive of the collection.
+pub struct Archive {
+    // The name of the archive, e.g. `fb2-000024-030559.zip`.
+    pub name: String,
+    // The books with the paths relative to the archive, i.e. the names
+    // of the entries.
+    pub books: Vec<Entry>,
+}
+
+// `Last,First,Middle` as `First Middle Last`.
+fn author(a: &str) -> String {
+    let mut parts: Vec<&str> = a.split(',').map(str::trim).filter(|p| !p.is_empty()).collect();
+    if !parts.is_empty() {
+        let last = parts.remove(0);
+        parts.push(last);
+    }
+    parts.join(" ")
+}
+
+// The colon-separated list with a colon at the end, e.g. `sf:sf_space:`.
+fn list(s: &str) -> impl Iterator<Item = &str> {
+    s.split(':').map(str::trim).filter(|p| !p.is_empty())
+}
+
+// The book from the line of the `.inp` file, or None if it is deleted
+// from the collection or has no file.
+fn book(line: &str, fields: &[String]) -> Option<(Option<String>, Entry)> {
+    let values: Vec<&str> = line.split('\x04').collect();
+    let get = |name: &str| fields.iter().position(|f| f == name)
+                                 .and_then(|i| values.get(i)).map(|v| v.trim())
+                                 .filter(|v| !v.is_empty());
+    if get("DEL").is_some_and(|d| d != "0") {
+        return None;
+    }
+    let file = get("FILE")?;
+    let path = match get("EXT") {
+        Some(ext) => format!("{}.{}", file, ext.trim_start_matches('.')),
+        None => file.to_string(),
+    };
+    let title = get("TITLE").map_or_else(|| file.to_string(), String::from);
+    Some((get("FOLDER").map(String::from), Entry {
+        path,
+        id: String::new(),
+        title,
+        authors: get("AUTHOR").map_or(Vec::new(), |a| list(a).map(author).collect()),
+        series: get("SERIES").map(String::from),
+        series_number: get("SERNO").and_then(|n| n.parse().ok()).filter(|n| *n > 0),
+        genres: get("GENRE").map_or(Vec::new(), |g| list(g).map(String::from).collect()),
+        lang: get("LANG").map(String::from),
+    }))
+}
+
+// Read the catalogue.  The books of the `.inp` file are in the archive
+// with the same name (or in the one given in the FOLDER field).
+pub fn parse(path: &Path) -> anyhow::Result<Vec<Archive>> {
+    let data = std::fs::read(path)
+               .with_context(|| format!("cannot read `{}'", path.display()))?;
+    let mut za = zip::ZipArchive::new(Cursor::new(&data[..]))
+                 .with_context(|| format!("`{}' is not an INPX catalogue", path.display()))?;
+    let mut names: Vec<String> = za.file_names().filter(|n| n.ends_with(".inp"))
+                                   .map(String::from).collect();
+    names.sort();
+    let mut text = |name: &str| -> anyhow::Result<String> {
+        let mut s = String::new();
+        za.by_name(name)?.read_to_string(&mut s)
+          .with_context(|| format!("cannot read `{}' in the catalogue", name))?;
+        Ok(s)
+    };
+    let fields: Vec<String> = match text("structure.info") {
+        Ok(s) => s.trim().split(';').filter(|f| !f.trim().is_empty())
+                  .map(|f| f.trim().to_uppercase()).collect(),
+        Err(_) => DEFAULT_FIELDS.iter().map(|f| f.to_string()).collect(),
+    };
+    let mut archives: Vec<Archive> = Vec::new();
+    for n in names {
+        let default = format!("{}.zip", n.trim_end_matches(".inp"));
+        for line in text(&n)?.lines() {
+            if let Some((folder, b)) = book(line, &fields) {
+                let name = folder.unwrap_or_else(|| default.clone());
+                match archives.iter_mut().rev().find(|a| a.name == name) {
+                    Some(a) => a.books.push(b),
+                    None => archives.push(Archive { name, books: vec![b] }),
+                }
+            }
+        }
+    }
+    Ok(archives)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record() {
+        let fields: Vec<String> = DEFAULT_FIELDS.iter().map(|f| f.to_string()).collect();
+        let line = "Стругацкий,Аркадий,Натанович:Стругацкий,Борис,Натанович:\x04sf:sf_social:\x04\
+                    Пикник на обочине\x04Миры братьев Стругацких\x045\x04123456\x04300000\x04\
+                    123456\x040\x04fb2\x042008-01-01\x04ru\x04";
+        let (folder, b) = book(line, &fields).unwrap();
+        assert_eq!(folder, None);
+        assert_eq!(b.path, "123456.fb2");
+        assert_eq!(b.title, "Пикник на обочине");
+        assert_eq!(b.authors, ["Аркадий Натанович Стругацкий", "Борис Натанович Стругацкий"]);
+        assert_eq!(b.series.as_deref(), Some("Миры братьев Стругацких"));
+        assert_eq!(b.series_number, Some(5));
+        assert_eq!(b.genres, ["sf", "sf_social"]);
+        assert_eq!(b.lang.as_deref(), Some("ru"));
+        // The deleted books are left out.
+        assert!(book(&line.replace("\x040\x04fb2", "\x041\x04fb2"), &fields).is_none());
+        // The order of the fields given by `structure.info`.
+        let fields: Vec<String> = ["FILE", "EXT", "FOLDER", "AUTHOR", "TITLE"]
+            .iter().map(|f| f.to_string()).collect();
+        let (folder, b) = book("7\x04.fb2\x04f-1.zip\x04Doe,John:\x04\x04", &fields).unwrap();
+        assert_eq!((folder.as_deref(), b.path.as_str()), (Some("f-1.zip"), "7.fb2"));
+        assert_eq!((b.title.as_str(), &b.authors[..]), ("7", &["John Doe".to_string()][..]));
+    }
+}
@@ -0,0 +1,638 @@
+// The library: the books in the directories listed under `library` in
+// `settings.yml`, including the ones inside zip archives.  Reading the
+// descriptions of all the books takes a while, so we keep them in an
+// index file together with the modification time and the size of the
+// files they are in, and only read the files that are new or changed.
+// The index of the archives of large collections can also be imported
+// from their INPX catalogues (see `inpx`), then we don't have to read
+// the archives at all.  The library screen lists the books by author,
+// series or genre, or the ones we have read recently, together with how
+// far we got in them.
+
+use std::{
+    collections::{
+        BTreeMap, HashMap, HashSet
+    }, fs::File, io::{
+        BufRead, BufReader, Cursor, Read, Seek, Write
+    }, path::{
+        Path, PathBuf
+    }
+};
+use anyhow::Context;
+use serde::{
+    Serialize, Deserialize
+};
+use termion::{
+    event::Key, input::Keys, style
+};
+use crate::{
+    archive, find, inpx, meta, overlay
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Entry {
+    // The location of the book the way `load_book` takes it, e.g.
+    // `/books/lib.zip:book.fb2`.
+    pub path: String,
+    // The identity of the book (see `meta::book_id`), the key of the
+    // saved position.  We don't know it for the books imported from
+    // INPX, those are known by the path.
+    pub id: String,
+    pub title: String,
+    pub authors: Vec<String>,
+    #[serde(default)]
+    pub series: Option<String>,
+    #[serde(default)]
+    pub series_number: Option<u32>,
+    #[serde(default)]
+    pub genres: Vec<String>,
+    #[serde(default)]
+    pub lang: Option<String>,
+}
+
+// The books in a file (an archive may have thousands of them) and the
+// state of the file when we read it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileRecord {
+    // Modification time (seconds since the epoch) and size in bytes.
+    pub mtime: u64,
+    pub size: u64,
+    pub books: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Library {
+    // Keyed by the canonical path of the file.
+    #[serde(default)]
+    pub files: BTreeMap<String, FileRecord>,
+}
+
+// What has changed when we updated the library.
+#[derive(Debug, Default)]
+pub struct Update {
+    // Files that we have read and those that we couldn't read.
+    pub read: usize,
+    pub failed: usize,
+    // Files that are gone.
+    pub removed: usize,
+}
+
+impl Update {
+    pub fn changed(&self) -> bool {
+        self.read > 0 || self.removed > 0
+    }
+}
+
+// The modification time and the size of the file.
+pub fn file_state(path: &Path) -> Option<(u64, u64)> {
+    let m = std::fs::metadata(path).ok()?;
+    let mtime = m.modified().ok()?.duration_since(std::time::UNIX_EPOCH).ok()?.as_secs();
+    Some((mtime, m.len()))
+}
+
+impl Library {
+    // Read the index, the library is empty if there is none yet.
+    pub fn load(fname: &str) -> anyhow::Result<Library> {
+        match std::fs::File::open(fname) {
+            Ok(f) => serde_json::from_reader(std::io::BufReader::new(f))
+                     .with_context(|| format!("cannot read the library `{}'", fname)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Library::default()),
+            Err(e) => Err(e).with_context(|| format!("cannot open the library `{}'", fname)),
+        }
+    }
+
+    // Write the index into a temporary file first and put it in place
+    // of the old one when it is on the disk, so that a save that is
+    // interrupted leaves the old index as it was.
+    pub fn save(&self, fname: &str) -> anyhow::Result<()> {
+        let tmp = format!("{}.tmp", fname);
+        let write = || -> anyhow::Result<()> {
+            let mut w = std::io::BufWriter::new(File::create(&tmp)?);
+            serde_json::to_writer(&mut w, self)?;
+            w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            std::fs::rename(&tmp, fname)?;
+            Ok(())
+        };
+        write().map_err(|e| {
+            let _ = std::fs::remove_file(&tmp);
+            e.context(format!("cannot write the library `{}'", fname))
+        })
+    }
+
+    pub fn books(&self) -> impl Iterator<Item = &Entry> {
+        self.files.values().flat_map(|f| f.books.iter())
+    }
+
+    // Bring the index up to date with the files in the directories
+    // `dirs`: read the new and the changed ones and forget the ones
+    // that are gone.  The files that we know from elsewhere (e.g. the
+    // archives imported from INPX) are kept while they don't change.
+    // We tell `progress` how many of the files that we read we have
+    // done so far and the name of the file we are about to read.
+    pub fn update(&mut self, dirs: &[String],
+                  progress: &mut dyn FnMut(usize, usize, &Path)) -> Update {
+        let mut files = Vec::new();
+        for d in dirs {
+            let d = std::fs::canonicalize(d).unwrap_or_else(|_| PathBuf::from(d));
+            walk(&d, &mut files);
+        }
+        let found: HashSet<String> = files.iter().map(|f| f.to_string_lossy().into_owned())
+                                          .collect();
+        files.extend(self.files.keys().filter(|k| !found.contains(*k)).map(PathBuf::from));
+
+        let mut res = Update::default();
+        let mut stale = Vec::new();
+        for f in files {
+            let key = f.to_string_lossy().into_owned();
+            match (file_state(&f), self.files.get(&key)) {
+                (None, _) => {
+                    if self.files.remove(&key).is_some() {
+                        res.removed += 1;
+                    }
+                }
+                (Some((mtime, size)), Some(r)) if r.mtime == mtime && r.size == size => (),
+                (Some(s), _) => stale.push((f, key, s)),
+            }
+        }
+        let n = stale.len();
+        for (i, (f, key, (mtime, size))) in stale.into_iter().enumerate() {
+            progress(i, n, &f);
+            let mut books = Vec::new();
+            // We remember the files we cannot read too, so that we don't
+            // try them again until they change.
+            if scan_file(&f, &mut books).is_err() {
+                res.failed += 1;
+            }
+            res.read += 1;
+            self.files.insert(key, FileRecord { mtime, size, books });
+        }
+        res
+    }
+
+    // Import the INPX catalogue of the collection whose archives are in
+    // `dir`.  The books are taken as they are in the catalogue, and the
+    // archives are not read as long as they don't change.  Returns the
+    // number of the books imported, of the archives that are missing,
+    // and whether the index has changed, i.e. needs to be saved.
+    pub fn import_inpx(&mut self, inpx: &Path,
+                       dir: &Path) -> anyhow::Result<(usize, usize, bool)> {
+        let dir = std::fs::canonicalize(dir)
+                  .with_context(|| format!("cannot find `{}'", dir.display()))?;
+        let mut imported = 0;
+        let mut missing = 0;
+        let mut changed = false;
+        for a in inpx::parse(inpx)? {
+            let file = dir.join(&a.name);
+            let (mtime, size) = match file_state(&file) {
+                Some(s) => s,
+                None => {
+                    missing += 1;
+                    continue;
+                }
+            };
+            let key = file.to_string_lossy().into_owned();
+            let books: Vec<Entry> = a.books.into_iter().map(|mut b| {
+                b.path = format!("{}:{}", key, b.path);
+                b
+            }).collect();
+            imported += books.len();
+            let r = FileRecord { mtime, size, books };
+            if self.files.get(&key) != Some(&r) {
+                self.files.insert(key, r);
+                changed = true;
+            }
+        }
+        Ok((imported, missing, changed))
+    }
+
+    // The books of the series `name` ordered by their numbers.  Different
+    // authors may have series with the same name, so if some of the books
+    // are by the `authors`, we take only those.
+    pub fn series(&self, name: &str, authors: &[String]) -> Vec<&Entry> {
+        let books: Vec<&Entry> = self.books().filter(|e| e.series.as_deref() == Some(name))
+                                     .collect();
+        let mut by_authors: Vec<&Entry> = books.iter().copied()
+            .filter(|e| e.authors.iter().any(|a| authors.contains(a))).collect();
+        if by_authors.is_empty() {
+            by_authors = books;
+        }
+        by_authors.sort_by(|a, b| (a.series_number.unwrap_or(u32::MAX), &a.title)
+                                  .cmp(&(b.series_number.unwrap_or(u32::MAX), &b.title)));
+        by_authors
+    }
+
+    // Fill in the identities of the books that we know only by the path
+    // (the ones from INPX) from `ids`, the identities of the books that
+    // we have opened by their paths.
+    pub fn set_ids(&mut self, ids: &BTreeMap<String, String>) {
+        for f in self.files.values_mut() {
+            for b in f.books.iter_mut().filter(|b| b.id.is_empty()) {
+                if let Some(id) = ids.get(&b.path) {
+                    b.id = id.clone();
+                }
+            }
+        }
+    }
+}
+
+// The library entry for the book `data` (FB2) found at `path`.
+fn entry(path: String, data: &[u8]) -> anyhow::Result<Entry> {
+    let desc = meta::parse_description(data)?;
+    let id = meta::book_id(data, &desc);
+    // The books without a title are known by the name of the file.
+    let title = desc.title.clone().unwrap_or_else(|| {
+        let name = path.rsplit(['/', ':']).next().unwrap_or(&path);
+        archive::uncompressed_name(Path::new(name)).file_stem()
+            .map_or(String::new(), |s| s.to_string_lossy().into_owned())
+    });
+    Ok(Entry {
+        path, id, title,
+        authors: desc.authors, series: desc.series, series_number: desc.series_number,
+        genres: desc.genres, lang: desc.lang,
+    })
+}
+
+// The books in the file `path`: the book itself, or the books in the
+// zip archive.  The archives of the collections can be large, so we
+// read them entry by entry, and only read the whole file if it is a
+// book or is compressed.
+fn scan_file(path: &Path, books: &mut Vec<Entry>) -> anyhow::Result<()> {
+    let name = path.to_string_lossy().into_owned();
+    let mut f = BufReader::new(File::open(path)?);
+    let mut uname = path.to_path_buf();
+    let mut data = Vec::new();
+    if archive::is_zip(f.fill_buf()?) {
+        let za = zip::ZipArchive::new(f)?;
+        if !archive::is_package(&za) {
+            return scan_zip(&name, za, books);
+        }
+        data = std::fs::read(path)?;
+        uname.set_extension("fb3");
+    } else {
+        f.read_to_end(&mut data)?;
+        if let Some(d) = archive::decompress(&data)? {
+            data = d;
+            uname = archive::uncompressed_name(&uname);
+        }
+        if archive::is_zip(&data) {
+            let za = zip::ZipArchive::new(Cursor::new(data))?;
+            if !archive::is_package(&za) {
+                return scan_zip(&name, za, books);
+            }
+            data = za.into_inner().into_inner();
+            uname.set_extension("fb3");
+        }
+    }
+    books.push(entry(name, &crate::to_fb2(data, &uname)?)?);
+    Ok(())
+}
+
+// The books in the zip archive `za` of the file `name`.  A broken book
+// in the archive doesn't spoil the rest.
+fn scan_zip<R: Read + Seek>(name: &str, mut za: zip::ZipArchive<R>,
+                            books: &mut Vec<Entry>) -> anyhow::Result<()> {
+    for e in archive::books(&za) {
+        let mut book = || -> anyhow::Result<Entry> {
+            let mut d = archive::read(&mut za, &e)?;
+            let mut n = PathBuf::from(&e);
+            if let Some(u) = archive::decompress(&d)? {
+                d = u;
+                n = archive::uncompressed_name(&n);
+            }
+            entry(format!("{}:{}", name, e), &crate::to_fb2(d, &n)?)
+        };
+        if let Ok(b) = book() {
+            books.push(b);
+        }
+    }
+    Ok(())
+}
+
+// Collect the files in `dir` and its subdirectories that may have
+// books.  Hidden files are skipped, and so are the symbolic links to
+// directories, which could make us go in circles.
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) {
+    let mut entries: Vec<_> = match std::fs::read_dir(dir) {
+        Ok(rd) => rd.flatten().collect(),
+        Err(_) => return,
+    };
+    entries.sort_by_key(|e| e.file_name());
+    for e in entries {
+        if e.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let p = e.path();
+        match e.file_type() {
+            Ok(t) if t.is_dir() => walk(&p, files),
+            Ok(_) if p.is_file() && archive::may_have_books(&p) => files.push(p),
+            _ => (),
+        }
+    }
+}
+
+// The name of the author that we sort by: the last name first.
+fn sort_name(a: &str) -> String {
+    match a.rsplit_once(' ') {
+        Some((first, last)) => format!("{} {}", last, first).to_lowercase(),
+        None => a.to_lowercase(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum View {
+    Recent,
+    Authors,
+    Series,
+    Genres,
+    All,
+    Find,
+}
+
+// The keys that switch between the views.
+const VIEWS: &[(char, &str)] = &[('/', "find"), ('r', "recent"), ('a', "authors"),
+                                 ('s', "series"), ('g', "genres"), ('l', "all"),
+                                 ('R', "rescan")];
+
+pub enum Choice {
+    Open(String),
+    Rescan,
+}
+
+// The line of the list for the book, with the percentage that we have
+// read (if we have opened it).
+pub fn book_item(e: &Entry, progress: &HashMap<String, usize>) -> String {
+    let p = progress.get(&e.id).map_or(String::new(), |p| format!("{}%", p));
+    let mut s = format!("{:>4}  {}", p, e.title);
+    if !e.authors.is_empty() {
+        s.push_str(&format!(" — {}", e.authors.join(", ")));
+    }
+    if let Some(series) = &e.series {
+        match e.series_number {
+            Some(n) => s.push_str(&format!("  [{} #{}]", series, n)),
+            None => s.push_str(&format!("  [{}]", series)),
+        }
+    }
+    s
+}
+
+// The groups of books in the `view` sorted by their names.
+fn groups(lib: &Library, view: View) -> Vec<(String, Vec<&Entry>)> {
+    let mut m: BTreeMap<(String, String), Vec<&Entry>> = BTreeMap::new();
+    for e in lib.books() {
+        let keys: Vec<String> = match view {
+            View::Authors if e.authors.is_empty() => vec![String::from("(no author)")],
+            View::Authors => e.authors.clone(),
+            View::Series => e.series.iter().cloned().collect(),
+            View::Genres => e.genres.clone(),
+            _ => Vec::new(),
+        };
+        for k in keys {
+            let sort = if view == View::Authors { sort_name(&k) } else { k.to_lowercase() };
+            m.entry((sort, k)).or_default().push(e);
+        }
+    }
+    m.into_iter().map(|((_, k), mut books)| {
+        books.sort_by(|a, b| (&a.series, a.series_number, &a.title)
+                             .cmp(&(&b.series, b.series_number, &b.title)));
+        (k, books)
+    }).collect()
+}
+
+// Let the reader choose a book from the list.  Returns the choice, the
+// view to switch to, or neither if the list was closed.
+fn choose_book<R: Read, W: Write>(keys: &mut Keys<R>, out: &mut W, size: (usize, usize),
+                                  title: &str, books: &[&Entry],
+                                  progress: &HashMap<String, usize>)
+                                  -> anyhow::Result<(Option<Choice>, Option<View>)> {
+    let items: Vec<String> = books.iter().map(|e| book_item(e, progress)).collect();
+    Ok(match overlay::choose_action(keys, out, size, title, &items, 0, VIEWS)? {
+        Some((i, None)) => (Some(Choice::Open(books[i].path.clone())), None),
+        Some((_, Some(k))) => action(k),
+        None => (None, None),
+    })
+}
+
+fn action(k: char) -> (Option<Choice>, Option<View>) {
+    match k {
+        'r' => (None, Some(View::Recent)),
+        'a' => (None, Some(View::Authors)),
+        's' => (None, Some(View::Series)),
+        'g' => (None, Some(View::Genres)),
+        '/' => (None, Some(View::Find)),
+        'R' => (Some(Choice::Rescan), None),
+        _ => (None, Some(View::All)),
+    }
+}
+
+// Let the reader find the book by typing a part of its title, authors
+// or series (see `find`), starting with the `query`.  The list of the
+// matches is updated with every key, the best match first.  Returns the
+// path of the chosen book, or None if the search was cancelled.
+pub fn find_book<R: Read, W: Write>(keys: &mut Keys<R>, out: &mut W,
+                                    (width, height): (usize, usize),
+                                    finder: &mut find::Finder,
+                                    progress: &HashMap<String, usize>,
+                                    query: &str) -> anyhow::Result<Option<String>> {
+    // The query and the help line take two rows.
+    let rows = height.saturating_sub(2).max(1);
+    let mut query = query.to_string();
+    let mut found = finder.find(&query);
+    let mut sel = 0;
+    let mut top = 0;
+    write!(out, "{}", termion::cursor::Show)?;
+    let res = loop {
+        if sel < top {
+            top = sel;
+        } else if sel >= top + rows {
+            top = sel + 1 - rows;
+        }
+        write!(out, "{}", termion::clear::All)?;
+        for (i, e) in found.iter().enumerate().skip(top).take(rows) {
+            let item = overlay::fit(&format!(" {}", book_item(e, progress)), width);
+            write!(out, "{}", termion::cursor::Goto(1, (i - top + 2) as u16))?;
+            if i == sel {
+                write!(out, "{}{}{}", style::Invert, item, style::Reset)?;
+            } else {
+                write!(out, "{}", item)?;
+            }
+        }
+        let help = format!(" {}/{}  Enter: open  Esc: cancel  Up/Down: select",
+                           if found.is_empty() { 0 } else { sel + 1 }, found.len());
+        write!(out, "{}{}", termion::cursor::Goto(1, height as u16), overlay::fit(&help, width))?;
+        let q = overlay::fit(&format!("Find: {}", query), width);
+        write!(out, "{}{}", termion::cursor::Goto(1, 1), q)?;
+        out.flush()?;
+
+        let last = found.len().saturating_sub(1);
+        let mut edited = false;
+        match keys.next() {
+            Some(Ok(Key::Char('\n'))) => {
+                if let Some(e) = found.get(sel) {
+                    break Some(e.path.clone());
+                }
+            }
+            Some(Ok(Key::Esc)) | None => break None,
+            Some(Ok(Key::Up)) | Some(Ok(Key::Ctrl('p'))) => sel = sel.saturating_sub(1),
+            Some(Ok(Key::Down)) | Some(Ok(Key::Ctrl('n'))) => sel = (sel + 1).min(last),
+            Some(Ok(Key::PageUp)) => sel = sel.saturating_sub(rows),
+            Some(Ok(Key::PageDown)) => sel = (sel + rows).min(last),
+            Some(Ok(Key::Backspace)) => {
+                query.pop();
+                edited = true;
+            }
+            Some(Ok(Key::Ctrl('u'))) => {
+                query.clear();
+                edited = true;
+            }
+            Some(Ok(Key::Char(c))) => {
+                query.push(c);
+                edited = true;
+            }
+            Some(Ok(_)) => (),
+            Some(Err(e)) => return Err(e.into()),
+        }
+        if edited {
+            found = finder.find(&query);
+            sel = 0;
+        }
+    };
+    write!(out, "{}", termion::cursor::Hide)?;
+    Ok(res)
+}
+
+// The library screen, `/` searches for the book.  The `progress` is
+// the percentage that we have read of the books by their identity, and
+// `recent` are the identities of the books we read recently, the latest
+// first.  Returns the book to open or the request to scan the
+// directories again, or None if the reader closed the library.
+pub fn browse<R: Read, W: Write>(keys: &mut Keys<R>, out: &mut W, size: (usize, usize),
+                                 lib: &Library, progress: &HashMap<String, usize>,
+                                 recent: &[String]) -> anyhow::Result<Option<Choice>> {
+    let header = format!("Library: {} books", lib.books().count());
+    let recent: Vec<&Entry> = recent.iter()
+        .filter_map(|id| lib.books().find(|e| &e.id == id)).collect();
+    let mut view = if recent.is_empty() { View::Authors } else { View::Recent };
+    // Where we go back to when the search is cancelled.
+    let mut back = view;
+    // The selected group in every view.
+    let mut selected: HashMap<&str, usize> = HashMap::new();
+    // Preparing the books for the search takes a while in a large
+    // library, so we do it once.
+    let mut finder = None;
+    loop {
+        // Where we go if the view has nothing to show: back, or to all
+        // the books, or out of the library if it has no books at all.
+        let fallback = if back != view {
+            Some(back)
+        } else if view != View::All {
+            Some(View::All)
+        } else {
+            None
+        };
+        let mut empty = false;
+        let mut nothing = |keys: &mut Keys<R>, out: &mut W, text: &str| {
+            empty = true;
+            overlay::popup(keys, out, size, "Library", text, &[]).map(|_| (None, fallback))
+        };
+        let (choice, next) = match view {
+            View::Find => {
+                let f = finder.get_or_insert_with(|| find::Finder::new(lib.books()));
+                match find_book(keys, out, size, f, progress, "")? {
+                    Some(p) => (Some(Choice::Open(p)), None),
+                    None => (None, Some(back)),
+                }
+            }
+            View::Recent if recent.is_empty() => nothing(keys, out, "No books read recently.")?,
+            View::All if lib.books().next().is_none() => {
+                nothing(keys, out, "There are no books in the library.")?
+            }
+            View::Recent => choose_book(keys, out, size, &format!("{} — recently read", header),
+                                        &recent, progress)?,
+            View::All => {
+                let mut all: Vec<&Entry> = lib.books().collect();
+                all.sort_by_key(|e| e.title.to_lowercase());
+                choose_book(keys, out, size, &format!("{} — all", header), &all, progress)?
+            }
+            _ => {
+                let name = match view {
+                    View::Authors => "authors",
+                    View::Series => "series",
+                    _ => "genres",
+                };
+                let groups = groups(lib, view);
+                if groups.is_empty() {
+                    nothing(keys, out, &format!("The books have no {}.", name))?
+                } else {
+                    let items: Vec<String> = groups.iter()
+                        .map(|(k, b)| format!("{} ({})", k, b.len())).collect();
+                    let sel = selected.get(name).copied().unwrap_or(0);
+                    match overlay::choose_action(keys, out, size,
+                                                 &format!("{} — {}", header, name),
+                                                 &items, sel, VIEWS)? {
+                        Some((i, None)) => {
+                            selected.insert(name, i);
+                            let (k, books) = &groups[i];
+                            match choose_book(keys, out, size, k, books, progress)? {
+                                // Back to the list of the groups.
+                                (None, None) => continue,
+                                r => r,
+                            }
+                        }
+                        Some((_, Some(k))) => action(k),
+                        None => (None, None),
+                    }
+                }
+            }
+        };
+        match (choice, next) {
+            (Some(c), _) => return Ok(Some(c)),
+            (None, Some(v)) => {
+                if view != View::Find && !empty {
+                    back = view;
+                }
+                view = v;
+            }
+            (None, None) => return Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The index is only to be written when something has changed, and
+    // the writing leaves no temporary file behind.
+    #[test]
+    fn incremental() {
+        let dir = std::env::temp_dir().join(format!("termbook-library-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("books")).unwrap();
+        std::fs::write(dir.join("books/a.fb2"),
+                       "<FictionBook><description><title-info><book-title>A</book-title>\
+                        </title-info></description><body><p>Text.</p></body></FictionBook>")
+            .unwrap();
+        let dirs = [dir.join("books").to_string_lossy().into_owned()];
+        let index = dir.join("library.json").to_string_lossy().into_owned();
+
+        let mut lib = Library::default();
+        let u = lib.update(&dirs, &mut |_, _, _| ());
+        assert_eq!((u.read, u.failed, u.changed()), (1, 0, true));
+        lib.save(&index).unwrap();
+        assert!(!Path::new(&format!("{}.tmp", index)).exists());
+        let mut lib = Library::load(&index).unwrap();
+        assert_eq!(lib.books().map(|e| e.title.as_str()).collect::<Vec<_>>(), ["A"]);
+        assert!(!lib.update(&dirs, &mut |_, _, _| ()).changed());
+
+        // Importing the same catalogue again changes nothing.
+        let mut w = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        w.start_file("c.inp", zip::write::FileOptions::default()).unwrap();
+        w.write_all("Doe,John:\x04sf:\x04B\x04\x04\x041\x04\x04\x040\x04fb2\x04\x04en\x04\n"
+                    .as_bytes()).unwrap();
+        std::fs::write(dir.join("c.inpx"), w.finish().unwrap().into_inner()).unwrap();
+        std::fs::write(dir.join("c.zip"), b"").unwrap();
+        let (n, missing, changed) = lib.import_inpx(&dir.join("c.inpx"), &dir).unwrap();
+        assert_eq!((n, missing, changed), (1, 0, true));
+        assert_eq!(lib.import_inpx(&dir.join("c.inpx"), &dir).unwrap(), (1, 0, false));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -5,11 +5,11 @@ use quick_xml::{
     Reader, events::Event
 };
 use termion::{
-    event::Key, input::TermRead, raw::IntoRawMode,
+    event::Key, input::{Keys, TermRead}, raw::IntoRawMode,
     style,color,terminal_size
 };
 use clap::{
-    Arg,app_from_crate,
+    Arg,AppSettings,SubCommand,app_from_crate,
     crate_name,crate_version,crate_authors,crate_description
 };
 use anyhow::{
@@ -19,36 +19,177 @@ use serde::{
     Serialize, Deserialize
 };
 use std::{
-    io::{BufRead, Write, stdout, stdin},
-    mem, collections::BTreeMap
+    io::{BufRead, Read, Write, stdout, stdin},
+    mem, collections::{BTreeMap, HashMap}
 };
 #[macro_use]
 extern crate lazy_static;
 
-
-
-
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd)]
+mod annotations;
+mod archive;
+mod bookmarks;
+mod convert;
+mod dict;
+mod fb2;
+mod fb3;
+mod find;
+mod goto;
+mod history;
+mod html;
+mod info;
+mod inpx;
+mod library;
+mod markup;
+mod md;
+mod meta;
+mod overlay;
+mod scan;
+mod search;
+mod stats;
+mod txt;
+mod vocabulary;
+
+
+
+
+// Position in the book: the index of the text node, the index of
+// the word within that node, and the character offset within that word.
+// The latter is non-zero only for the lines that start with the tail
+// of a hyphenated (or a chopped) word.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, PartialOrd)]
 struct BookState {
     tag_count: usize,
     word_offset: usize,
+    #[serde(default)]
+    char_offset: usize,
 }
 
 #[derive(Serialize, Deserialize)]
 struct TBconfig {
+    // Book positions keyed by the identity of the book (see `meta::book_id`).
+    // Older versions used canonical paths as keys, such entries are
+    // migrated to the identity key when the book is opened.
     books: BTreeMap<String, BookState>,
+    // Secondary lookup: the identity of the book that we have seen last
+    // time at the given canonical path.  This helps when the identity
+    // changes, e.g. when the content of the book has been edited.
+    #[serde(default)]
+    paths: BTreeMap<String, String>,
+    // Where to show the status line.
+    #[serde(default)]
+    status_bar: StatusBar,
+    // Cached results of `scan::scan_book` keyed by the book identity.
+    #[serde(default)]
+    scans: BTreeMap<String, scan::BookScan>,
+    // Reading speed and the history of reading sessions.
+    #[serde(default)]
+    stats: stats::ReadingStats,
+    // Bookmarks and marks keyed by the book identity.
+    #[serde(default)]
+    bookmarks: BTreeMap<String, bookmarks::BookMarks>,
+    // History of jumps keyed by the book identity.
+    #[serde(default)]
+    history: BTreeMap<String, history::History>,
+    // Highlights and notes keyed by the book identity.
+    #[serde(default)]
+    annotations: BTreeMap<String, Vec<annotations::Annotation>>,
+    // Paths to the dictionaries: .ifo files of StarDict dictionaries
+    // or .index files of dictd ones.
+    #[serde(default)]
+    dictionaries: Vec<String>,
+    // Words marked as unknown in all the books.
+    #[serde(default)]
+    vocabulary: Vec<vocabulary::Entry>,
+    // Directories with the books that the library screen shows.
+    #[serde(default)]
+    library: Vec<String>,
+    // How much of the book we have read (in percent) when we closed it
+    // last time, keyed by the book identity.
+    #[serde(default)]
+    progress: BTreeMap<String, usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum StatusBar {
+    Off,
+    Top,
+    #[default]
+    Bottom,
+}
+
+impl TBconfig {
+    // Find the saved position of the book with the identity `id` that
+    // lives at `path`.
+    fn lookup_book(&mut self, id: &str, path: &str) -> Option<BookState> {
+        if let Some(s) = self.books.get(id) {
+            return Some(*s);
+        }
+        // Migrate the old path-keyed entry.
+        if let Some(s) = self.books.remove(path) {
+            self.books.insert(id.to_string(), s);
+            return Some(s);
+        }
+        // The book at this path used to have a different identity.
+        self.paths.get(path).and_then(|old| self.books.get(old)).copied()
+    }
+
+    fn update_book(&mut self, id: &str, path: &str, s: BookState) {
+        self.books.insert(id.to_string(), s);
+        self.paths.insert(path.to_string(), id.to_string());
+    }
+
+    // The scan of the book, we cache it as it requires parsing
+    // the entire book.
+    fn book_scan(&mut self, id: &str, data: &[u8]) -> anyhow::Result<scan::BookScan> {
+        match self.scans.get(id) {
+            // Scans made by older versions do not have word counts or
+            // the hash of the content.
+            Some(s) if s.size == data.len() && s.hash == meta::content_hash(data)
+                       && (s.total_words > 0 || s.total_chars == 0) => Ok(s.clone()),
+            _ => {
+                let s = scan::scan_book(data)?;
+                self.scans.insert(id.to_string(), s.clone());
+                Ok(s)
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
 struct Line {
-    // If the line is coming from the FB2 file, then there should
-    // be Some(offset) indicating the source position.  Otherwise
-    // if the line is inserted by further postprocessing, its source
-    // is None.
-    xml_offset: Option<BookState>,
+    // The position of the first visible character of the line in the
+    // FB2 file.  Lines that do not contain any text from the book
+    // (empty lines, decorations) get the position of the text node
+    // that follows them, so that restoring to such a line is exact.
+    xml_offset: BookState,
+    // Number of characters of the book text before the line, see
+    // `scan::text_len`.
+    text_offset: usize,
+    // The pieces of the book text that the line consists of.
+    words: Vec<Frag>,
     content: String
 }
 
+// A piece of a word from the book that we have put on a line.  We use
+// these to find the text on the screen, e.g. to highlight search results.
+#[derive(Debug, Clone, Copy)]
+struct Frag {
+    // Position of the first character of the piece.
+    pos: BookState,
+    // Number of characters in the piece.
+    len: usize,
+    // Visible column of the piece in the line.
+    col: usize,
+}
+
+impl Frag {
+    // Position right after the last character of the piece.
+    fn end(&self) -> BookState {
+        BookState { char_offset: self.pos.char_offset + self.len, ..self.pos }
+    }
+}
+
 #[derive(Debug)]
 enum Align {
     Left,
@@ -63,6 +204,7 @@ enum FBstyle {
     Title,
     Subtitle,
     Emph,
+    Strike,
     // Add more stuff
 }
 
@@ -85,7 +227,17 @@ struct WriterState {
     pub eof: bool,
     // We use these fields to annotate the lines with their positions
     // in the xml document, so that we could restore it on the next load.
+    // `xml_offset` is the position of the text node we are processing,
+    // and `line_start` is the position of the first word that we have
+    // put into the current buffer line (together with its text offset).
     pub xml_offset: BookState,
+    pub line_start: Option<(BookState, usize)>,
+    // Text offset of the beginning and of the end of the current
+    // text node.
+    pub node_text_offset: usize,
+    pub text_offset: usize,
+    // Pieces of words in the current buffer line.
+    pub frags: Vec<Frag>,
     // XXX DEBUG ONLY. We want to keep the collection of tags that we
     // are skipping.  When the collection will become empty, all tags
     // are handled.
@@ -101,8 +253,14 @@ struct WriterState {
     pub styles: Vec<FBstyle>,
     // Are we outputing the title right now
     pub in_title: bool,
-    // TODO implement parsing of a description and get rid of this field.
-    pub skip: bool,
+    // Are we outputing preformatted text (e.g. code from the books
+    // converted from Markdown) right now.
+    pub pre: bool,
+    // Do we break the words that don't fit into the line.
+    pub hyphenate: bool,
+    // Counts the text nodes and tells whether we are in `<description>`
+    // or `<binary>`, which we skip.
+    pub nodes: scan::NodeCounter,
     pub last_line_empty: bool,
     // Do we expect the next paragraph to come to be the first one in
     // the section, body, etc.  This impacts whether we add indent in the
@@ -114,47 +272,134 @@ struct WriterState {
 
 impl WriterState {
     fn line_done(&mut self) {
-        let mut t = mem::replace(&mut self.l, String::from(""));
-        // TODO this is not correct, as we store the xml offset that
-        // occurs at the *end* of the line, not at the beginning...
-        let o = self.xml_offset;
+        let mut t = mem::take(&mut self.l);
+        let (o, to) = match self.line_start.take() {
+            Some(o) => o,
+            None => (self.next_offset(), self.text_offset),
+        };
 
         let s = self.line_width - self.pos;
         // We might have not yet inserted the prefix, in which case
         // we are done here.
-        if t.len() == 0 {
-            self.lines.push(Line {xml_offset: Some(o), content: t});
+        let mut words = mem::take(&mut self.frags);
+        if t.is_empty() {
+            self.lines.push(Line {xml_offset: o, text_offset: to, words,
+                                  content: t});
             self.pos = 0;
             self.needs_prefix = true;
             self.last_line_empty = true;
             return
         }
 
-        match self.align {
+        let shift = match self.align {
             Align::Right => {
                 //print!("pr = {}; t= {}; s = {}\r\n", self.prefix, t, s);
                 let q = " ".to_owned().repeat(s);
                 if s > 0 {
                 t.insert_str(self.prefix.chars().count(), &q);
                 }
+                s
             }
             Align::Center => {
                 let q = " ".to_owned().repeat(s/2);
                 t.insert_str(self.prefix.chars().count(), &q);
+                s/2
             }
-            _ => ()
+            _ => 0
+        };
+        for f in words.iter_mut() {
+            f.col += shift;
         }
         // Close all the styles for the given line
         for s in self.styles.iter().rev() {
-            if let Some(s) = self.smap.get(&s) {
+            if let Some(s) = self.smap.get(s) {
                 t.push_str(&s.1);
             }
         }
-        self.lines.push(Line {xml_offset: Some(o), content: t});
+        self.lines.push(Line {xml_offset: o, text_offset: to, words, content: t});
         self.pos = 0;
         self.needs_prefix = true;
         self.last_line_empty = false;
     }
+    // The position of the text node that we haven't read yet.
+    fn next_offset(&self) -> BookState {
+        BookState { tag_count: self.xml_offset.tag_count + 1,
+                    word_offset: 0, char_offset: 0 }
+    }
+    // Remember that the current line starts at the character `c` of
+    // the word `w` in the current text node, unless the line already
+    // has some text in it.  The `t` is the number of characters in
+    // the text node before that position.
+    fn mark_line_start(&mut self, w: usize, c: usize, t: usize) {
+        if self.line_start.is_none() {
+            self.line_start = Some((BookState { word_offset: w, char_offset: c,
+                                                ..self.xml_offset },
+                                    self.node_text_offset + t));
+        }
+    }
+    // Push the piece `w` of the word number `word` in the current text
+    // node.  The piece starts at the character `c` of the word, and `t`
+    // is the number of characters in the text node before the word.
+    fn push_text(&mut self, w: &str, word: usize, c: usize, t: usize) {
+        self.mark_line_start(word, c, t + c);
+        if !w.is_empty() {
+            let pos = BookState { word_offset: word, char_offset: c,
+                                  ..self.xml_offset };
+            let col = self.prefix.chars().count() + self.pos;
+            self.frags.push(Frag { pos, len: w.chars().count(), col });
+        }
+        self.push_word(w);
+    }
+    // Push the preformatted text of the current text node: keep the
+    // lines and the indentation as they are, and instead of hyphenating
+    // break the lines that do not fit at the word boundaries.
+    fn push_pre(&mut self, s: &str) {
+        // Word number in the text node and the characters before it.
+        let mut word = 0;
+        let mut done = 0;
+        for (k, line) in s.split('\n').enumerate() {
+            if k > 0 {
+                self.line_done();
+                self.line += 1;
+            }
+            let line = line.replace('\t', "    ");
+            let base = line.as_ptr() as usize;
+            let mut col = 0;
+            for w in line.split_whitespace() {
+                let wcol = line[..w.as_ptr() as usize - base].chars().count();
+                let wlen = w.chars().count();
+                if self.pos > 0 && self.pos + wcol - col + wlen > self.line_width {
+                    self.line_done();
+                    self.line += 1;
+                } else {
+                    // The indentation that is too wide for the line is
+                    // cut, so that the word still fits if it can.
+                    let gap = (wcol - col).min(self.chars_left().saturating_sub(wlen));
+                    self.push_word(&" ".repeat(gap));
+                }
+                if wlen > self.chars_left() {
+                    // The first piece fills the rest of the line.
+                    let v: Vec<_> = w.chars().collect();
+                    let mut i = 0;
+                    while i < v.len() {
+                        if i > 0 {
+                            self.line_done();
+                            self.line += 1;
+                        }
+                        let n = self.chars_left().min(v.len() - i);
+                        let l = v[i..i + n].iter().collect::<String>();
+                        self.push_text(&l, word, i, done);
+                        i += n;
+                    }
+                } else {
+                    self.push_text(w, word, 0, done);
+                }
+                col = wcol + wlen;
+                word += 1;
+                done += wlen;
+            }
+        }
+    }
     fn _dprint(&self) {
         print!("line: {}, pos: {}, eof: {}", self.line, self.pos, self.eof);
     }
@@ -166,7 +411,9 @@ impl WriterState {
         self.line_width - self.pos
     }
     fn push_empty_line(&mut self) {
-        self.lines.push(Line {xml_offset: None, content: String::from("")});
+        let o = self.next_offset();
+        self.lines.push(Line {xml_offset: o, text_offset: self.text_offset,
+                              words: Vec::new(), content: String::from("")});
         self.last_line_empty = true;
     }
     fn ensure_empty_line(&mut self) {
@@ -194,7 +441,7 @@ impl WriterState {
             self.l.push_str(&self.prefix);
             // push all the styles in the beginning of the line
             for s in &self.styles {
-                if let Some(s) = self.smap.get(&s) {
+                if let Some(s) = self.smap.get(s) {
                   self.l.push_str(&s.0);
                 }
             }
@@ -203,7 +450,7 @@ impl WriterState {
         if self.in_title {
             self.l.push_str(&w.to_uppercase());
         } else  {
-            self.l.push_str(&w);
+            self.l.push_str(w);
         }
         // XXX we often know the length of the string, as we sometimes
         // check whether the word would fit into the remaining line...
@@ -249,6 +496,12 @@ impl std::fmt::Display for ProcessingError {
     }
 }
 
+lazy_static! {
+    // Splits a word into the leading punctuation, the word itself and
+    // the trailing punctuation, see `OutText::out`.
+    static ref WORD_RE: regex::Regex = regex::Regex::new(r"(\W*)(\w*)(\W*)").unwrap();
+}
+
 trait OutText {
     fn out (&self, s: &str, state: &mut WriterState) -> anyhow::Result<()>;
 }
@@ -257,7 +510,7 @@ impl OutText for Standard {
     fn out (&self, s: &str, state: &mut WriterState) -> anyhow::Result<()> {
         // Sometimes we can get bogous inputs that are either empty or consist
         // only of whitespaces.
-        if s.trim().len() == 0 {
+        if s.trim().is_empty() {
             return Ok(());
         }
 
@@ -267,16 +520,15 @@ impl OutText for Standard {
             state.push_word(" ");
         }
 
-        lazy_static! {
-            static ref RE: regex::Regex = regex::Regex::new(r"(\W*)(\w*)(\W*)").unwrap();
-        }
+        // Number of characters in the words before `w`.
+        let mut done = 0;
         for (i, w) in s.split_whitespace().enumerate() {
             let wlen = w.chars().count();
 
             let space = if i == 0 { "" } else { " " };
             if wlen + space.len() <= state.chars_left() {
                 state.push_word(space);
-                state.push_word(w);
+                state.push_text(w, i, 0, done);
             } else {
                 // Note that the following regexp is used to peel off
                 // punctuation from the sequence of non-whitespace caracters
@@ -290,7 +542,7 @@ impl OutText for Standard {
                 // way (as long as we don't compile regrexp all the time).
                 // It is perfectly fine to reconsider this decision later
                 // in case we hit a niticeable performance penalty.
-                let caps = RE.captures(w).ok_or(ProcessingError::new(
+                let caps = WORD_RE.captures(w).ok_or(ProcessingError::new(
                         &format!("regexp failed while recognising `{}'", w)))?;
                 let wprefix = caps.get(1).ok_or(ProcessingError::new(
                         &format!("error getting caputure group 1 in `{}'", w)))?
@@ -305,7 +557,12 @@ impl OutText for Standard {
                 // FIXME we don't need to create vector, inline the code!
                 // Hyphenate the word
                 let mut triples = Vec::new();
-                for n in self.hyphenate(wmiddle).breaks {
+                let breaks = if state.hyphenate {
+                    self.hyphenate(wmiddle).breaks
+                } else {
+                    Vec::new()
+                };
+                for n in breaks {
                     let (head, tail) = wmiddle.split_at(n);
                     let hyphen = if head.ends_with('-') { "" } else { "-" };
                     triples.push((head, hyphen, tail));
@@ -325,15 +582,17 @@ impl OutText for Standard {
                                 <= state.line_width);
                         // push space only if we are not at the first word
                         state.push_word(space);
-                        state.push_word(wprefix);
-                        state.push_word(head);
+                        let c = wprefix.chars().count();
+                        state.push_text(wprefix, i, 0, done);
+                        state.push_text(head, i, c, done);
                         state.push_word(hyp);
                         state.line_done();
 
-                        state.push_word(tail);
-                        state.push_word(wpostfix);
-                        // update xml_txt_off with the current word count `i`
-                        state.xml_offset.word_offset = i;
+                        // The next line starts in the middle of the word.
+                        let c = c + head.chars().count();
+                        state.push_text(tail, i, c, done);
+                        state.push_text(wpostfix, i, c + tail.chars().count(),
+                                        done);
                         hyp_found = true;
                         state.line += 1;
                         break;
@@ -343,26 +602,25 @@ impl OutText for Standard {
                 // If we didn't find the hyphenation, break right here
                 if !hyp_found {
                     state.line_done();
-                    // update xml_txt_off with the current word count `i`
-                    state.xml_offset.word_offset = i;
-
                     state.line += 1;
                     // If `w` is crazily long, we'll just break in the middle
                     if wlen > state.line_width {
                         // FIXME this is quite weird now, the last chunk of
                         // the `w` might be shorter than the line...
                         let v: Vec<_> = w.chars().collect();
-                        for l in v.chunks (state.line_width) {
-                            state.l = l.iter().collect::<String>();
+                        for (k, l) in v.chunks (state.line_width).enumerate() {
+                            let l = l.iter().collect::<String>();
+                            state.push_text(&l, i, k * state.line_width, done);
                             state.line_done();
                             state.line += 1;
                         }
                     } else {
-                        state.push_word(w);
+                        state.push_text(w, i, 0, done);
                     }
                 }
 
             }
+            done += wlen;
         }
 
         if s.ends_with(" ") && state.chars_left() >= 1 {
@@ -383,11 +641,15 @@ fn crank<B: BufRead> (reader : &mut Reader<B>,
 
     let l = ws.line + count;
     while !ws.eof && ws.line < l {
-        match reader.read_event(&mut buf) {
+        let ev = reader.read_event(&mut buf);
+        let node = match &ev {
+            Ok(e) => ws.nodes.feed(e),
+            Err(_) => None,
+        };
+        match ev {
             Ok(Event::Start(ref e)) => {
                 match e.name() {
 
-                    b"binary" | b"description" => { ws.skip = true; }
                     b"p" => {
                         if !ws.in_title && !ws.first_paragraph {
                             ws.ensure_new_line();
@@ -400,6 +662,10 @@ fn crank<B: BufRead> (reader : &mut Reader<B>,
                         }
                     }
                     b"stanza" | b"section" => (),
+                    b"pre" => {
+                        ws.ensure_empty_line();
+                        ws.pre = true;
+                    }
                     b"poem" => {
                         ws.ensure_empty_line();
                     }
@@ -417,6 +683,9 @@ fn crank<B: BufRead> (reader : &mut Reader<B>,
                     b"strong" => {
                         ws.push_fmt_start(FBstyle::Strong);
                     }
+                    b"strikethrough" => {
+                        ws.push_fmt_start(FBstyle::Strike);
+                    }
                     b"title" => {
                         ws.ensure_empty_line();
                         ws.push_fmt_start(FBstyle::Title);
@@ -425,7 +694,7 @@ fn crank<B: BufRead> (reader : &mut Reader<B>,
                     b"subtitle" => {
                         ws.ensure_empty_line();
                         ws.push_fmt_start(FBstyle::Subtitle);
-                        ws.push_word(&"§ ".to_string());
+                        ws.push_word("§ ");
                         //ws.in_title = true;
                     }
                     b"text-author" => {
@@ -433,21 +702,19 @@ fn crank<B: BufRead> (reader : &mut Reader<B>,
                         // otherwise we need to have a stack of aligns...
                         ws.align = Align::Right;
                         ws.ensure_new_line();
-                        ws.push_word(&"– ".to_string());
+                        ws.push_word("– ");
                     }
 
                     _ => {
-                        if !ws.skip {
+                        if !ws.nodes.skipping() {
                             ws.tags.insert(
                                 std::str::from_utf8(e.name())?.to_string());
                         }
-                        ()
                     }
                 }
             },
             Ok(Event::End(ref e)) => {
                 match e.name() {
-                    b"binary" | b"description" => { ws.skip = false; }
                     b"p" => {
                         ws.line_done();
                         if !ws.in_title && ws.first_paragraph {
@@ -460,6 +727,11 @@ fn crank<B: BufRead> (reader : &mut Reader<B>,
                     b"poem" => {
                         ws.ensure_empty_line();
                     }
+                    b"pre" => {
+                        ws.line_done();
+                        ws.pre = false;
+                        ws.ensure_empty_line();
+                    }
                     b"stanza" => {
                         ws.ensure_empty_line();
                     }
@@ -489,6 +761,9 @@ fn crank<B: BufRead> (reader : &mut Reader<B>,
                     b"strong" => {
                         ws.push_fmt_end(FBstyle::Strong);
                     }
+                    b"strikethrough" => {
+                        ws.push_fmt_end(FBstyle::Strike);
+                    }
                     b"title" => {
                         ws.push_fmt_end(FBstyle::Title);
                         ws.ensure_empty_line();
@@ -509,12 +784,15 @@ fn crank<B: BufRead> (reader : &mut Reader<B>,
                 }
             },
 
-            Ok(Event::Text(e)) => {
-                if !ws.skip {
-                  let t = e.unescape_and_decode(&reader)?;
-                  ws.xml_offset.tag_count += 1;
-                  ws.xml_offset.word_offset = 0;
-                  hyphenator.out (&t, ws)?;
+            Ok(Event::Text(e)) if node.is_some() => {
+                let t = e.unescape_and_decode(reader)?;
+                ws.xml_offset.tag_count = ws.nodes.tag_count;
+                ws.node_text_offset = ws.text_offset;
+                ws.text_offset += scan::text_len(&t);
+                if ws.pre {
+                    ws.push_pre(&t);
+                } else {
+                    hyphenator.out (&t, ws)?;
                 }
             },
             Ok(Event::Empty(e)) => {
@@ -523,7 +801,7 @@ fn crank<B: BufRead> (reader : &mut Reader<B>,
                         ws.push_empty_line();
                     }
                     _ => {
-                        if !ws.skip {
+                        if !ws.nodes.skipping() {
                             ws.tags.insert(
                                 std::str::from_utf8(e.name())?.to_string());
                         }
@@ -547,80 +825,1048 @@ fn crank<B: BufRead> (reader : &mut Reader<B>,
     Ok(())
 }
 
-// fill the screen starting from the line at index `line_idx`,
-// and assuming that the screen is `height` lines.
-fn print_n_lines (ws : &mut WriterState,
-                  start_idx : usize,
-                  lines : usize) -> usize {
+// Find the index of the line that is "closest" to the position `s`,
+// i.e. the line that contains the character at this position.
+//    - If we don't find the offset that is smaller than `s`, we
+//      start from the beginning of the book.
+//    - If the offset is too large (bogus config file) we'll end-up
+//      at the last line of the book.
+// Several lines (e.g. empty line and a decoration) may share the same
+// position, in which case we pick the first one.
+fn line_for_position(lines: &[Line], s: BookState) -> usize {
+    match lines.iter().rposition(|l| l.xml_offset <= s) {
+        Some(mut i) => {
+            while i > 0 && lines[i-1].xml_offset == lines[i].xml_offset {
+                i -= 1;
+            }
+            i
+        }
+        None => 0
+    }
+}
+
+// The book that we are reading: the parser, and the lines that
+// we have laid out so far.
+struct Book {
+    reader: Reader<Box<dyn BufRead>>,
+    hyphenator: Standard,
+    ws: WriterState,
+}
+
+impl Book {
+    // Prepare to lay out the book in lines of `line_width` characters.
+    // Without `color` the styles of the text are not shown.
+    fn new(data: Vec<u8>, line_width: usize, color: bool) -> anyhow::Result<Book> {
+        let reader : Reader<Box<dyn BufRead>> =
+            quick_xml::Reader::from_reader(Box::new(std::io::Cursor::new(data)));
+
+        // TODO: parse <description> of the book and choose the appropriate
+        // language, and possible get other meta-information.
+        let hyphenator = Standard::from_embedded(Language::Russian)?;
+
+        // Prepare the state structure for the xml parser.
+        let lines = Vec::new();
+        let l = String::from("");
+        let styles = Vec::new();
+        let tags = std::collections::HashSet::<String>::new();
+
+        // TODO read this from the config file.
+        let mut smap = std::collections::HashMap::new();
+        smap.insert(FBstyle::Strong,(style::Bold.to_string(), style::NoBold.to_string()));
+        smap.insert(FBstyle::Strike,(style::CrossedOut.to_string(),
+                                     style::NoCrossedOut.to_string()));
+        smap.insert(FBstyle::Title,(color::Fg(color::LightBlue).to_string(),
+                                    color::Fg(color::Reset).to_string()));
+
+        smap.insert(FBstyle::Subtitle,(color::Fg(color::LightBlue).to_string(),
+                                    color::Fg(color::Reset).to_string()));
+        smap.insert(FBstyle::Emph,(color::Fg(color::LightCyan).to_string(),
+                                    color::Fg(color::Reset).to_string()));
+
+        smap.insert(FBstyle::Bold,(color::Fg(color::LightGreen).to_string(),
+                                    color::Fg(color::Reset).to_string()));
+        if !color {
+            // The styles are still tracked, but they produce nothing.
+            for v in smap.values_mut() {
+                *v = (String::new(), String::new());
+            }
+        }
+
+        let ws = WriterState { line: 0, pos: 0,
+                               line_width,
+                               l,
+                               lines,
+                               eof: false,
+                               xml_offset: BookState::default(),
+                               line_start: None,
+                               node_text_offset: 0,
+                               text_offset: 0,
+                               frags: Vec::new(),
+                               tags,
+                               prefix: String::from(""), needs_prefix: true,
+                               align: Align::Left,
+                               smap,
+                               styles,
+                               in_title: false, pre: false, hyphenate: true,
+                               nodes: scan::NodeCounter::default(),
+                               last_line_empty: false,
+                               first_paragraph: true};
+        Ok(Book { reader, hyphenator, ws })
+    }
+
+    // Make sure that we have laid out at least `n` lines, unless the
+    // book is shorter than that.
+    fn ensure_lines(&mut self, n: usize) -> anyhow::Result<()> {
+        while !self.ws.eof && self.ws.lines.len() < n {
+            let count = n - self.ws.lines.len();
+            crank(&mut self.reader, &self.hyphenator, &mut self.ws, count)?;
+        }
+        Ok(())
+    }
+
+    // Read enough text, i.e. until we see a line that starts after
+    // the position `s`, and return the index of the line containing it.
+    fn seek(&mut self, s: BookState) -> anyhow::Result<usize> {
+        while !self.ws.eof
+              // Automatic lexicographic order due to ParialOrd.
+              && self.ws.lines.last().is_none_or(|l| l.xml_offset <= s) {
+            crank(&mut self.reader, &self.hyphenator, &mut self.ws, 100)?;
+        }
+        Ok(line_for_position(&self.ws.lines, s))
+    }
+
+    // Same as `seek`, but the position is given as the text offset.
+    fn seek_text(&mut self, t: usize) -> anyhow::Result<usize> {
+        while !self.ws.eof
+              && self.ws.lines.last().is_none_or(|l| l.text_offset <= t) {
+            crank(&mut self.reader, &self.hyphenator, &mut self.ws, 100)?;
+        }
+        Ok(self.ws.lines.iter().rposition(|l| l.text_offset <= t).unwrap_or(0))
+    }
+
+    // The position of the line `i`, or of the beginning of the book
+    // if we don't have such a line.
+    fn position(&self, i: usize) -> BookState {
+        self.ws.lines.get(i).map_or(BookState::default(), |l| l.xml_offset)
+    }
+
+    // Find the index of the line where the go-to target is.
+    fn seek_target(&mut self, t: goto::Target,
+                   scan: &scan::BookScan) -> anyhow::Result<usize> {
+        match t {
+            goto::Target::Percent(p) => self.seek_text(scan.total_chars * p / 100),
+            goto::Target::Chapter(n) => {
+                let c = scan.chapters.get(n - 1).ok_or(ProcessingError::new(
+                        &format!("there are only {} chapters", scan.chapters.len())))?;
+                self.seek(c.xml_offset)
+            }
+            goto::Target::Position(s) => self.seek(s),
+        }
+    }
+}
+
+// The new top line such that the line `i` is on the screen: we don't
+// scroll if the line is already visible.
+fn show_line(top: usize, i: usize, rows: usize) -> usize {
+    if i >= top && i < top + rows { top } else { i }
+}
 
-    let mut i = 0;
-    while start_idx + i < ws.lines.len() && i < lines {
-        let l = &ws.lines[start_idx+i];
-        print!("{:<4}{}\r\n", " ", l.content);
-        // XXX this is only for debugging, we will get rid of xml offsets.
-        /* if let Some(o) = l.xml_offset {
-            print!("{:<4}{:<4}    {}\r\n", o.tag_count,
-                                           o.word_offset, l.content);
+// Marks for the search hits on the screen.
+fn search_marks<'a> (s: &search::Search,
+                     ws: &WriterState,
+                     top: usize,
+                     rows: usize,
+                     on: &'a str, off: &'a str) -> Vec<Mark<'a>> {
+    let lines = &ws.lines[top.min(ws.lines.len())..(top + rows).min(ws.lines.len())];
+    match (lines.first(), lines.last()) {
+        (Some(a), Some(b)) if s.visible => {
+            s.hits_between(a.xml_offset.tag_count, b.xml_offset.tag_count)
+             .iter()
+             .map(|h| Mark { start: h.start, end: h.end, on, off })
+             .collect()
         }
-        else {
-            print!("{:<8}    {}\r\n", "---", l.content);
-        } */
-        i += 1;
+        _ => Vec::new()
     }
-    i
 }
 
-fn main () -> anyhow::Result<()> {
-    let app = app_from_crate!()
-             .arg(
-                Arg::with_name("input")
-                    .help("input file containing the fb2 book")
-                    .index(1)
-                    .required(true),
-              )
-              .get_matches();
+// The message about the search hit `i`.
+fn search_msg(s: &search::Search, i: usize,
+              wrapped: bool, forward: bool) -> String {
+    let mut m = format!("{} [{}/{}]", s.pattern, i + 1, s.hits.len());
+    if wrapped {
+        m.push_str(if forward { " search hit BOTTOM, continuing at TOP" }
+                   else { " search hit TOP, continuing at BOTTOM" });
+    }
+    m
+}
 
-    // TODO add a flag that can specify where the settings live,
-    // and use some default location, using xdg defaults.
-    //
-    // Read the config file for the termbook, including
-    // the state of the books that we have ever read.
-    let config_fname = "settings.yml";
-    let config_file_r = std::fs::File::open(config_fname)
-        .with_context(|| format!("cannot open settings `{}'", config_fname))?;
-    let mut tbconf: TBconfig
-        = serde_yaml::from_reader(std::io::BufReader::new(config_file_r))?;
+// Read a line of input in the status row.  Returns None if the
+// input was cancelled with Esc.
+fn prompt<R: std::io::Read, W: Write> (keys: &mut Keys<R>,
+                                       out: &mut W,
+                                       scr: &Screen,
+                                       p: &str) -> anyhow::Result<Option<String>> {
+    let mut input = String::new();
+    write!(out, "{}", termion::cursor::Show)?;
+    let res = loop {
+        write!(out, "{}{}{}{}",
+               termion::cursor::Goto(1, scr.status_row() as u16),
+               termion::clear::CurrentLine, p, input)?;
+        out.flush()?;
+        match keys.next() {
+            Some(Ok(Key::Char('\n'))) => break Some(input),
+            Some(Ok(Key::Esc)) | None => break None,
+            Some(Ok(Key::Backspace)) => {
+                if input.pop().is_none() {
+                    break None;
+                }
+            }
+            Some(Ok(Key::Char(c))) => input.push(c),
+            Some(Ok(_)) => (),
+            Some(Err(e)) => return Err(e.into()),
+        }
+    };
+    write!(out, "{}", termion::cursor::Hide)?;
+    Ok(res)
+}
+
+// Geometry of the screen.
+struct Screen {
+    width: usize,
+    height: usize,
+    status_bar: StatusBar,
+}
+
+impl Screen {
+    // Number of rows that we use for the text.  We always keep one
+    // row for the status line, even if we don't show it.
+    fn rows(&self) -> usize {
+        self.height - 1
+    }
+    // The row of the first line of the text.
+    fn text_row(&self) -> usize {
+        if self.status_bar == StatusBar::Top { 2 } else { 1 }
+    }
+    fn status_row(&self) -> usize {
+        if self.status_bar == StatusBar::Top { 1 } else { self.height }
+    }
+}
+
+// Compose the status line: author and title of the book, the current
+// chapter, how far we are in the book, estimated time to finish the
+// chapter and the book (if we know the reading speed `wpm`), and the
+// current time.  The position is taken from the line `l` which is the
+// top line on the screen.
+fn status_line (desc: &meta::Description,
+                scan: &scan::BookScan,
+                l: Option<&Line>,
+                wpm: Option<f64>,
+                width: usize) -> String {
+    let mut left = desc.short_name();
+    let mut right = Vec::new();
+    if let Some(l) = l {
+        if let Some(c) = scan.chapter_at(l.xml_offset) {
+            left.push_str(" | ");
+            left.push_str(&c.title);
+        }
+        right.push(format!("{}%", scan.percent(l.text_offset)));
+        if let Some(wpm) = wpm {
+            let cpw = scan.chars_per_word();
+            let chapter_end = scan.next_chapter(l.xml_offset)
+                              .map_or(scan.total_chars, |c| c.text_offset);
+            let words = |end: usize| end.saturating_sub(l.text_offset) as f64 / cpw;
+            right.push(format!("chapter {}", stats::fmt_minutes(
+                                stats::minutes_left(words(chapter_end), wpm))));
+            right.push(format!("book {}", stats::fmt_minutes(
+                                stats::minutes_left(words(scan.total_chars), wpm))));
+        }
+    }
+    if let Ok(t) = time::strftime("%H:%M", &time::now()) {
+        right.push(t);
+    }
+    let right = right.join("  ");
+
+    // Cut the left part if it doesn't fit, keeping a space on
+    // both sides of the line.
+    let room = width.saturating_sub(right.chars().count() + 3);
+    if left.chars().count() > room {
+        left = left.chars().take(room.saturating_sub(1)).collect();
+        left.push('…');
+    }
+    let fill = width.saturating_sub(left.chars().count()
+                                    + right.chars().count() + 2);
+    format!(" {}{}{} ", left, " ".repeat(fill), right)
+}
+
+// A range of the book text [start, end) that we show in a different style.
+struct Mark<'a> {
+    start: BookState,
+    end: BookState,
+    on: &'a str,
+    off: &'a str,
+}
+
+// Visible columns of the line `l` that are covered by the mark `m`.
+// The gaps between the words are covered if the mark continues
+// through them.
+fn mark_columns(l: &Line, m: &Mark) -> Vec<(usize, usize)> {
+    let mut cols: Vec<(usize, usize)> = Vec::new();
+    // Did the mark cover the previous piece till its end.
+    let mut open = false;
+    for f in &l.words {
+        let a = if f.pos > m.start { f.pos } else { m.start };
+        let b = if f.end() < m.end { f.end() } else { m.end };
+        if a >= b {
+            open = false;
+            continue;
+        }
+        let from = f.col + a.char_offset - f.pos.char_offset;
+        let to = f.col + b.char_offset - f.pos.char_offset;
+        match cols.last_mut() {
+            Some(c) if open && a == f.pos => c.1 = to,
+            _ => cols.push((from, to)),
+        }
+        open = b == f.end();
+    }
+    cols
+}
+
+// Insert the escape sequences of the marks around the given visible
+// columns of the line, skipping the escape sequences that are already
+// in the line.
+fn apply_marks(content: &str, cols: &[(usize, usize, &str, &str)]) -> String {
+    let mut res = String::new();
+    let mut col = 0;
+    let mut chars = content.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Copy the escape sequence as is: ESC [ params final-byte.
+            res.push(c);
+            for c in chars.by_ref() {
+                res.push(c);
+                if c != '[' && ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+            continue;
+        }
+        for &(_, to, _, off) in cols {
+            if to == col { res.push_str(off); }
+        }
+        for &(from, _, on, _) in cols {
+            if from == col { res.push_str(on); }
+        }
+        res.push(c);
+        col += 1;
+    }
+    for &(from, to, _, off) in cols {
+        if from < col && to >= col { res.push_str(off); }
+    }
+    res
+}
+
+// Draw the lines starting from the index `top` and the status line.
+fn draw_page<W: Write> (out: &mut W,
+                        ws: &WriterState,
+                        top: usize,
+                        scr: &Screen,
+                        status: &str,
+                        marks: &[Mark]) -> std::io::Result<()> {
+    write!(out, "{}", termion::clear::All)?;
+    for (i, l) in ws.lines.iter().skip(top).take(scr.rows()).enumerate() {
+        let mut cols = Vec::new();
+        for m in marks {
+            cols.extend(mark_columns(l, m).into_iter()
+                        .map(|(a, b)| (a, b, m.on, m.off)));
+        }
+        let content = if cols.is_empty() {
+            l.content.clone()
+        } else {
+            apply_marks(&l.content, &cols)
+        };
+        write!(out, "{}{:<4}{}",
+               termion::cursor::Goto(1, (scr.text_row() + i) as u16),
+               " ", content)?;
+        // XXX this is only for debugging.
+        /* let o = l.xml_offset;
+        write!(out, "{:<4}{:<4}{:<4}{}", o.tag_count, o.word_offset,
+                                        o.char_offset, l.content)?; */
+    }
+    if scr.status_bar != StatusBar::Off {
+        write!(out, "{}{}{}{}",
+               termion::cursor::Goto(1, scr.status_row() as u16),
+               style::Invert, status, style::Reset)?;
+    }
+    out.flush()
+}
+
+// The first word that starts on a line after (or before if `down` is
+// not set) the line with the position `s`.
+fn line_word(book: &mut Book, s: BookState, down: bool) -> anyhow::Result<BookState> {
+    let mut i = book.seek(s)?;
+    loop {
+        i = match (down, i) {
+            (true, i) => i + 1,
+            (false, 0) => return Ok(s),
+            (false, i) => i - 1,
+        };
+        book.ensure_lines(i + 1)?;
+        match book.ws.lines.get(i) {
+            None => return Ok(s),
+            Some(l) => if let Some(f) = l.words.iter().find(|f| f.pos.char_offset == 0) {
+                return Ok(f.pos);
+            }
+        }
+    }
+}
+
+// Move the cursor at the word `s` according to the key: by words with
+// `h`/`l` (or `b`/`w`) and by lines with `j`/`k`.  Returns None if the
+// key is not a movement.
+fn move_cursor(book: &mut Book, nodes: &[scan::TextNode],
+               s: BookState, k: Key) -> anyhow::Result<Option<BookState>> {
+    Ok(Some(match k {
+        Key::Left | Key::Char('h') | Key::Char('b') => {
+            scan::prev_word(nodes, s).unwrap_or(s)
+        }
+        Key::Right | Key::Char('l') | Key::Char('w') => {
+            scan::next_word(nodes, s).unwrap_or(s)
+        }
+        Key::Down | Key::Char('j') => line_word(book, s, true)?,
+        Key::Up | Key::Char('k') => line_word(book, s, false)?,
+        _ => return Ok(None),
+    }))
+}
+
+// The first word that starts on the screen.
+fn first_word(book: &Book, top: usize, rows: usize,
+              nodes: &[scan::TextNode]) -> Option<BookState> {
+    let first = book.ws.lines.iter().skip(top).take(rows)
+                .flat_map(|l| l.words.iter()).next().map(|f| f.pos);
+    match first {
+        Some(s) if s.char_offset > 0 => scan::next_word(nodes, s),
+        s => s,
+    }
+}
+
+// Scroll so that the line `i` is visible, keeping the screen where
+// it is if possible.
+fn scroll_to(top: usize, i: usize, rows: usize) -> usize {
+    if i < top {
+        i
+    } else if i >= top + rows {
+        i + 1 - rows
+    } else {
+        top
+    }
+}
+
+// Choose a word to look up with the cursor, starting with the word `start`.
+// Returns the word and the key it was chosen with (Enter to look it
+// up, `a` to add it to the vocabulary), or None if the reader left
+// the cursor mode.
+fn word_cursor<R: Read, W: Write> (keys: &mut Keys<R>,
+                                   out: &mut W,
+                                   book: &mut Book,
+                                   top: &mut usize,
+                                   scr: &Screen,
+                                   nodes: &[scan::TextNode],
+                                   start: BookState)
+                                   -> anyhow::Result<Option<(BookState, char)>> {
+    let help = " -- LOOKUP --  h/l: word  j/k: line  Enter: look up  \
+                a: add to vocabulary  Esc: done";
+    let on = style::Invert.to_string();
+    let off = style::NoInvert.to_string();
+    let mut cur = start;
+    loop {
+        *top = scroll_to(*top, book.seek(cur)?, scr.rows());
+        book.ensure_lines(*top + scr.rows())?;
+        draw_page(out, &book.ws, *top, scr, &overlay::fit(help, scr.width),
+                  &[Mark { start: cur, end: scan::word_end(nodes, cur),
+                           on: &on, off: &off }])?;
+        match keys.next() {
+            Some(Ok(Key::Char(c @ '\n'))) | Some(Ok(Key::Char(c @ 'a'))) => {
+                return Ok(Some((cur, c)))
+            }
+            Some(Ok(Key::Esc)) | Some(Ok(Key::Char('q'))) | None => return Ok(None),
+            Some(Ok(k)) => {
+                if let Some(s) = move_cursor(book, nodes, cur, k)? {
+                    cur = s;
+                }
+            }
+            Some(Err(e)) => return Err(e.into()),
+        }
+    }
+}
+
+// Select a passage in the visual mode, starting with the first word
+// on the screen.  Returns the selected text [start, end), or None if
+// the reader cancelled the selection.
+fn visual_select<R: Read, W: Write> (keys: &mut Keys<R>,
+                                     out: &mut W,
+                                     book: &mut Book,
+                                     top: &mut usize,
+                                     scr: &Screen,
+                                     nodes: &[scan::TextNode])
+                                     -> anyhow::Result<Option<(BookState, BookState)>> {
+    let (mut anchor, mut head) = match first_word(book, *top, scr.rows(), nodes) {
+        Some(s) => (s, s),
+        None => return Ok(None),
+    };
+    let on = style::Invert.to_string();
+    let off = style::NoInvert.to_string();
+    let help = " -- VISUAL --  h/l: word  j/k: line  s, (, ): sentence  \
+                o: other end  Enter: highlight  Esc: cancel";
+    loop {
+        let (a, b) = if anchor <= head { (anchor, head) } else { (head, anchor) };
+        let b = scan::word_end(nodes, b);
+        // Scroll so that the moving end of the selection is visible.
+        *top = scroll_to(*top, book.seek(head)?, scr.rows());
+        book.ensure_lines(*top + scr.rows())?;
+        draw_page(out, &book.ws, *top, scr, &overlay::fit(help, scr.width),
+                  &[Mark { start: a, end: b, on: &on, off: &off }])?;
+
+        match keys.next() {
+            Some(Ok(Key::Char('\n'))) => return Ok(Some((a, b))),
+            Some(Ok(Key::Esc)) | None => return Ok(None),
+            Some(Ok(Key::Char('s'))) => {
+                anchor = scan::sentence_start(nodes, head);
+                head = scan::sentence_end(nodes, head);
+            }
+            Some(Ok(Key::Char(')'))) => {
+                // To the end of this sentence or of the next one.
+                let e = scan::sentence_end(nodes, head);
+                head = if e != head { e } else {
+                    scan::next_word(nodes, head)
+                        .map_or(head, |w| scan::sentence_end(nodes, w))
+                };
+            }
+            Some(Ok(Key::Char('('))) => {
+                let s = scan::sentence_start(nodes, head);
+                head = if s != head { s } else {
+                    scan::prev_word(nodes, head)
+                        .map_or(head, |w| scan::sentence_start(nodes, w))
+                };
+            }
+            Some(Ok(Key::Char('o'))) => mem::swap(&mut anchor, &mut head),
+            Some(Ok(k)) => {
+                if let Some(s) = move_cursor(book, nodes, head, k)? {
+                    head = s;
+                }
+            }
+            Some(Err(e)) => return Err(e.into()),
+        }
+    }
+}
+
+// Marks for the highlights that may be on the screen.
+fn annotation_marks<'a> (list: &[annotations::Annotation],
+                         ws: &WriterState,
+                         top: usize,
+                         rows: usize,
+                         on: &'a str, off: &'a str) -> Vec<Mark<'a>> {
+    let from = match ws.lines.get(top) {
+        Some(l) => l.xml_offset,
+        None => return Vec::new(),
+    };
+    let to = ws.lines.get(top + rows).map(|l| l.xml_offset);
+    list.iter()
+        .filter(|a| a.end > from && to.is_none_or(|t| a.start < t))
+        .map(|a| Mark { start: a.start, end: a.end, on, off })
+        .collect()
+}
+
+// The items for the list of highlights.
+fn annotation_items(list: &[annotations::Annotation], scan: &scan::BookScan) -> Vec<String> {
+    list.iter().map(|a| {
+        let mut s = format!("{:>3}%  «{}»", scan.percent(a.text_offset), a.text);
+        if let Some(n) = &a.note {
+            s.push_str(&format!("  — {}", n));
+        }
+        s
+    }).collect()
+}
+
+// The `annotations export` subcommand: print the highlights of the
+// book in the given format.
+fn annotations_cmd(tbconf: &mut TBconfig, input: &str, format: &str) -> anyhow::Result<()> {
+    let data = load_book(input, false)?;
+    let desc = meta::parse_description(&data)?;
+    let id = meta::book_id(&data, &desc);
+    let scan = tbconf.book_scan(&id, &data)?;
+    let list = tbconf.annotations.get(&id).map_or(&[][..], |l| &l[..]);
+    match format {
+        "json" => println!("{}", annotations::to_json(&desc, &scan, list)?),
+        _ => print!("{}", annotations::to_markdown(&desc, &scan, list)),
+    }
+    Ok(())
+}
+
+// The `info` subcommand.  A book that we cannot read doesn't stop us
+// from going through the rest of them.
+fn info_cmd(inputs: &[&str], json: bool, counts: bool) -> anyhow::Result<()> {
+    let mut failed = 0;
+    for (i, input) in inputs.iter().enumerate() {
+        let res = load_book(input, false).and_then(|data| {
+            let desc = meta::parse_description(&data)?;
+            let c = if counts { Some(info::count(&data, desc.cover.as_deref())?) } else { None };
+            if json {
+                println!("{}", info::to_json(input, &desc, c.as_ref())?);
+            } else {
+                if inputs.len() > 1 {
+                    println!("{}File:      {}", if i > 0 { "\n" } else { "" }, input);
+                }
+                print!("{}", info::to_text(&desc, c.as_ref()));
+            }
+            Ok(())
+        });
+        if let Err(e) = res {
+            eprintln!("{}: {:#}", input, e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow::anyhow!("cannot read {} of the {} books", failed, inputs.len()));
+    }
+    Ok(())
+}
+
+// The percentage that we have read of the books by their identity.
+// The books we closed before we started to save it get the percentage
+// of the chapter where we stopped.
+fn reading_progress(tbconf: &TBconfig) -> HashMap<String, usize> {
+    let mut p: HashMap<String, usize> = tbconf.books.iter().filter_map(|(id, s)| {
+        let scan = tbconf.scans.get(id)?;
+        Some((id.clone(), scan.chapter_at(*s).map_or(0, |c| scan.percent(c.text_offset))))
+    }).collect();
+    p.extend(tbconf.progress.iter().map(|(id, n)| (id.clone(), *n)));
+    p
+}
+
+// The identities of the books we have read, the latest first.
+fn recent_books(tbconf: &TBconfig) -> Vec<String> {
+    let mut recent: Vec<String> = Vec::new();
+    for s in tbconf.stats.sessions.iter().rev() {
+        if !recent.contains(&s.book) {
+            recent.push(s.book.clone());
+        }
+    }
+    recent
+}
+
+// Show the library and return the book that the reader chose.  The
+// index of the library is brought up to date with the directories
+// every time we show it, which only takes reading the new and the
+// changed files.
+fn library_cmd(tbconf: &TBconfig, fname: &str) -> anyhow::Result<Option<String>> {
+    if tbconf.library.is_empty() {
+        return Err(anyhow::anyhow!("no book given; list the directories with the books \
+                                    under `library' in the settings to browse them"));
+    }
+    if !termion::is_tty(&stdout()) {
+        return Err(anyhow::anyhow!("no book given, and the library needs a terminal"));
+    }
+    let mut lib = library::Library::load(fname)?;
+    let progress = reading_progress(tbconf);
+    let recent = recent_books(tbconf);
+
+    let (w, h) = terminal_size()?;
+    let size = (w as usize, h as usize);
+    let mut out = stdout().into_raw_mode()?;
+    let mut keys = stdin().keys();
+    loop {
+        let update = lib.update(&tbconf.library, &mut |i, n, f| {
+            let msg = overlay::fit(&format!("Scanning the library {}/{}: {}", i + 1, n,
+                                            f.display()), size.0);
+            let _ = write!(out, "{}{}{}", termion::clear::All,
+                           termion::cursor::Goto(1, 1), msg);
+            let _ = out.flush();
+        });
+        if update.changed() {
+            lib.save(fname)?;
+        }
+        if update.failed > 0 {
+            overlay::popup(&mut keys, &mut out, size, "Library",
+                           &format!("Cannot read {} of the files.", update.failed), &[])?;
+        }
+        lib.set_ids(&tbconf.paths);
+        match library::browse(&mut keys, &mut out, size, &lib, &progress, &recent)? {
+            Some(library::Choice::Open(p)) => {
+                write!(out, "{}{}", termion::clear::All, termion::cursor::Goto(1, 1))?;
+                out.flush()?;
+                return Ok(Some(p));
+            }
+            Some(library::Choice::Rescan) => (),
+            None => break,
+        }
+    }
+    write!(out, "{}{}", termion::clear::All, termion::cursor::Goto(1, 1))?;
+    out.flush()?;
+    Ok(None)
+}
+
+// The `open` subcommand: find the book in the library by the `query`
+// (see `find`).  The book is opened right away if there is no doubt
+// which one is meant, otherwise the reader chooses from the matches.
+fn open_cmd(tbconf: &TBconfig, query: &str, fname: &str) -> anyhow::Result<Option<String>> {
+    if tbconf.library.is_empty() {
+        return Err(anyhow::anyhow!("list the directories with the books under `library' \
+                                    in the settings to open them by the title"));
+    }
+    let mut lib = library::Library::load(fname)?;
+    let update = lib.update(&tbconf.library, &mut |i, n, f| {
+        eprintln!("Scanning the library {}/{}: {}", i + 1, n, f.display());
+    });
+    if update.changed() {
+        lib.save(fname)?;
+    }
+    lib.set_ids(&tbconf.paths);
+    let mut finder = find::Finder::new(lib.books());
+    if let Some(e) = finder.best(query) {
+        return Ok(Some(e.path.clone()));
+    }
+    let best = match finder.find(query).first() {
+        Some(e) => e.path.clone(),
+        None => return Err(anyhow::anyhow!("no book in the library matches `{}'", query)),
+    };
+    // There is no one to ask.
+    if !termion::is_tty(&stdout()) {
+        return Ok(Some(best));
+    }
+
+    let (w, h) = terminal_size()?;
+    let mut out = stdout().into_raw_mode()?;
+    let chosen = library::find_book(&mut stdin().keys(), &mut out, (w as usize, h as usize),
+                                    &mut finder, &reading_progress(tbconf), query)?;
+    write!(out, "{}{}", termion::clear::All, termion::cursor::Goto(1, 1))?;
+    out.flush()?;
+    Ok(chosen)
+}
+
+// The `library scan` subcommand: bring the index of the library up to
+// date without showing it.
+fn library_scan_cmd(tbconf: &TBconfig, fname: &str) -> anyhow::Result<()> {
+    if tbconf.library.is_empty() {
+        return Err(anyhow::anyhow!("list the directories with the books under `library' \
+                                    in the settings to scan them"));
+    }
+    let mut lib = library::Library::load(fname)?;
+    let update = lib.update(&tbconf.library, &mut |i, n, f| {
+        eprintln!("{}/{}: {}", i + 1, n, f.display());
+    });
+    if update.changed() {
+        lib.save(fname)?;
+    }
+    println!("{} books; read {} files, {} of them failed; {} files are gone",
+             lib.books().count(), update.read, update.failed, update.removed);
+    Ok(())
+}
+
+// The `library import` subcommand: add the books of the INPX catalogue
+// to the index of the library.
+fn library_import_cmd(inpx: &str, dir: Option<&str>, fname: &str) -> anyhow::Result<()> {
+    let inpx = std::path::Path::new(inpx);
+    // The archives are usually next to the catalogue.
+    let dir = match dir {
+        Some(d) => std::path::PathBuf::from(d),
+        None => inpx.parent().filter(|p| !p.as_os_str().is_empty())
+                    .map_or_else(|| std::path::PathBuf::from("."), |p| p.to_path_buf()),
+    };
+    let mut lib = library::Library::load(fname)?;
+    let (books, missing, changed) = lib.import_inpx(inpx, &dir)?;
+    if changed {
+        lib.save(fname)?;
+    }
+    println!("imported {} books", books);
+    if missing > 0 {
+        eprintln!("{} of the archives are not in `{}'", missing, dir.display());
+    }
+    Ok(())
+}
+
+// The `vocabulary export` subcommand.
+fn vocabulary_cmd(tbconf: &TBconfig, format: &str) {
+    match format {
+        "anki" => print!("{}", vocabulary::to_anki(&tbconf.vocabulary)),
+        _ => print!("{}", vocabulary::to_tsv(&tbconf.vocabulary)),
+    }
+}
+
+// Where the keys come from: the terminal rather than stdin when the
+// book itself is read from stdin.
+fn keyboard(input: &str) -> anyhow::Result<Box<dyn Read>> {
+    if archive::split_path(input).0 == "-" {
+        Ok(Box::new(termion::get_tty().context("cannot open the terminal")?))
+    } else {
+        Ok(Box::new(stdin()))
+    }
+}
+
+// The format of the book that doesn't have an extension (e.g. the one
+// from stdin) judging by its start: FB2 or HTML, otherwise plain text.
+fn guess_format(data: &[u8]) -> Option<&'static str> {
+    let start = String::from_utf8_lossy(&data[..data.len().min(1024)]).to_lowercase();
+    if start.contains("<fictionbook") {
+        Some("fb2")
+    } else if start.contains("<html") || start.contains("<!doctype html") {
+        Some("html")
+    } else if start.trim_start_matches('\u{feff}').trim_start().starts_with('<') {
+        None
+    } else {
+        Some("txt")
+    }
+}
+
+// Ask the reader which of the books in the archive to open.
+fn choose_entry(archive: &str, names: &[String]) -> anyhow::Result<String> {
+    let (w, h) = terminal_size()?;
+    let mut out = stdout().into_raw_mode()?;
+    let choice = overlay::choose(&mut keyboard(archive)?.keys(), &mut out, (w as usize, h as usize),
+                                 &format!("Books in {}", archive), names, 0)?;
+    write!(out, "{}{}", termion::clear::All, termion::cursor::Goto(1, 1))?;
+    out.flush()?;
+    choice.map(|i| names[i].clone())
+          .ok_or_else(|| anyhow::anyhow!("no book in `{}' was chosen", archive))
+}
+
+// Read the entire book into memory.  We need its content to compute
+// the identity of the book before parsing it.  Books in other formats
+// are converted into FB2.  If the archive has several books, we ask
+// the reader to choose one when we are `interactive`.
+fn load_book(input: &str, interactive: bool) -> anyhow::Result<Vec<u8>> {
+    let (path, entry) = archive::split_path(input);
+    let mut data = Vec::new();
+    if path == "-" {
+        stdin().read_to_end(&mut data).context("cannot read the book from stdin")?;
+    } else {
+        let f = std::fs::File::open(path)
+                .with_context(|| format!("cannot open file `{}'", path))?;
+        std::io::BufReader::new(f).read_to_end(&mut data)?;
+    }
+    let mut name = std::path::PathBuf::from(path);
+    if let Some(d) = archive::decompress(&data)? {
+        data = d;
+        name = archive::uncompressed_name(&name);
+    }
+    // If we have a zipped file, we'd have to unzip it first.  We check
+    // the content rather than the extension, as the books are often
+    // named `.fb2.zip` or have no extension at all; FB3 books are zip
+    // files too, but they are books rather than archives.
+    if archive::is_zip(&data) {
+        let mut za = zip::ZipArchive::new(std::io::Cursor::new(&data[..]))?;
+        let entry = match entry {
+            Some(e) => Some(e.to_string()),
+            None if archive::is_package(&za) => None,
+            None => {
+                let books = archive::books(&za);
+                match books.len() {
+                    0 => return Err(anyhow::anyhow!("there are no books in `{}'", path)),
+                    1 => books.into_iter().next(),
+                    _ if interactive => Some(choose_entry(path, &books)?),
+                    _ => return Err(anyhow::anyhow!(
+                             "there are several books in `{}', choose one with `{}:BOOK': {}",
+                             path, path, books.join(", "))),
+                }
+            }
+        };
+        match entry {
+            Some(e) => {
+                let d = archive::read(&mut za, &e)?;
+                name = std::path::PathBuf::from(e);
+                data = match archive::decompress(&d)? {
+                    Some(d) => {
+                        name = archive::uncompressed_name(&name);
+                        d
+                    }
+                    None => d,
+                };
+            }
+            None => { name.set_extension("fb3"); }
+        }
+    } else if let Some(e) = entry {
+        return Err(anyhow::anyhow!("cannot open `{}' in `{}': not a zip archive", e, path));
+    }
+
+    to_fb2(data, &name)
+}
+
+// Convert the book into FB2 unless it is FB2 already.  The format is
+// given by the extension of the `name` of the book, or by its content
+// if there is no extension.
+fn to_fb2(data: Vec<u8>, name: &std::path::Path) -> anyhow::Result<Vec<u8>> {
+    let stem = name.file_stem().map_or(String::new(), |s| s.to_string_lossy().into_owned());
+    let ext = name.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+    match ext.as_deref().or_else(|| guess_format(&data)) {
+        Some("txt") => Ok(txt::to_fb2(&data, &stem)),
+        Some("fb3") => fb3::to_fb2(&data, &stem),
+        Some("md") | Some("markdown") => Ok(md::to_fb2(&data, &stem)),
+        Some("html") | Some("htm") | Some("xhtml") => Ok(html::to_fb2(&data, &stem)),
+        _ => Ok(data),
+    }
+}
+
+// The items for the list of bookmarks.
+fn bookmark_items(bm: &[bookmarks::Bookmark], scan: &scan::BookScan) -> Vec<String> {
+    bm.iter().map(|b| {
+        let mut s = format!("{:>3}%  ", scan.percent(b.text_offset));
+        if let Some(n) = &b.name {
+            s.push_str(&format!("[{}]  ", n));
+        }
+        s.push_str(&b.snippet);
+        s
+    }).collect()
+}
+
+// The `search` subcommand: print all the matches of the `pattern`
+// in the book, one per line.
+fn search_cmd(tbconf: &mut TBconfig, input: &str, pattern: &str) -> anyhow::Result<()> {
+    let data = load_book(input, false)?;
+    let desc = meta::parse_description(&data)?;
+    let scan = tbconf.book_scan(&meta::book_id(&data, &desc), &data)?;
+    let nodes = scan::text_nodes(&data)?;
+    let re = search::compile(pattern)?;
+    for h in search::find_all(&nodes, &re) {
+        println!("{}\t{}%\t{}",
+                 scan.chapter_at(h.start).map_or("", |c| &c.title),
+                 scan.percent(h.text_offset),
+                 search::snippet(&nodes, &h, 30));
+    }
+    Ok(())
+}
 
-    // The location of the book that we are about to open.
-    let input = app.value_of("input").ok_or(ProcessingError::new(
-            &"cannot get the value of the input file"))?;
-
-    // Get absolute path of the book --- we use it as a key in the file
-    // that keeps states (tag_offset and word offset).
-    let input_rel = std::path::PathBuf::from(input);
-    let input_abs = std::fs::canonicalize(&input_rel)?
-                    // TODO get rid of this unwrap
-                    .into_os_string().into_string().unwrap();
-
-    let f = std::fs::File::open(&input)
-            .with_context(|| format!("cannot open file `{}'", input))?;
-
-    let mut za;
-    let mut reader : Reader<Box<dyn BufRead>> =
-    // If we have a zipped file, we'd have to unzip it first.
-    if input_rel.extension() == Some(std::ffi::OsStr::new("zip")) {
-        za = zip::read::ZipArchive::new(f)?;
-        let zf = za.by_index(0)?;
-        let zfr = std::io::BufReader::new(zf);
-        quick_xml::Reader::from_reader(Box::new(zfr))
+// Whether to use colours in the output for the `--color` option.
+fn use_color(opt: Option<&str>) -> bool {
+    match opt {
+        Some("always") => true,
+        Some("never") => false,
+        _ => termion::is_tty(&stdout()),
+    }
+}
+
+// Write the lines of the whole book into `out`.
+fn print_book<W: Write>(book: &mut Book, out: &mut W) -> anyhow::Result<()> {
+    let mut printed = 0;
+    loop {
+        book.ensure_lines(printed + 100)?;
+        for l in &book.ws.lines[printed..] {
+            writeln!(out, "{}", l.content.trim_end())?;
+        }
+        printed = book.ws.lines.len();
+        if book.ws.eof {
+            break;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// The `--dump` mode and the `cat` subcommand: lay out the whole book
+// in lines of `width` characters and print it.
+fn dump_cmd(input: &str, width: usize, color: bool) -> anyhow::Result<()> {
+    let mut book = Book::new(load_book(input, false)?, width, color)?;
+    let out = stdout();
+    match print_book(&mut book, &mut std::io::BufWriter::new(out.lock())) {
+        // The reader has seen enough, e.g. quit `less`.
+        Err(e) if e.downcast_ref::<std::io::Error>()
+                   .is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe) => Ok(()),
+        r => r,
+    }
+}
+
+// The `convert` subcommand: write the book into `output` in the format
+// given by its extension.  The plain text is laid out in lines of
+// `width` characters, with the words broken unless `hyphenate` is off.
+fn convert_cmd(input: &str, output: &str, width: usize, hyphenate: bool) -> anyhow::Result<()> {
+    let out = std::path::Path::new(output);
+    let ext = out.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+    let data = load_book(input, false)?;
+    match ext.as_deref() {
+        Some("txt") => {
+            let mut book = Book::new(data, width, false)?;
+            book.ws.hyphenate = hyphenate;
+            let f = std::fs::File::create(out)
+                    .with_context(|| format!("cannot create `{}'", output))?;
+            print_book(&mut book, &mut std::io::BufWriter::new(f))
+        }
+        Some("md") | Some("markdown") => convert::markdown(&data, out),
+        Some("html") | Some("htm") | Some("xhtml") => convert::html(&data, out),
+        Some("epub") => convert::epub(&data, out),
+        _ => Err(anyhow::anyhow!("cannot tell the format of `{}', \
+                                  use .txt, .md, .html or .epub", output)),
+    }
+}
+
+// The items for the list of search results.
+fn search_items(s: &search::Search, nodes: &[scan::TextNode],
+                scan: &scan::BookScan) -> Vec<String> {
+    s.hits.iter().map(|h| {
+        format!("{:>3}%  {}  {}", scan.percent(h.text_offset),
+                scan.chapter_at(h.start).map_or("", |c| &c.title),
+                search::snippet(nodes, h, 30))
+    }).collect()
+}
+
+// The library for looking up the books of the series, with the
+// identities of the books that we know only by their paths filled in.
+// It is loaded into `lib` the first time we need it.
+fn series_library<'a>(lib: &'a mut Option<library::Library>, tbconf: &TBconfig, fname: &str)
+                      -> anyhow::Result<&'a library::Library> {
+    match lib {
+        Some(l) => Ok(l),
+        None => {
+            let mut l = library::Library::load(fname)?;
+            l.set_ids(&tbconf.paths);
+            Ok(lib.insert(l))
+        }
+    }
+}
+
+// The books of the series of the book (`desc`, with the identity `id`
+// at `path`) in the library, and where the book itself is among them.
+// The series is taken from the library if the book doesn't name it.
+fn series_of<'a>(lib: &'a library::Library, desc: &meta::Description, id: &str, path: &str)
+                 -> (Vec<&'a library::Entry>, Option<usize>) {
+    let this = |e: &library::Entry| e.path == path || e.id == id;
+    let name = desc.series.clone()
+                   .or_else(|| lib.books().find(|e| this(e)).and_then(|e| e.series.clone()));
+    let books = match name {
+        Some(n) => lib.series(&n, &desc.authors),
+        None => Vec::new(),
+    };
+    let cur = books.iter().position(|e| this(e));
+    (books, cur)
+}
+
+// Show the book page by page in the terminal.  The position in the book
+// and the rest of the state are saved into `config_fname` when the book
+// is closed.  Returns the book to read next if the reader chose one from
+// the series (see `library::Library::series`).
+fn read_book(tbconf: &mut TBconfig, config_fname: &str, library_fname: &str,
+             input: &str, target: Option<goto::Target>) -> anyhow::Result<Option<String>> {
+    let data = load_book(input, true)?;
+
+    // Get absolute path of the book --- we use it as a secondary key
+    // in the file that keeps states (tag_offset and word offset).  The
+    // book from stdin doesn't have a path, the hash of its content takes
+    // its place.
+    let (path, entry) = archive::split_path(input);
+    let mut input_abs = if path == "-" {
+        format!("stdin:{:016x}", meta::content_hash(&data))
     } else {
-        let fr = std::io::BufReader::new(f);
-        quick_xml::Reader::from_reader(Box::new(fr))
+        std::fs::canonicalize(path)?
+        // TODO get rid of this unwrap
+        .into_os_string().into_string().unwrap()
     };
+    if let Some(e) = entry {
+        input_abs = format!("{}:{}", input_abs, e);
+    }
 
-    // TODO: parse <description> of the book and choose the appropriate
-    // language, and possible get other meta-information.
-    let hyphenator = Standard::from_embedded(Language::Russian)?;
+    // The identity of the book is the primary key for the saved position.
+    let desc = meta::parse_description(&data)?;
+    let book_id = meta::book_id(&data, &desc);
+
+    // The total length of the text and the chapters.
+    let scan = tbconf.book_scan(&book_id, &data)?;
 
     // get terminal size
     //
@@ -632,52 +1878,22 @@ fn main () -> anyhow::Result<()> {
     let w = w16 as usize;
     let h = h16 as usize;
 
-    // Prepare the state structure for the xml parser.
-    let lines = Vec::new();
-    let l = String::from("");
-    let styles = Vec::new();
-    let tags = std::collections::HashSet::<String>::new();
-
-    // TODO read this from the config file.
-    let mut smap = std::collections::HashMap::new();
-    smap.insert(FBstyle::Strong,(style::Bold.to_string(), style::NoBold.to_string()));
-    smap.insert(FBstyle::Title,(color::Fg(color::LightBlue).to_string(),
-                                color::Fg(color::Reset).to_string()));
-
-    smap.insert(FBstyle::Subtitle,(color::Fg(color::LightBlue).to_string(),
-                                color::Fg(color::Reset).to_string()));
-    smap.insert(FBstyle::Emph,(color::Fg(color::LightCyan).to_string(),
-                                color::Fg(color::Reset).to_string()));
-
-    smap.insert(FBstyle::Bold,(color::Fg(color::LightGreen).to_string(),
-                                color::Fg(color::Reset).to_string()));
-
     assert!(w>12);
-    let mut ws = WriterState { line: 0, pos: 0,
-                               // TODO use config to set maxline.
-                               line_width: core::cmp::min((w-12).into(),50),
-                               l: l,
-                               lines: lines,
-                               eof: false,
-                               xml_offset: BookState{tag_count:0, word_offset:0},
-                               tags: tags,
-                               prefix: String::from(""), needs_prefix: true,
-                               align: Align::Left,
-                               smap: smap,
-                               styles: styles,
-                               in_title: false, skip: false,
-                               last_line_empty: false,
-                               first_paragraph: true};
+    // TODO use config to set maxline.
+    let mut book = Book::new(data.clone(), core::cmp::min(w-12,50), true)?;
 
 
+    // TODO lift this validation up.
+    assert!(h>1);
+    let scr = Screen { width: w, height: h, status_bar: tbconf.status_bar };
+
     // Prepare to start termion with terminal in raw mode.
-    let stdin = stdin();
+    let keyboard = keyboard(input)?;
     let mut stdout = stdout().into_raw_mode()?;
 
-    // So far this is our index into the ws.lines which we use
-    // when KeyDown is pressed, so that we know how much lines
-    // do we print.
-    let mut lines_idx = 0;
+    // The index of the line in ws.lines that is shown at the top
+    // of the screen.
+    let mut top = 0;
 
     write!(stdout,
            "{}{}{}",
@@ -686,94 +1902,887 @@ fn main () -> anyhow::Result<()> {
            termion::cursor::Hide)?;
     stdout.flush()?;
 
+    // Open the book at the location given in the command line, or
     // check whether we have a saved position of that book in
     // the config file.
-    if tbconf.books.contains_key(&input_abs) {
-        let bstate = tbconf.books.get(&input_abs).ok_or(ProcessingError::new(
-                &format!("error obtaining a state of `{}'", &input_abs)))?;
-        // read enough text
-        while !ws.eof
-              // Automatic lexicographic order due to ParialOrd.
-              && ws.xml_offset < *bstate {
-            crank(&mut reader, &hyphenator, &mut ws, 100)?;
-        }
-        // find the index of the line that is "closest" to the
-        // saved state.
-        //    - If we don't find the offset that is smaller
-        //      than the stored one, we start from the beginning of the book.
-        //    - If the offset is too large (bogus config file) we'll end-up
-        //      at the last line of the book.
-        lines_idx = ws.lines.iter()
-                    .rposition(|p| match p.xml_offset {
-                                      Some(o) => o <= *bstate,
-                                      None => false
-                                    })
-                    .unwrap_or(0);
+    if let Some(t) = target {
+        top = book.seek_target(t, &scan)?;
+    } else if let Some(bstate) = tbconf.lookup_book(&book_id, &input_abs) {
+        top = book.seek(bstate)?;
     }
 
-    // print the initial screen of text.
-    // TODO lift this validation up.
-    assert!(h>1);
-    crank(&mut reader, &hyphenator, &mut ws, h)?;
-    lines_idx += print_n_lines(&mut ws, lines_idx, h-1);
-    stdout.flush()?;
+    // Highlights are shown with a different background.
+    let highlight_on = color::Bg(color::Blue).to_string();
+    let highlight_off = color::Bg(color::Reset).to_string();
 
-    for c in stdin.keys() {
+    // print the initial screen of text.
+    book.ensure_lines(top + scr.rows())?;
+    let status = status_line(&desc, &scan, book.ws.lines.get(top),
+                             tbconf.stats.wpm(&book_id), scr.width);
+    let marks = match tbconf.annotations.get(&book_id) {
+        Some(l) => annotation_marks(l, &book.ws, top, scr.rows(),
+                                    &highlight_on, &highlight_off),
+        None => Vec::new()
+    };
+    draw_page(&mut stdout, &book.ws, top, &scr, &status, &marks)?;
+
+    // Text nodes of the book for searching, we collect them when
+    // we search for the first time.
+    let mut nodes: Option<Vec<scan::TextNode>> = None;
+    let mut search: Option<search::Search> = None;
+    // Dictionaries are loaded when we look up a word for the first time.
+    let mut dicts: Option<anyhow::Result<Vec<dict::Dictionary>>> = None;
+    // Internal links of the book, collected when we need them first.
+    let mut links: Option<scan::Links> = None;
+    let search_on = style::Invert.to_string();
+    let search_off = style::NoInvert.to_string();
+    // The library, loaded when we look for the other books of the series.
+    let mut lib: Option<library::Library> = None;
+    // Whether we have offered to open the next book of the series.
+    let mut offered = false;
+    // Whether the reader closed the book, and the book to open instead.
+    let mut closed = false;
+    let mut next_book: Option<String> = None;
+
+    let mut tracker = stats::Tracker::new(&book_id, &desc.short_name());
+    // A message that we show in the status line instead of the status.
+    let mut msg: Option<String> = None;
+    let mut keys = keyboard.keys();
+    while let Some(c) = keys.next() {
+        let old_top = top;
+        // Whether the key was a jump that we record in the history.
+        let mut jumped = false;
         match c.unwrap() {
             Key::Char('q') => {
-                // Grab the first non-empty offset, or (0,0) in case we don't have any.
-                let mut i = lines_idx.saturating_sub(h-1);
-                let mut s = BookState { tag_count: 0, word_offset: 0};
-                while i > 0 {
-                    match ws.lines[i].xml_offset {
-                        Some(o) => {
-                            s = o;
-                            break;
-                        }
-                        None => i -= 1
-                    }
-                }
-                // add or update the book position.
-                tbconf.books.insert(input_abs, s);
-
-                // save the config into the yaml file.
-                let config_file_w = std::fs::File::create(config_fname)?;
-                serde_yaml::to_writer(std::io::BufWriter::new(&config_file_w), &tbconf)?;
-                config_file_w.sync_all()?;
+                closed = true;
                 break
             }
             Key::Up => {
-                termion::cursor::Goto(1,1);
-                lines_idx = lines_idx.saturating_sub(h);
-                lines_idx += print_n_lines(&mut ws, lines_idx, h-1)
+                top = top.saturating_sub(1);
             }
             Key::PageUp => {
-                termion::cursor::Goto(1,1);
-                lines_idx = lines_idx.saturating_sub(2*h-2);
-                lines_idx += print_n_lines(&mut ws, lines_idx, h-1)
+                top = top.saturating_sub(scr.rows());
             }
             Key::Down => {
-                if lines_idx+1 >= ws.lines.len() {
-                  // XXX here 10 is just a magic number...
-                  crank(&mut reader, &hyphenator, &mut ws, 10)?;
+                book.ensure_lines(top + scr.rows() + 1)?;
+                if top + scr.rows() < book.ws.lines.len() {
+                    top += 1;
                 }
-                lines_idx += print_n_lines(&mut ws, lines_idx, 1);
             }
             Key::PageDown => {
-                if lines_idx+(h as usize) >= ws.lines.len() {
-                  crank(&mut reader, &hyphenator, &mut ws, h)?;
+                book.ensure_lines(top + 2 * scr.rows())?;
+                if top + scr.rows() < book.ws.lines.len() {
+                    top += scr.rows();
+                }
+            }
+            Key::Char(':') => {
+                if let Some(g) = prompt(&mut keys, &mut stdout, &scr, ":")? {
+                    match goto::parse_target(&g) {
+                        Some(t) => match book.seek_target(t, &scan) {
+                            Ok(i) => {
+                                top = i;
+                                jumped = true;
+                            }
+                            Err(e) => msg = Some(e.to_string()),
+                        },
+                        None => msg = Some(format!("cannot parse `{}'", g)),
+                    }
+                }
+            }
+            Key::Char(c @ '/') | Key::Char(c @ '?') => {
+                let p = match prompt(&mut keys, &mut stdout, &scr, &c.to_string())? {
+                    // Empty pattern repeats the last search.
+                    Some(p) if p.is_empty() => search.as_ref().map(|s| s.pattern.clone()),
+                    p => p,
+                };
+                if let Some(p) = p {
+                    match search::compile(&p) {
+                        Ok(re) => {
+                            if nodes.is_none() {
+                                nodes = Some(scan::text_nodes(&data)?);
+                            }
+                            let hits = search::find_all(nodes.as_deref().unwrap_or(&[]), &re);
+                            let forward = c == '/';
+                            let from = book.position(top);
+                            let mut s = search::Search::new(&p, forward, hits);
+                            match s.find(from, forward, forward) {
+                                Some((i, wrapped)) => {
+                                    top = show_line(top, book.seek(s.hits[i].start)?,
+                                                    scr.rows());
+                                    msg = Some(search_msg(&s, i, wrapped, forward));
+                                    s.current = Some(i);
+                                    jumped = true;
+                                }
+                                None => msg = Some(format!("Pattern not found: {}", p)),
+                            }
+                            search = Some(s);
+                        }
+                        Err(e) => msg = Some(e.to_string()),
+                    }
+                }
+            }
+            Key::Char(c @ 'n') | Key::Char(c @ 'N') => {
+                if let Some(s) = &mut search {
+                    let forward = s.forward == (c == 'n');
+                    let from = match s.current {
+                        Some(i) => s.hits[i].start,
+                        None => book.position(top),
+                    };
+                    match s.find(from, forward, s.current.is_none()) {
+                        Some((i, wrapped)) => {
+                            top = show_line(top, book.seek(s.hits[i].start)?,
+                                            scr.rows());
+                            msg = Some(search_msg(s, i, wrapped, forward));
+                            s.current = Some(i);
+                            jumped = true;
+                            s.visible = true;
+                        }
+                        None => msg = Some(format!("Pattern not found: {}",
+                                                   s.pattern)),
+                    }
+                }
+            }
+            Key::Char('s') => {
+                // The list of the hits of the last search.
+                match (&mut search, &nodes) {
+                    (Some(s), Some(nodes)) if !s.hits.is_empty() => {
+                        let items = search_items(s, nodes, &scan);
+                        let title = format!("Search results for `{}'", s.pattern);
+                        if let Some(i) = overlay::choose(&mut keys, &mut stdout,
+                                                         (scr.width, scr.height), &title,
+                                                         &items, s.current.unwrap_or(0))? {
+                            top = show_line(top, book.seek(s.hits[i].start)?, scr.rows());
+                            s.current = Some(i);
+                            jumped = true;
+                            s.visible = true;
+                        }
+                    }
+                    _ => msg = Some("No search results".to_string()),
+                }
+            }
+            Key::Char('b') => {
+                if let (Some(l), Some(name)) = (book.ws.lines.get(top),
+                        prompt(&mut keys, &mut stdout, &scr, "Bookmark name: ")?) {
+                    if nodes.is_none() {
+                        nodes = Some(scan::text_nodes(&data)?);
+                    }
+                    let snippet = scan::text_at(nodes.as_deref().unwrap_or(&[]),
+                                                l.xml_offset, 60);
+                    let name = name.trim();
+                    tbconf.bookmarks.entry(book_id.clone()).or_default()
+                          .add(bookmarks::Bookmark {
+                              name: if name.is_empty() { None } else { Some(name.to_string()) },
+                              pos: l.xml_offset,
+                              text_offset: l.text_offset,
+                              snippet });
+                    msg = Some("Bookmark added".to_string());
+                }
+            }
+            Key::Char('B') => {
+                // The list of bookmarks, where they can be deleted as well.
+                let mut sel = 0;
+                loop {
+                    let bm = match tbconf.bookmarks.get_mut(&book_id) {
+                        Some(bm) if !bm.bookmarks.is_empty() => bm,
+                        _ => {
+                            msg = Some("No bookmarks".to_string());
+                            break;
+                        }
+                    };
+                    let items = bookmark_items(&bm.bookmarks, &scan);
+                    match overlay::choose_action(&mut keys, &mut stdout,
+                                                 (scr.width, scr.height), "Bookmarks",
+                                                 &items, sel, &[('d', "delete")])? {
+                        Some((i, Some(_))) => {
+                            bm.bookmarks.remove(i);
+                            sel = i;
+                        }
+                        Some((i, None)) => {
+                            top = book.seek(bm.bookmarks[i].pos)?;
+                            jumped = true;
+                            break;
+                        }
+                        None => break,
+                    }
+                }
+                if tbconf.bookmarks.get(&book_id).is_some_and(|bm| bm.is_empty()) {
+                    tbconf.bookmarks.remove(&book_id);
+                }
+            }
+            Key::Char('m') => {
+                match (keys.next(), book.ws.lines.get(top)) {
+                    (Some(Ok(Key::Char(c @ 'a'..='z'))), Some(l)) => {
+                        tbconf.bookmarks.entry(book_id.clone()).or_default()
+                              .marks.insert(c, l.xml_offset);
+                        msg = Some(format!("Mark `{}' set", c));
+                    }
+                    (Some(Err(e)), _) => return Err(e.into()),
+                    _ => (),
+                }
+            }
+            Key::Char('\'') => {
+                if let Some(Ok(Key::Char(c @ 'a'..='z'))) = keys.next() {
+                    match tbconf.bookmarks.get(&book_id).and_then(|bm| bm.marks.get(&c)) {
+                        Some(s) => {
+                            top = book.seek(*s)?;
+                            jumped = true;
+                        }
+                        None => msg = Some(format!("Mark `{}' not set", c)),
+                    }
+                }
+            }
+            Key::Char('t') => {
+                // Table of contents.
+                let items: Vec<String> = scan.chapters.iter().map(|c| {
+                    format!("{}{}  {}%", "  ".repeat(c.depth - 1), c.title,
+                            scan.percent(c.text_offset))
+                }).collect();
+                let cur = book.position(top);
+                let sel = scan.chapters.iter().rposition(|c| c.xml_offset <= cur)
+                          .unwrap_or(0);
+                if items.is_empty() {
+                    msg = Some("No chapters".to_string());
+                } else if let Some(i) = overlay::choose(&mut keys, &mut stdout,
+                                                        (scr.width, scr.height),
+                                                        "Contents", &items, sel)? {
+                    top = book.seek(scan.chapters[i].xml_offset)?;
+                    jumped = true;
+                }
+            }
+            Key::Char('f') => {
+                // Follow a link (e.g. a footnote) on the screen.  If there
+                // are several of them, we let the reader choose one.
+                if links.is_none() {
+                    links = Some(scan::links(&data)?);
+                }
+                if nodes.is_none() {
+                    nodes = Some(scan::text_nodes(&data)?);
+                }
+                let end = book.ws.lines.get(top + scr.rows()).map(|l| l.xml_offset);
+                let visible = links.as_ref().map_or(Vec::new(), |ls| {
+                    ls.between(book.position(top), end)
+                });
+                let target = match visible.len() {
+                    0 => {
+                        msg = Some("No links on the screen".to_string());
+                        None
+                    }
+                    1 => Some(visible[0].1),
+                    _ => {
+                        let items: Vec<String> = visible.iter().map(|(l, a)| {
+                            format!("{}  {}", l.text, scan::text_at(
+                                    nodes.as_deref().unwrap_or(&[]), *a, 60))
+                        }).collect();
+                        overlay::choose(&mut keys, &mut stdout, (scr.width, scr.height),
+                                        "Links", &items, 0)?
+                            .map(|i| visible[i].1)
+                    }
+                };
+                if let Some(a) = target {
+                    top = book.seek(a)?;
+                    jumped = true;
+                }
+            }
+            Key::Char('v') => {
+                if nodes.is_none() {
+                    nodes = Some(scan::text_nodes(&data)?);
+                }
+                let nodes = nodes.as_deref().unwrap_or(&[]);
+                if let Some((a, b)) = visual_select(&mut keys, &mut stdout, &mut book,
+                                                    &mut top, &scr, nodes)? {
+                    if let Some(note) = prompt(&mut keys, &mut stdout, &scr, "Note: ")? {
+                        let note = note.trim();
+                        annotations::add(tbconf.annotations.entry(book_id.clone())
+                                         .or_default(),
+                                         annotations::Annotation {
+                            start: a, end: b,
+                            text_offset: scan::text_offset_at(nodes, a),
+                            text: scan::text_between(nodes, a, Some(b), usize::MAX),
+                            note: if note.is_empty() { None } else { Some(note.to_string()) },
+                        });
+                        msg = Some("Highlight added".to_string());
+                    }
+                }
+            }
+            Key::Char('d') => {
+                // Look up the words chosen with the cursor and add them
+                // to the vocabulary until the reader leaves the cursor mode.
+                if dicts.is_none() {
+                    dicts = Some(tbconf.dictionaries.iter().map(|d| dict::open(d))
+                                 .collect::<anyhow::Result<Vec<_>>>());
+                }
+                if nodes.is_none() {
+                    nodes = Some(scan::text_nodes(&data)?);
+                }
+                let nodes = nodes.as_deref().unwrap_or(&[]);
+                let (dicts, mut cur) = match dicts.as_ref() {
+                    Some(Ok(d)) => (&d[..], first_word(&book, top, scr.rows(), nodes)),
+                    Some(Err(e)) => {
+                        msg = Some(e.to_string());
+                        (&[][..], None)
+                    }
+                    None => (&[][..], None),
+                };
+                let mut added = 0;
+                while let Some((w, k)) = match cur {
+                    Some(c) => word_cursor(&mut keys, &mut stdout, &mut book,
+                                           &mut top, &scr, nodes, c)?,
+                    None => None,
+                } {
+                    cur = Some(w);
+                    let word = scan::word_at(nodes, w).unwrap_or_default();
+                    let found = dict::lookup(dicts, word);
+                    let add = k == 'a' || {
+                        let (title, text) = match &found {
+                            Some((form, articles)) => {
+                                (form.clone(), articles.iter()
+                                               .map(|(d, a)| format!("[{}]\n{}", d, a))
+                                               .collect::<Vec<_>>().join("\n\n"))
+                            }
+                            None if dicts.is_empty() => {
+                                (word.to_string(), "No dictionaries in the settings".to_string())
+                            }
+                            None => (word.to_string(), "Not found".to_string()),
+                        };
+                        overlay::popup(&mut keys, &mut stdout, (scr.width, scr.height),
+                                       &title, &text, &[('a', "add to vocabulary")])?
+                            == Some('a')
+                    };
+                    if add {
+                        let (form, definition) = match found {
+                            Some((form, articles)) => {
+                                (form, articles.into_iter().next().map(|a| a.1)
+                                                .unwrap_or_default())
+                            }
+                            None => (dict::candidates(word).into_iter().nth(1)
+                                     .unwrap_or_else(|| word.to_string()), String::new()),
+                        };
+                        let end = scan::word_end(nodes, scan::sentence_end(nodes, w));
+                        let context = scan::text_between(nodes, scan::sentence_start(nodes, w),
+                                                         Some(end), usize::MAX);
+                        if vocabulary::add(&mut tbconf.vocabulary, vocabulary::Entry {
+                            word: form, definition, context,
+                            book: book_id.clone(), title: desc.short_name(), pos: w,
+                            added: std::time::SystemTime::now()
+                                   .duration_since(std::time::UNIX_EPOCH)
+                                   .map_or(0, |d| d.as_secs()),
+                        }) {
+                            added += 1;
+                        }
+                    }
+                }
+                if added > 0 {
+                    msg = Some(format!("Added {} word{} to the vocabulary", added,
+                                       if added == 1 { "" } else { "s" }));
+                }
+            }
+            Key::Char('a') => {
+                // The list of highlights, where they can be deleted as well.
+                let mut sel = 0;
+                loop {
+                    let list = match tbconf.annotations.get_mut(&book_id) {
+                        Some(l) if !l.is_empty() => l,
+                        _ => {
+                            msg = Some("No highlights".to_string());
+                            break;
+                        }
+                    };
+                    let items = annotation_items(list, &scan);
+                    match overlay::choose_action(&mut keys, &mut stdout,
+                                                 (scr.width, scr.height), "Highlights",
+                                                 &items, sel, &[('d', "delete")])? {
+                        Some((i, Some(_))) => {
+                            list.remove(i);
+                            sel = i;
+                        }
+                        Some((i, None)) => {
+                            top = book.seek(list[i].start)?;
+                            jumped = true;
+                            break;
+                        }
+                        None => break,
+                    }
+                }
+                if tbconf.annotations.get(&book_id).is_some_and(|l| l.is_empty()) {
+                    tbconf.annotations.remove(&book_id);
+                }
+            }
+            Key::Char('S') => {
+                // The books of the series, the reader may open one of them.
+                match series_library(&mut lib, tbconf, library_fname)
+                      .map(|lib| series_of(lib, &desc, &book_id, &input_abs)) {
+                    Err(e) => msg = Some(e.to_string()),
+                    Ok((books, _)) if books.is_empty() => {
+                        msg = Some(if desc.series.is_some() {
+                            "No books of the series in the library".to_string()
+                        } else {
+                            "The book is not in a series".to_string()
+                        });
+                    }
+                    Ok((books, cur)) => {
+                        let progress = reading_progress(tbconf);
+                        let items: Vec<String> = books.iter()
+                            .map(|e| library::book_item(e, &progress)).collect();
+                        let title = format!("Series: {}",
+                                            books[0].series.as_deref().unwrap_or_default());
+                        if let Some(i) = overlay::choose(&mut keys, &mut stdout,
+                                                         (scr.width, scr.height), &title,
+                                                         &items, cur.unwrap_or(0))? {
+                            if books[i].path != input_abs && books[i].id != book_id {
+                                next_book = Some(books[i].path.clone());
+                                closed = true;
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+            k @ Key::Ctrl('o') | k @ Key::Char('\t') => {
+                // Back and forward in the history of jumps.
+                let cur = book.position(top);
+                let s = tbconf.history.get_mut(&book_id).and_then(|h| {
+                    if k == Key::Ctrl('o') { h.back(cur) } else { h.forward(cur) }
+                });
+                match s {
+                    Some(s) => top = book.seek(s)?,
+                    None => msg = Some("No more history".to_string()),
+                }
+            }
+            Key::Esc => {
+                // Hide the search highlighting.
+                if let Some(s) = &mut search {
+                    s.visible = false;
                 }
-                lines_idx += print_n_lines(&mut ws, lines_idx, h-1);
             }
             _ => {}
         }
-        stdout.flush()?;
+        if jumped && top != old_top {
+            tbconf.history.entry(book_id.clone()).or_default()
+                  .push(book.position(old_top));
+        }
+        book.ensure_lines(top + scr.rows())?;
+        // Jumps are not reading, so we only track the scrolling.
+        if top > old_top && top - old_top <= scr.rows() {
+            let chars = book.ws.lines[top].text_offset
+                        .saturating_sub(book.ws.lines[old_top].text_offset);
+            let words = (chars as f64 / scan.chars_per_word()).round() as usize;
+            tracker.advance(&mut tbconf.stats, top - old_top, words);
+        }
+        let status = match msg.take() {
+            Some(m) => format!(" {}", m),
+            None => status_line(&desc, &scan, book.ws.lines.get(top),
+                                tbconf.stats.wpm(&book_id), scr.width)
+        };
+        let mut marks = match tbconf.annotations.get(&book_id) {
+            Some(l) => annotation_marks(l, &book.ws, top, scr.rows(),
+                                        &highlight_on, &highlight_off),
+            None => Vec::new()
+        };
+        if let Some(s) = &search {
+            marks.extend(search_marks(s, &book.ws, top, scr.rows(),
+                                      &search_on, &search_off));
+        }
+        draw_page(&mut stdout, &book.ws, top, &scr, &status, &marks)?;
+
+        // The reader has got to the end of the book, offer to open the
+        // next one of the series.
+        if !offered && book.ws.eof && top + scr.rows() >= book.ws.lines.len() {
+            offered = true;
+            let (books, cur) = match series_library(&mut lib, tbconf, library_fname) {
+                Ok(lib) => series_of(lib, &desc, &book_id, &input_abs),
+                Err(e) => {
+                    draw_page(&mut stdout, &book.ws, top, &scr, &format!(" {}", e), &marks)?;
+                    continue;
+                }
+            };
+            let number = desc.series_number.or_else(|| cur.and_then(|i| books[i].series_number));
+            let next = number.and_then(|n| {
+                books.iter().find(|e| e.series_number.is_some_and(|m| m > n))
+            });
+            if let Some(e) = next {
+                let text = library::book_item(e, &reading_progress(tbconf));
+                if overlay::popup(&mut keys, &mut stdout, (scr.width, scr.height),
+                                  "Next in the series", text.trim(),
+                                  &[('o', "open")])?.is_some() {
+                    next_book = Some(e.path.clone());
+                    closed = true;
+                    break;
+                }
+                draw_page(&mut stdout, &book.ws, top, &scr, &status, &marks)?;
+            }
+        }
+    }
+
+    if closed {
+        // Grab the offset of the top line on the screen, or (0,0,0)
+        // in case we don't have any.
+        let s = book.position(top);
+        // add or update the book position.
+        tbconf.update_book(&book_id, &input_abs, s);
+        tbconf.progress.insert(book_id.clone(), scan.percent(
+                book.ws.lines.get(top).map_or(0, |l| l.text_offset)));
+        tracker.finish(&mut tbconf.stats, scr.rows());
+
+        // save the config into the yaml file.
+        let config_file_w = std::fs::File::create(config_fname)?;
+        serde_yaml::to_writer(std::io::BufWriter::new(&config_file_w), &tbconf)?;
+        config_file_w.sync_all()?;
     }
 
     write!(stdout, "{}", termion::cursor::Show)?;
+    if next_book.is_some() {
+        return Ok(next_book);
+    }
     // XXX this is debugging info.
-    for x in &ws.tags {
+    for x in &book.ws.tags {
         print!("{}\r\n", x);
     }
+    Ok(None)
+}
+
+fn main () -> anyhow::Result<()> {
+    let width_arg = Arg::with_name("width")
+                    .long("width")
+                    .value_name("N")
+                    .default_value("72")
+                    .validator(|v| match v.parse::<usize>() {
+                        Ok(n) if n >= 20 => Ok(()),
+                        _ => Err(String::from("the width must be a number, at least 20")),
+                    })
+                    .help("width of the text in the dump mode");
+    let color_arg = Arg::with_name("color")
+                    .long("color")
+                    .value_name("WHEN")
+                    .possible_values(&["auto", "always", "never"])
+                    .default_value("auto")
+                    .help("show the styles of the text in the dump mode \
+                           with the terminal colours");
+    let app = app_from_crate!()
+             .setting(AppSettings::SubcommandsNegateReqs)
+             .arg(
+                Arg::with_name("input")
+                    .help("input file containing the book, `-' reads it from stdin; \
+                           without it we show the library")
+                    .index(1),
+              )
+              .arg(
+                Arg::with_name("goto")
+                    .long("goto")
+                    .value_name("TARGET")
+                    .help("open the book at the given location: \
+                           percentage (50%), chapter (c5) or \
+                           position (123.4.0)")
+                    .takes_value(true),
+              )
+              .arg(
+                Arg::with_name("dump")
+                    .long("dump")
+                    .help("print the whole book instead of showing it page \
+                           by page, that's what happens when the output \
+                           is not a terminal")
+              )
+              .arg(width_arg.clone())
+              .arg(color_arg.clone())
+              .subcommand(
+                SubCommand::with_name("cat")
+                    .about("prints the whole book laid out at the given width")
+                    .arg(Arg::with_name("book").required(true).index(1))
+                    .arg(width_arg.clone())
+                    .arg(color_arg)
+              )
+              .subcommand(
+                SubCommand::with_name("convert")
+                    .about("converts the book into plain text, Markdown, HTML \
+                            or EPUB, depending on the extension of the output")
+                    .arg(Arg::with_name("book").required(true).index(1))
+                    .arg(Arg::with_name("output")
+                         .short("o")
+                         .long("output")
+                         .value_name("FILE")
+                         .required(true)
+                         .help("file to write: out.txt, out.md, out.html \
+                                or out.epub"))
+                    .arg(width_arg.help("width of the plain text"))
+                    .arg(Arg::with_name("no-hyphenation")
+                         .long("no-hyphenation")
+                         .help("move the words that don't fit to the next \
+                                line rather than breaking them in the plain \
+                                text"))
+              )
+              .subcommand(
+                SubCommand::with_name("info")
+                    .about("prints the title, the authors, the series and \
+                            the rest of the meta information of the books")
+                    .arg(Arg::with_name("book").required(true).multiple(true).index(1))
+                    .arg(Arg::with_name("json")
+                         .long("json")
+                         .help("print a line of JSON for every book"))
+                    .arg(Arg::with_name("counts")
+                         .long("counts")
+                         .help("count the words, the sections and the images \
+                                and measure the cover, which takes reading \
+                                the whole book"))
+              )
+              .subcommand(
+                SubCommand::with_name("open")
+                    .about("opens the book from the library whose title, \
+                            authors or series match the query, or lets you \
+                            choose from the matches")
+                    .arg(Arg::with_name("query").required(true).multiple(true).index(1)
+                         .help("letters of the title, the authors or the \
+                                series in their order, Latin letters match \
+                                the other alphabets"))
+              )
+              .subcommand(
+                SubCommand::with_name("library")
+                    .about("works with the index of the books in the library")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        SubCommand::with_name("scan")
+                            .about("reads the new and the changed books in the \
+                                    directories of the library")
+                    )
+                    .subcommand(
+                        SubCommand::with_name("import")
+                            .about("adds the books of the INPX catalogue of a \
+                                    collection to the library")
+                            .arg(Arg::with_name("inpx").required(true).index(1))
+                            .arg(Arg::with_name("dir")
+                                 .long("dir")
+                                 .value_name("DIR")
+                                 .help("directory with the archives of the \
+                                        collection, the one of the catalogue \
+                                        by default"))
+                    )
+              )
+              .subcommand(
+                SubCommand::with_name("stats")
+                    .about("prints reading speed and reading history")
+              )
+              .subcommand(
+                SubCommand::with_name("annotations")
+                    .about("works with the highlights and notes")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        SubCommand::with_name("export")
+                            .about("prints the highlights and notes of the book")
+                            .arg(Arg::with_name("book").required(true).index(1))
+                            .arg(Arg::with_name("format")
+                                 .long("format")
+                                 .value_name("FORMAT")
+                                 .possible_values(&["md", "json"])
+                                 .default_value("md")
+                                 .help("output format: Markdown or JSON"))
+                    )
+              )
+              .subcommand(
+                SubCommand::with_name("vocabulary")
+                    .about("works with the words collected while reading")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        SubCommand::with_name("export")
+                            .about("prints the collected words")
+                            .arg(Arg::with_name("format")
+                                 .long("format")
+                                 .value_name("FORMAT")
+                                 .possible_values(&["tsv", "anki"])
+                                 .default_value("tsv")
+                                 .help("output format: tab-separated values or \
+                                        CSV for importing into Anki"))
+                    )
+              )
+              .subcommand(
+                SubCommand::with_name("search")
+                    .about("prints chapter, percentage and context of \
+                            every match of the pattern in the book")
+                    .arg(Arg::with_name("book").required(true).index(1))
+                    .arg(Arg::with_name("pattern").required(true).index(2)
+                         .help("text to search for, see the README for \
+                                the syntax"))
+              )
+              .get_matches();
+
+    // TODO add a flag that can specify where the settings live,
+    // and use some default location, using xdg defaults.
+    //
+    // Read the config file for the termbook, including
+    // the state of the books that we have ever read.
+    let config_fname = "settings.yml";
+    // The index of the library, see `library::Library`.
+    let library_fname = "library.json";
+    let config_file_r = std::fs::File::open(config_fname)
+        .with_context(|| format!("cannot open settings `{}'", config_fname))?;
+    let mut tbconf: TBconfig
+        = serde_yaml::from_reader(std::io::BufReader::new(config_file_r))?;
+
+    if app.subcommand_matches("stats").is_some() {
+        stats::print_stats(&tbconf.stats);
+        return Ok(());
+    }
+    if let Some(m) = app.subcommand_matches("cat") {
+        // The arguments are required or have defaults.
+        return dump_cmd(m.value_of("book").unwrap_or_default(),
+                        m.value_of("width").and_then(|w| w.parse().ok()).unwrap_or(72),
+                        use_color(m.value_of("color")));
+    }
+    if let Some(m) = app.subcommand_matches("convert") {
+        return convert_cmd(m.value_of("book").unwrap_or_default(),
+                           m.value_of("output").unwrap_or_default(),
+                           m.value_of("width").and_then(|w| w.parse().ok()).unwrap_or(72),
+                           !m.is_present("no-hyphenation"));
+    }
+    if let Some(m) = app.subcommand_matches("info") {
+        let books: Vec<&str> = m.values_of("book").map_or(Vec::new(), |v| v.collect());
+        return info_cmd(&books, m.is_present("json"), m.is_present("counts"));
+    }
+    if let Some(m) = app.subcommand_matches("library") {
+        if let Some(m) = m.subcommand_matches("import") {
+            return library_import_cmd(m.value_of("inpx").unwrap_or_default(),
+                                      m.value_of("dir"), library_fname);
+        }
+        return library_scan_cmd(&tbconf, library_fname);
+    }
+    if let Some(m) = app.subcommand_matches("search") {
+        // Both arguments are required, so clap makes sure they are there.
+        return search_cmd(&mut tbconf, m.value_of("book").unwrap_or_default(),
+                          m.value_of("pattern").unwrap_or_default());
+    }
+
+    if let Some(m) = app.subcommand_matches("annotations")
+                        .and_then(|m| m.subcommand_matches("export")) {
+        return annotations_cmd(&mut tbconf, m.value_of("book").unwrap_or_default(),
+                               m.value_of("format").unwrap_or_default());
+    }
+
+    if let Some(m) = app.subcommand_matches("vocabulary")
+                        .and_then(|m| m.subcommand_matches("export")) {
+        vocabulary_cmd(&tbconf, m.value_of("format").unwrap_or_default());
+        return Ok(());
+    }
+
+    // The location of the book that we are about to open, the one
+    // from the library if it is not given.
+    let chosen;
+    let input = match app.value_of("input") {
+        Some(i) => i,
+        None => {
+            chosen = match app.subcommand_matches("open") {
+                Some(m) => {
+                    let query: Vec<&str> = m.values_of("query")
+                                            .map_or(Vec::new(), |v| v.collect());
+                    open_cmd(&tbconf, &query.join(" "), library_fname)?
+                }
+                None => library_cmd(&tbconf, library_fname)?,
+            };
+            match &chosen {
+                Some(p) => p.as_str(),
+                None => return Ok(()),
+            }
+        }
+    };
+
+    // There is no terminal to show the book page by page.
+    if app.is_present("dump") || !termion::is_tty(&stdout()) {
+        return dump_cmd(input,
+                        app.value_of("width").and_then(|w| w.parse().ok()).unwrap_or(72),
+                        use_color(app.value_of("color")));
+    }
+
+    let mut target = match app.value_of("goto") {
+        Some(g) => Some(goto::parse_target(g).ok_or(ProcessingError::new(
+                &format!("cannot parse the location `{}'", g)))?),
+        None => None
+    };
+    // Read the books one after another while the reader opens the next
+    // book of the series.
+    let mut input = input.to_string();
+    while let Some(next) = read_book(&mut tbconf, config_fname, library_fname,
+                                     &input, target.take())? {
+        input = next;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The preformatted lines indented deeper than the width of the line
+    // used to overflow it and panic.
+    #[test]
+    fn deep_pre_indent() {
+        for indent in [" ".repeat(40), "\t".repeat(12)] {
+            let fb2 = format!("<FictionBook><body><section><pre>{}code here\n{}x \
+                               averyveryverylongidentifier</pre></section></body>\
+                               </FictionBook>", indent, indent);
+            let mut book = Book::new(fb2.into_bytes(), 20, false).unwrap();
+            book.ensure_lines(100).unwrap();
+            assert!(book.ws.lines.iter().any(|l| l.content.contains("code")));
+            assert!(book.ws.lines.iter().all(|l| l.content.chars().count() <= 20));
+        }
+    }
+
+    // The character at the position `s` in the line, if it is there.
+    fn char_at(l: &Line, s: BookState) -> Option<char> {
+        let f = l.words.iter().find(|f| f.pos <= s && s < f.end())?;
+        l.content.chars().nth(f.col + s.char_offset - f.pos.char_offset)
+    }
+
+    // The position of the first character of a line is restored to the
+    // line that shows that character at another width, also when the
+    // line starts with the rest of a hyphenated word.
+    #[test]
+    fn restore_at_other_width() {
+        let text = "Достопримечательности старинного города привлекали \
+                    многочисленных путешественников, которые восхищались \
+                    великолепной архитектурой и неповторимыми достопримечательностями. ";
+        let fb2 = format!("<FictionBook><body><section><p>{}</p><p>{}</p></section></body>\
+                           </FictionBook>", text.repeat(3), text);
+        let mut book = Book::new(fb2.clone().into_bytes(), 40, false).unwrap();
+        book.ensure_lines(1000).unwrap();
+        let starts: Vec<(BookState, char)> = book.ws.lines.iter().filter_map(|l| {
+            let f = l.words.first()?;
+            Some((f.pos, l.content.chars().nth(f.col)?))
+        }).collect();
+        assert!(starts.iter().any(|(s, _)| s.char_offset > 0));
+        for width in [25, 80] {
+            for (s, c) in &starts {
+                let mut b = Book::new(fb2.clone().into_bytes(), width, false).unwrap();
+                let i = b.seek(*s).unwrap();
+                assert_eq!(char_at(&b.ws.lines[i], *s), Some(*c), "{:?} at {}", s, width);
+            }
+        }
+    }
+
+    // The struck out text of Markdown and HTML is shown crossed out.
+    #[test]
+    fn strikethrough() {
+        let books = [md::to_fb2(b"Some ~~old~~ text.\n", "a.md"),
+                     html::to_fb2(b"<p>Some <s>old</s> <del>text</del>.</p>", "a.html")];
+        for fb2 in books {
+            assert!(String::from_utf8_lossy(&fb2).contains("<strikethrough>old</strikethrough>"));
+            let mut book = Book::new(fb2, 40, true).unwrap();
+            book.ensure_lines(10).unwrap();
+            let on = style::CrossedOut.to_string();
+            assert!(book.ws.lines.iter().any(|l| l.content.contains(&format!("{}old", on))));
+            assert!(!book.ws.tags.contains("strikethrough"));
+        }
+    }
+
+    // The sentences and the joined text follow the paragraphs, not the
+    // gaps in the numbers of the text nodes.
+    #[test]
+    fn adjacent_paragraphs() {
+        let fb2 = b"<FictionBook><body><section><p>One <emphasis>big</emphasis>\
+                    <strong>fat</strong> <emphasis>cat</emphasis></p><p>Two.</p>\
+                    </section></body></FictionBook>";
+        let nodes = scan::text_nodes(fb2).unwrap();
+        let at = |tag_count| BookState { tag_count, word_offset: 0, char_offset: 0 };
+        let two = at(nodes.last().unwrap().tag_count);
+        assert_eq!(scan::sentence_start(&nodes, two), two);
+        assert_eq!(scan::sentence_end(&nodes, at(nodes[0].tag_count)).tag_count,
+                   nodes[3].tag_count);
+        assert_eq!(scan::text_between(&nodes, at(nodes[0].tag_count), None, usize::MAX),
+                   "One bigfat cat Two.");
+    }
+}
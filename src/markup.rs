@@ -0,0 +1,334 @@
+// Building FB2 out of the documents with markup, i.e. Markdown and
+// HTML.  They have the same things in them: headings, paragraphs with
+// emphasis, quotes, lists, code blocks and links, and only the syntax
+// differs.  The frontends parse the syntax and tell the builder about
+// the structure, and the builder turns it into FB2: the headings start
+// sections (so that they end up in the table of contents), the quotes
+// become citations, the list items get their bullets, and so on.
+
+use std::collections::{
+    HashMap, HashSet
+};
+use crate::fb2::{
+    self, escape
+};
+
+// Bullets of the unnumbered lists by the nesting level.
+const BULLETS: &[&str] = &["• ", "◦ ", "▪ "];
+
+pub struct Builder {
+    w: fb2::Writer,
+    // Level of the headings that start the top-level sections.
+    top: usize,
+    // Whether a paragraph is open.
+    para: bool,
+    // Opening and closing markup of the inline elements we are in.  They
+    // are closed at the end of the paragraph and opened again in the next
+    // one, as FB2 doesn't allow them to span paragraphs.
+    inline: Vec<(String, String)>,
+    // Lists we are in, with the number of the next item for the numbered
+    // ones.
+    lists: Vec<Option<u64>>,
+    // Bullet of the list item that doesn't have any text yet.
+    bullet: Option<String>,
+    // Number of quotes we are in.
+    quotes: usize,
+    // Text of the code block we are in.
+    pre: Option<String>,
+    // Level, id and text of the heading we are in.
+    heading: Option<(usize, Option<String>, String)>,
+    // Ids that the next paragraph gets.
+    ids: Vec<String>,
+    // Ids that we have given out, they have to be unique.
+    used: HashSet<String>,
+    // Numbers of the notes by their labels.
+    notes: HashMap<String, usize>,
+    // Id and markup of the note we are in.
+    note: Option<(String, String)>,
+}
+
+// The id of the heading the way GitHub makes them, so that the links
+// to the headings in Markdown documents work.
+pub fn slug(s: &str) -> String {
+    s.trim().to_lowercase().chars().filter_map(|c| match c {
+        ' ' => Some('-'),
+        c if c.is_alphanumeric() || c == '-' || c == '_' => Some(c),
+        _ => None,
+    }).collect()
+}
+
+fn note_id(label: &str) -> String {
+    format!("n_{}", slug(label))
+}
+
+impl Builder {
+    // Start the book, see `fb2::Writer::new`.  The headings of the level
+    // `top` start the top-level sections.
+    pub fn new(title: &str, authors: &[String], id: &str, top: usize) -> Builder {
+        Builder {
+            w: fb2::Writer::new(title, authors, id), top,
+            para: false, inline: Vec::new(), lists: Vec::new(), bullet: None,
+            quotes: 0, pre: None, heading: None, ids: Vec::new(),
+            used: HashSet::new(), notes: HashMap::new(), note: None,
+        }
+    }
+
+    // The markup goes either to the body or to the note we are in.
+    fn out(&mut self, xml: &str) {
+        match &mut self.note {
+            Some((_, s)) => s.push_str(xml),
+            None => self.w.raw(xml),
+        }
+    }
+
+    fn open_paragraph(&mut self) {
+        let mut s = match self.ids.first() {
+            Some(id) => format!("<p id=\"{}\">", escape(id)),
+            None => String::from("<p>"),
+        };
+        for id in self.ids.drain(..).skip(1) {
+            s.push_str(&format!("<a id=\"{}\"></a>", escape(&id)));
+        }
+        if let Some(b) = self.bullet.take() {
+            s.push_str(&escape(&b));
+        }
+        for (o, _) in &self.inline {
+            s.push_str(o);
+        }
+        self.out(&s);
+        self.para = true;
+    }
+
+    // End the current paragraph: the text that follows starts a new
+    // one.  That's what the ends of the blocks and the line breaks do.
+    pub fn end_paragraph(&mut self) {
+        if !self.para {
+            return;
+        }
+        let mut s: String = self.inline.iter().rev().map(|(_, c)| c.as_str()).collect();
+        s.push_str("</p>\n");
+        self.out(&s);
+        self.para = false;
+    }
+
+    // The text as is, the whitespaces at the start of the paragraph are
+    // ignored.
+    pub fn text(&mut self, t: &str) {
+        if let Some(p) = &mut self.pre {
+            p.push_str(t);
+            return;
+        }
+        if let Some((_, _, h)) = &mut self.heading {
+            h.push_str(t);
+            return;
+        }
+        let t = if self.para { t } else { t.trim_start() };
+        if t.is_empty() {
+            return;
+        }
+        if !self.para {
+            self.open_paragraph();
+        }
+        self.out(&escape(t));
+    }
+
+    fn push_inline(&mut self, open: String, close: String) {
+        if self.para {
+            self.out(&open);
+        }
+        self.inline.push((open, close));
+    }
+
+    // Start the inline element `name`: `emphasis`, `strong` or
+    // `strikethrough`.
+    pub fn inline(&mut self, name: &str) {
+        self.push_inline(format!("<{}>", name), format!("</{}>", name));
+    }
+
+    // Start the link to `href`.  We can only follow the links within
+    // the book, the rest are left as plain text.
+    pub fn link(&mut self, href: &str) {
+        match href.strip_prefix('#').filter(|t| !t.is_empty()) {
+            Some(t) => self.push_inline(format!("<a l:href=\"#{}\">", escape(t)),
+                                        String::from("</a>")),
+            None => self.push_inline(String::new(), String::new()),
+        }
+    }
+
+    // End the innermost inline element or link.
+    pub fn end_inline(&mut self) {
+        if let Some((_, c)) = self.inline.pop() {
+            if self.para {
+                self.out(&c);
+            }
+        }
+    }
+
+    fn in_link(&self) -> bool {
+        self.inline.iter().any(|(_, c)| c == "</a>")
+    }
+
+    // Make `id` the target of the links, it points at the text that
+    // follows.
+    pub fn anchor(&mut self, id: &str) {
+        self.used.insert(id.to_string());
+        // Links can't be nested, so the anchor within a link goes to
+        // the next paragraph.
+        if self.para && !self.in_link() {
+            self.out(&format!("<a id=\"{}\"></a>", escape(id)));
+        } else {
+            self.ids.push(id.to_string());
+        }
+    }
+
+    fn unique(&mut self, id: String) -> String {
+        let id = if id.is_empty() { String::from("section") } else { id };
+        let mut res = id.clone();
+        let mut n = 0;
+        while self.used.contains(&res) {
+            n += 1;
+            res = format!("{}-{}", id, n);
+        }
+        self.used.insert(res.clone());
+        res
+    }
+
+    // Start the heading of the `level`, the text of the heading is
+    // collected until `end_heading`.
+    pub fn heading(&mut self, level: usize, id: Option<&str>) {
+        self.end_paragraph();
+        self.heading = Some((level, id.map(str::to_string), String::new()));
+    }
+
+    pub fn end_heading(&mut self) {
+        let (level, id, text) = match self.heading.take() {
+            Some(h) => h,
+            None => return,
+        };
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        // Sections can't be nested in the notes and quotes, so there the
+        // heading is just a paragraph in bold.
+        if self.note.is_some() || self.quotes > 0 {
+            self.inline("strong");
+            self.text(&text);
+            self.end_inline();
+            self.end_paragraph();
+            return;
+        }
+        let id = id.unwrap_or_else(|| slug(&text));
+        let id = self.unique(id);
+        let title: Vec<String> = Some(text).filter(|t| !t.is_empty()).into_iter().collect();
+        self.w.section(level.saturating_sub(self.top) + 1, Some(&id), &title);
+    }
+
+    pub fn quote(&mut self) {
+        self.end_paragraph();
+        // Nested quotes look the same as the outer one.
+        if self.quotes == 0 {
+            self.out("<cite>\n");
+        }
+        self.quotes += 1;
+    }
+
+    pub fn end_quote(&mut self) {
+        if self.quotes == 0 {
+            return;
+        }
+        self.end_paragraph();
+        self.quotes -= 1;
+        if self.quotes == 0 {
+            self.out("</cite>\n");
+        }
+    }
+
+    // Start the list, numbered from `start` or unnumbered.
+    pub fn list(&mut self, start: Option<u64>) {
+        self.end_paragraph();
+        self.lists.push(start);
+    }
+
+    pub fn end_list(&mut self) {
+        self.end_paragraph();
+        self.lists.pop();
+        self.bullet = None;
+    }
+
+    pub fn item(&mut self) {
+        self.end_paragraph();
+        let depth = self.lists.len().max(1);
+        self.bullet = Some(match self.lists.last_mut() {
+            Some(Some(n)) => {
+                *n += 1;
+                format!("{}. ", *n - 1)
+            }
+            _ => BULLETS[(depth - 1) % BULLETS.len()].to_string(),
+        });
+    }
+
+    pub fn end_item(&mut self) {
+        self.end_paragraph();
+        self.bullet = None;
+    }
+
+    // Start the code block, its text is kept as is until `end_pre`.
+    pub fn pre(&mut self) {
+        self.end_paragraph();
+        self.pre = Some(String::new());
+    }
+
+    pub fn end_pre(&mut self) {
+        if let Some(p) = self.pre.take() {
+            let p = p.strip_prefix('\n').unwrap_or(&p).trim_end();
+            if !p.is_empty() {
+                self.out(&format!("<pre>{}</pre>\n", escape(p)));
+            }
+        }
+    }
+
+    // The thematic break between the paragraphs.
+    pub fn rule(&mut self) {
+        self.end_paragraph();
+        self.out("<empty-line/>\n");
+    }
+
+    fn note_number(&mut self, label: &str) -> usize {
+        let n = self.notes.len() + 1;
+        *self.notes.entry(label.to_string()).or_insert(n)
+    }
+
+    // The reference to the note with the `label`, the notes are numbered
+    // in the order they are first mentioned.
+    pub fn note_ref(&mut self, label: &str) {
+        let n = self.note_number(label);
+        self.push_inline(format!("<a l:href=\"#{}\" type=\"note\">", escape(&note_id(label))),
+                         String::from("</a>"));
+        self.text(&format!("[{}]", n));
+        self.end_inline();
+    }
+
+    // Start the note with the `label`, everything until `end_note` goes
+    // to the notes.
+    pub fn note(&mut self, label: &str) {
+        self.end_paragraph();
+        let n = self.note_number(label);
+        self.note = Some((note_id(label), format!("<title><p>{}</p></title>\n", n)));
+    }
+
+    pub fn end_note(&mut self) {
+        self.end_paragraph();
+        if let Some((id, s)) = self.note.take() {
+            self.w.note(&format!("<section id=\"{}\">\n{}</section>\n", escape(&id), s));
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.end_pre();
+        self.end_heading();
+        self.end_paragraph();
+        while self.quotes > 0 {
+            self.end_quote();
+        }
+        self.end_note();
+        self.w.finish()
+    }
+}
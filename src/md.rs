@@ -0,0 +1,116 @@
+// Markdown books.  The elements of the document map to FB2 almost
+// directly, see `markup::Builder`; the title of the book is taken from
+// the front matter or from the first top-level heading.
+
+use pulldown_cmark::{
+    Event, Options, Parser, Tag, TagEnd
+};
+use crate::{
+    markup, meta, txt
+};
+
+// The value of the `name: value` line of the front matter.
+fn front_matter_field(fm: &str, name: &str) -> Option<String> {
+    fm.lines().find_map(|l| {
+        let (k, v) = l.split_once(':')?;
+        let v = v.trim().trim_matches(['"', '\'']);
+        if k.trim() == name && !v.is_empty() { Some(v.to_string()) } else { None }
+    })
+}
+
+// Convert the document into FB2.  The `name` (e.g. the name of the
+// file) is the title of the book unless the document says otherwise.
+pub fn to_fb2(data: &[u8], name: &str) -> Vec<u8> {
+    let text = txt::decode(data);
+    let opts = Options::ENABLE_FOOTNOTES | Options::ENABLE_TABLES
+               | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS
+               | Options::ENABLE_HEADING_ATTRIBUTES
+               | Options::ENABLE_YAML_STYLE_METADATA_BLOCKS;
+    let events: Vec<Event> = Parser::new_ext(&text, opts).collect();
+
+    // The first pass collects the front matter, the text of the first
+    // heading and the level of the top-level headings.
+    let mut front_matter = String::new();
+    let mut first_heading: Option<String> = None;
+    let mut top = None;
+    let mut in_fm = false;
+    let mut in_heading = false;
+    for e in &events {
+        match e {
+            Event::Start(Tag::MetadataBlock(_)) => in_fm = true,
+            Event::End(TagEnd::MetadataBlock(_)) => in_fm = false,
+            Event::Start(Tag::Heading { level, .. }) => {
+                let l = *level as usize;
+                top = Some(top.map_or(l, |t: usize| t.min(l)));
+                in_heading = first_heading.is_none();
+                if in_heading {
+                    first_heading = Some(String::new());
+                }
+            }
+            Event::End(TagEnd::Heading(_)) => in_heading = false,
+            Event::Text(t) | Event::Code(t) if in_fm => front_matter.push_str(t),
+            Event::Text(t) | Event::Code(t) if in_heading => {
+                if let Some(h) = &mut first_heading {
+                    h.push_str(t);
+                }
+            }
+            _ => (),
+        }
+    }
+    let title = front_matter_field(&front_matter, "title")
+                .or(first_heading.filter(|h| !h.trim().is_empty()))
+                .unwrap_or_else(|| name.to_string());
+    let authors: Vec<String> = front_matter_field(&front_matter, "author").into_iter().collect();
+
+    let mut b = markup::Builder::new(title.trim(), &authors,
+                                     &format!("md:{:016x}", meta::content_hash(data)),
+                                     top.unwrap_or(1));
+    let mut first_cell = false;
+    for e in events {
+        match e {
+            Event::Start(Tag::MetadataBlock(_)) => in_fm = true,
+            Event::End(TagEnd::MetadataBlock(_)) => in_fm = false,
+            _ if in_fm => (),
+            Event::Start(Tag::Paragraph) | Event::End(TagEnd::Paragraph) => b.end_paragraph(),
+            Event::Start(Tag::Heading { level, id, .. }) => b.heading(level as usize, id.as_deref()),
+            Event::End(TagEnd::Heading(_)) => b.end_heading(),
+            Event::Start(Tag::BlockQuote(_)) => b.quote(),
+            Event::End(TagEnd::BlockQuote(_)) => b.end_quote(),
+            Event::Start(Tag::CodeBlock(_)) => b.pre(),
+            Event::End(TagEnd::CodeBlock) => b.end_pre(),
+            Event::Start(Tag::List(start)) => b.list(start),
+            Event::End(TagEnd::List(_)) => b.end_list(),
+            Event::Start(Tag::Item) => b.item(),
+            Event::End(TagEnd::Item) => b.end_item(),
+            Event::Start(Tag::FootnoteDefinition(label)) => b.note(&label),
+            Event::End(TagEnd::FootnoteDefinition) => b.end_note(),
+            Event::Start(Tag::Emphasis) => b.inline("emphasis"),
+            Event::Start(Tag::Strong) => b.inline("strong"),
+            Event::Start(Tag::Strikethrough) => b.inline("strikethrough"),
+            Event::Start(Tag::Link { dest_url, .. }) => b.link(&dest_url),
+            Event::End(TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough
+                       | TagEnd::Link) => b.end_inline(),
+            // A row of the table is a paragraph with the cells separated
+            // by bars.
+            Event::Start(Tag::TableHead | Tag::TableRow) => {
+                b.end_paragraph();
+                first_cell = true;
+            }
+            Event::End(TagEnd::TableHead | TagEnd::TableRow) => b.end_paragraph(),
+            Event::Start(Tag::TableCell) => {
+                if !first_cell {
+                    b.text(" | ");
+                }
+                first_cell = false;
+            }
+            Event::Text(t) | Event::Code(t) => b.text(&t),
+            Event::SoftBreak => b.text(" "),
+            Event::HardBreak => b.end_paragraph(),
+            Event::Rule => b.rule(),
+            Event::FootnoteReference(label) => b.note_ref(&label),
+            Event::TaskListMarker(done) => b.text(if done { "[x] " } else { "[ ] " }),
+            _ => (),
+        }
+    }
+    b.finish()
+}
@@ -0,0 +1,170 @@
+// Meta-information about the book that we get from the `<description>`
+// section of the fb2 file, and the identity of the book that we use
+// as a key for the saved positions.
+
+use quick_xml::{
+    Reader, events::{
+        BytesStart, Event
+    }
+};
+
+#[derive(Debug, Default, Clone)]
+pub struct Description {
+    // The content of `<document-info><id>`.
+    pub id: Option<String>,
+    pub title: Option<String>,
+    // Full names of the authors as "First Middle Last".
+    pub authors: Vec<String>,
+    // The series (`<sequence>`) and the number of the book in it.
+    pub series: Option<String>,
+    pub series_number: Option<u32>,
+    pub lang: Option<String>,
+    pub genres: Vec<String>,
+    pub publisher: Option<String>,
+    pub year: Option<String>,
+    // The id of the `<binary>` with the cover.
+    pub cover: Option<String>,
+}
+
+impl Description {
+    // A short "Author: Title" string to refer to the book.
+    pub fn short_name(&self) -> String {
+        let title = self.title.clone().unwrap_or_default();
+        match self.authors.first() {
+            Some(a) if !title.is_empty() => format!("{}: {}", a, title),
+            Some(a) => a.clone(),
+            None => title,
+        }
+    }
+}
+
+// Parse the `<description>` of the book.  We stop reading as soon as
+// we see `</description>`, so this is cheap even for large books.
+pub fn parse_description(data: &[u8]) -> anyhow::Result<Description> {
+    let mut reader = Reader::from_reader(data);
+    reader.trim_text(true);
+
+    let mut desc = Description::default();
+    let mut buf = Vec::new();
+    // Stack of the open tags inside of `<description>`.
+    let mut path: Vec<Vec<u8>> = Vec::new();
+    let mut in_desc = false;
+    // Parts of the author name that we are collecting.
+    let mut author: Vec<(Vec<u8>, String)> = Vec::new();
+
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) => {
+                if e.name() == b"description" {
+                    in_desc = true;
+                } else if in_desc {
+                    path.push(e.name().to_vec());
+                    element_attributes(&reader, e, &path, &mut desc);
+                }
+            }
+            Ok(Event::Empty(ref e)) if in_desc => {
+                path.push(e.name().to_vec());
+                element_attributes(&reader, e, &path, &mut desc);
+                path.pop();
+            }
+            Ok(Event::End(ref e)) => {
+                if e.name() == b"description" {
+                    break;
+                }
+                if path.len() == 2 && path[0] == b"title-info"
+                   && path[1] == b"author" {
+                    let name = author_name(&author);
+                    if !name.is_empty() {
+                        desc.authors.push(name);
+                    }
+                    author.clear();
+                }
+                path.pop();
+            }
+            Ok(Event::Text(e)) if in_desc => {
+                let t = e.unescape_and_decode(&reader)?;
+                let t = t.trim();
+                let p: Vec<&[u8]> = path.iter().map(|p| &p[..]).collect();
+                match p[..] {
+                    _ if t.is_empty() => (),
+                    [b"document-info", b"id"] => desc.id = Some(t.to_string()),
+                    [b"title-info", b"book-title"] => desc.title = Some(t.to_string()),
+                    [b"title-info", b"author", part] => {
+                        author.push((part.to_vec(), t.to_string()));
+                    }
+                    [b"title-info", b"lang"] => desc.lang = Some(t.to_string()),
+                    [b"title-info", b"genre"] => desc.genres.push(t.to_string()),
+                    [b"publish-info", b"publisher"] => desc.publisher = Some(t.to_string()),
+                    [b"publish-info", b"year"] => desc.year = Some(t.to_string()),
+                    // The date of writing, if there is no year of
+                    // publication.
+                    [b"title-info", b"date"] if desc.year.is_none() => {
+                        desc.year = Some(t.to_string());
+                    }
+                    _ => (),
+                }
+            }
+            // Books without description (or broken xml) simply do
+            // not have the meta information.
+            Ok(Event::Eof) | Err(_) => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(desc)
+}
+
+// The meta information in the attributes of the element at `path`
+// inside of `<description>`: the series and the cover.
+fn element_attributes<B: std::io::BufRead>(reader: &Reader<B>, e: &BytesStart,
+                                           path: &[Vec<u8>], desc: &mut Description) {
+    let attr = |name: &[u8]| e.attributes().flatten()
+        .find(|a| a.key == name || a.key.ends_with(&[&b":"[..], name].concat()))
+        .and_then(|a| a.unescape_and_decode_value(reader).ok());
+    let p: Vec<&[u8]> = path.iter().map(|p| &p[..]).collect();
+    match p[..] {
+        [b"title-info", b"sequence"] if desc.series.is_none() => {
+            desc.series = attr(b"name").map(|n| n.trim().to_string()).filter(|n| !n.is_empty());
+            desc.series_number = attr(b"number").and_then(|n| n.trim().parse().ok());
+        }
+        [b"title-info", b"coverpage", b"image"] if desc.cover.is_none() => {
+            desc.cover = attr(b"href").map(|h| h.trim_start_matches('#').to_string());
+        }
+        _ => (),
+    }
+}
+
+// Assemble the name of the author from the parts found in `<author>`.
+// If there is no name, we use the nickname.
+fn author_name(parts: &[(Vec<u8>, String)]) -> String {
+    let get = |k: &[u8]| parts.iter().find(|(n, _)| n == k).map(|(_, v)| v.as_str());
+    let name = [get(b"first-name"), get(b"middle-name"), get(b"last-name")]
+               .iter().flatten().cloned().collect::<Vec<_>>().join(" ");
+    if name.is_empty() {
+        get(b"nickname").unwrap_or("").to_string()
+    } else {
+        name
+    }
+}
+
+// 64-bit FNV-1a hash.  We don't use `DefaultHasher` from std, as its
+// algorithm is not guaranteed to be stable between Rust releases, and
+// the hash ends up in the settings file.
+pub fn content_hash(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in data {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h
+}
+
+// The identity of the book that does not depend on where the file lives.
+// We use the id from `<document-info>` if the book has one, otherwise we
+// fall back to the hash of the (uncompressed) content.
+pub fn book_id(data: &[u8], desc: &Description) -> String {
+    match &desc.id {
+        Some(id) => format!("id:{}", id),
+        None => format!("hash:{:016x}", content_hash(data)),
+    }
+}
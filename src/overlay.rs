@@ -0,0 +1,179 @@
+// A list of items shown over the text, e.g. search results, from
+// which the reader can choose one.
+
+use termion::{
+    event::Key, input::Keys, style
+};
+use std::io::{
+    Read, Write
+};
+
+// Cut the string to fit into `width` characters.
+pub fn fit(s: &str, width: usize) -> String {
+    if s.chars().count() <= width {
+        return s.to_string();
+    }
+    let mut r: String = s.chars().take(width.saturating_sub(1)).collect();
+    r.push('…');
+    r
+}
+
+// Show the list of `items` with the `title` on a screen of the given
+// size, starting with the item `selected`.  Returns the index of the
+// chosen item, or None if the reader cancelled the choice.
+pub fn choose<R: Read, W: Write> (keys: &mut Keys<R>,
+                                  out: &mut W,
+                                  size: (usize, usize),
+                                  title: &str,
+                                  items: &[String],
+                                  selected: usize) -> anyhow::Result<Option<usize>> {
+    Ok(choose_action(keys, out, size, title, items, selected, &[])?
+       .map(|(i, _)| i))
+}
+
+// Same as `choose`, but the reader can also apply one of the `actions`
+// (a key and its description) to the selected item.  Returns the index
+// of the item and the key of the action, or None if the item was
+// simply chosen with Enter.
+pub fn choose_action<R: Read, W: Write> (keys: &mut Keys<R>,
+                                         out: &mut W,
+                                         (width, height): (usize, usize),
+                                         title: &str,
+                                         items: &[String],
+                                         selected: usize,
+                                         actions: &[(char, &str)])
+                                         -> anyhow::Result<Option<(usize, Option<char>)>> {
+    if items.is_empty() {
+        return Ok(None);
+    }
+    // The title and the help line take two rows.
+    let rows = height.saturating_sub(2).max(1);
+    let mut sel = selected.min(items.len() - 1);
+    let mut top = sel.saturating_sub(rows / 2);
+
+    loop {
+        if sel < top {
+            top = sel;
+        } else if sel >= top + rows {
+            top = sel + 1 - rows;
+        }
+
+        write!(out, "{}{}{}{}{}", termion::clear::All,
+               termion::cursor::Goto(1, 1), style::Bold,
+               fit(title, width), style::Reset)?;
+        for (i, item) in items.iter().enumerate().skip(top).take(rows) {
+            write!(out, "{}", termion::cursor::Goto(1, (i - top + 2) as u16))?;
+            let item = fit(&format!(" {}", item), width);
+            if i == sel {
+                write!(out, "{}{}{}", style::Invert, item, style::Reset)?;
+            } else {
+                write!(out, "{}", item)?;
+            }
+        }
+        let mut help = format!(" {}/{}  Enter: select  Esc: cancel",
+                               sel + 1, items.len());
+        for (k, d) in actions {
+            help.push_str(&format!("  {}: {}", k, d));
+        }
+        write!(out, "{}{}", termion::cursor::Goto(1, height as u16),
+               fit(&help, width))?;
+        out.flush()?;
+
+        match keys.next() {
+            Some(Ok(Key::Char('\n'))) => return Ok(Some((sel, None))),
+            Some(Ok(Key::Char(c))) if actions.iter().any(|a| a.0 == c) => {
+                return Ok(Some((sel, Some(c))))
+            }
+            Some(Ok(Key::Esc)) | Some(Ok(Key::Char('q'))) | None => return Ok(None),
+            Some(Ok(Key::Up)) | Some(Ok(Key::Char('k'))) => sel = sel.saturating_sub(1),
+            Some(Ok(Key::Down)) | Some(Ok(Key::Char('j'))) => {
+                sel = (sel + 1).min(items.len() - 1)
+            }
+            Some(Ok(Key::PageUp)) => sel = sel.saturating_sub(rows),
+            Some(Ok(Key::PageDown)) => sel = (sel + rows).min(items.len() - 1),
+            Some(Ok(Key::Home)) => sel = 0,
+            Some(Ok(Key::End)) => sel = items.len() - 1,
+            Some(Ok(_)) => (),
+            Some(Err(e)) => return Err(e.into()),
+        }
+    }
+}
+
+// Wrap the text into lines of at most `width` characters.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for para in text.lines() {
+        let mut l = String::new();
+        for w in para.split_whitespace() {
+            if !l.is_empty() && l.chars().count() + 1 + w.chars().count() > width {
+                lines.push(std::mem::take(&mut l));
+            }
+            if !l.is_empty() {
+                l.push(' ');
+            }
+            l.push_str(w);
+        }
+        lines.push(fit(&l, width));
+    }
+    lines
+}
+
+// Show the `text` in a box at the bottom half of the screen, over
+// the text of the book.  The box is closed with Esc, `q` or Enter,
+// or with one of the `actions` (a key and its description), in which
+// case we return the key of the action.
+pub fn popup<R: Read, W: Write> (keys: &mut Keys<R>,
+                                 out: &mut W,
+                                 (width, height): (usize, usize),
+                                 title: &str,
+                                 text: &str,
+                                 actions: &[(char, &str)]) -> anyhow::Result<Option<char>> {
+    let w = width.saturating_sub(2).max(4);
+    let h = (height / 2).max(4);
+    // The first row of the box.
+    let row = height.saturating_sub(h) + 1;
+    let lines = wrap(text, w - 4);
+    let rows = h - 2;
+    let mut top = 0;
+
+    loop {
+        let border = "─".repeat(w - 2);
+        write!(out, "{}┌{}┐", termion::cursor::Goto(2, row as u16), border)?;
+        let t = fit(&format!(" {} ", title), w - 4);
+        write!(out, "{}{}{}{}", termion::cursor::Goto(4, row as u16),
+               style::Bold, t, style::Reset)?;
+        for i in 0..rows {
+            let l = lines.get(top + i).map_or("", |l| l.as_str());
+            write!(out, "{}│ {:<2$} │", termion::cursor::Goto(2, (row + 1 + i) as u16),
+                   l, w - 4)?;
+        }
+        write!(out, "{}└{}┘", termion::cursor::Goto(2, (row + h - 1) as u16), border)?;
+        let help: Vec<String> = actions.iter().map(|(k, d)| format!("{}: {}", k, d))
+                                       .collect();
+        if !help.is_empty() {
+            write!(out, "{}{}", termion::cursor::Goto(4, (row + h - 1) as u16),
+                   fit(&format!(" {} ", help.join("  ")), w / 2))?;
+        }
+        if lines.len() > rows {
+            let more = format!(" {}/{} ", top + rows.min(lines.len() - top), lines.len());
+            let col = w.saturating_sub(more.len()).max(1);
+            write!(out, "{}{}", termion::cursor::Goto(col as u16, (row + h - 1) as u16), more)?;
+        }
+        out.flush()?;
+
+        let last = lines.len().saturating_sub(rows);
+        match keys.next() {
+            Some(Ok(Key::Esc)) | Some(Ok(Key::Char('q'))) | Some(Ok(Key::Char('\n')))
+            | None => return Ok(None),
+            Some(Ok(Key::Char(c))) if actions.iter().any(|a| a.0 == c) => {
+                return Ok(Some(c))
+            }
+            Some(Ok(Key::Up)) | Some(Ok(Key::Char('k'))) => top = top.saturating_sub(1),
+            Some(Ok(Key::Down)) | Some(Ok(Key::Char('j'))) => top = (top + 1).min(last),
+            Some(Ok(Key::PageUp)) => top = top.saturating_sub(rows),
+            Some(Ok(Key::PageDown)) => top = (top + rows).min(last),
+            Some(Ok(_)) => (),
+            Some(Err(e)) => return Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,494 @@
+// A quick pass over the entire book that collects the information
+// we need before laying out the text: the total length of the text
+// (so that we can show how far we are in the book), and the list of
+// chapters.  The result is cached per book in the settings file.
+//
+// Note that the text nodes have to be counted exactly the same way
+// as `crank` does it, otherwise the positions won't match, so both
+// count them with `NodeCounter`.
+
+use quick_xml::{
+    Reader, events::Event
+};
+use serde::{
+    Serialize, Deserialize
+};
+use crate::BookState;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Chapter {
+    pub title: String,
+    // Nesting level of the section, starting with 1.
+    pub depth: usize,
+    // Position of the first word of the title.
+    pub xml_offset: BookState,
+    pub text_offset: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BookScan {
+    // The size and the hash (see `meta::content_hash`) of the book,
+    // we use them to check that the cached scan belongs to the same
+    // content.
+    pub size: usize,
+    #[serde(default)]
+    pub hash: u64,
+    // Number of non-whitespace characters in the book.
+    pub total_chars: usize,
+    #[serde(default)]
+    pub total_words: usize,
+    pub chapters: Vec<Chapter>,
+}
+
+// The length of the text that we use to measure the progress.  We
+// ignore whitespaces, as their amount depends on formatting.
+pub fn text_len(s: &str) -> usize {
+    s.chars().filter(|c| !c.is_whitespace()).count()
+}
+
+// Counts the text nodes of the book the way the positions
+// (`BookState::tag_count`) refer to them: the text of `<binary>` and
+// `<description>` is not a part of the book.
+#[derive(Debug, Default)]
+pub struct NodeCounter {
+    pub tag_count: usize,
+    skip: bool,
+}
+
+impl NodeCounter {
+    // Take the next event of the book into account.  Returns the number
+    // of the text node if the event is a text node of the book.
+    pub fn feed(&mut self, e: &Event) -> Option<usize> {
+        match e {
+            Event::Start(e) if matches!(e.name(), b"binary" | b"description") => {
+                self.skip = true;
+            }
+            Event::End(e) if matches!(e.name(), b"binary" | b"description") => {
+                self.skip = false;
+            }
+            Event::Text(_) if !self.skip => {
+                self.tag_count += 1;
+                return Some(self.tag_count);
+            }
+            _ => (),
+        }
+        None
+    }
+
+    // Whether we are outside of the text of the book.
+    pub fn skipping(&self) -> bool {
+        self.skip
+    }
+}
+
+// Go through the events of the book, telling `f` the number of the text
+// node (see `NodeCounter`) for the text nodes of the book.
+fn walk(data: &[u8], mut f: impl FnMut(&Reader<&[u8]>, &Event, Option<usize>)
+                                       -> anyhow::Result<()>) -> anyhow::Result<()> {
+    let mut reader = Reader::from_reader(data);
+    let mut buf = Vec::new();
+    let mut counter = NodeCounter::default();
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Eof) => break,
+            Ok(e) => {
+                let n = counter.feed(&e);
+                f(&reader, &e, n)?;
+            }
+            Err(e) => {
+                return Err(anyhow::anyhow!("Error at position {}: {:?}",
+                           reader.buffer_position(), e))
+            }
+        }
+        buf.clear();
+    }
+    Ok(())
+}
+
+impl BookScan {
+    // The innermost chapter that contains the position `s`.
+    pub fn chapter_at(&self, s: BookState) -> Option<&Chapter> {
+        self.chapters.iter().rev().find(|c| c.xml_offset <= s)
+    }
+
+    // The chapter that follows the position `s`.
+    pub fn next_chapter(&self, s: BookState) -> Option<&Chapter> {
+        self.chapters.iter().find(|c| c.xml_offset > s)
+    }
+
+    // Average number of characters (as in `text_len`) per word.
+    pub fn chars_per_word(&self) -> f64 {
+        if self.total_words == 0 {
+            return 6.0;
+        }
+        self.total_chars as f64 / self.total_words as f64
+    }
+
+    pub fn percent(&self, text_offset: usize) -> usize {
+        if self.total_chars == 0 {
+            return 100;
+        }
+        core::cmp::min(100, text_offset * 100 / self.total_chars)
+    }
+}
+
+pub fn scan_book(data: &[u8]) -> anyhow::Result<BookScan> {
+    let mut scan = BookScan { size: data.len(), hash: crate::meta::content_hash(data),
+                              ..BookScan::default() };
+    // Bodies with a name (notes, comments) do not have chapters.
+    let mut in_notes = false;
+    let mut depth = 0;
+    // The title that we are collecting right now.
+    let mut title: Option<Chapter> = None;
+
+    walk(data, |reader, ev, n| {
+        match ev {
+            Event::Start(e) => {
+                match e.name() {
+                    b"body" => {
+                        in_notes = e.attributes()
+                                    .flatten()
+                                    .any(|a| a.key == b"name");
+                    }
+                    b"section" => { depth += 1; }
+                    b"title" if depth > 0 && !in_notes => {
+                        title = Some(Chapter { title: String::new(), depth,
+                                               xml_offset: BookState::default(),
+                                               text_offset: 0 });
+                    }
+                    _ => (),
+                }
+            }
+            Event::End(e) => {
+                match e.name() {
+                    b"section" => { depth -= 1; }
+                    b"title" => {
+                        if let Some(c) = title.take() {
+                            if !c.title.is_empty() {
+                                scan.chapters.push(c);
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            Event::Text(e) => {
+                let tag_count = match n {
+                    Some(n) => n,
+                    None => return Ok(()),
+                };
+                let t = e.unescape_and_decode(reader)?;
+                if let Some(c) = &mut title {
+                    let t = t.split_whitespace().collect::<Vec<_>>().join(" ");
+                    if !t.is_empty() {
+                        if c.title.is_empty() {
+                            c.xml_offset.tag_count = tag_count;
+                            c.text_offset = scan.total_chars;
+                        } else {
+                            c.title.push(' ');
+                        }
+                        c.title.push_str(&t);
+                    }
+                }
+                scan.total_chars += text_len(&t);
+                scan.total_words += t.split_whitespace().count();
+            }
+            _ => (),
+        }
+        Ok(())
+    })?;
+    Ok(scan)
+}
+
+// A text node of the book as `crank` sees it.
+pub struct TextNode {
+    pub tag_count: usize,
+    // The number of the paragraph (or of another block of text) that
+    // the node is in, the nodes between the blocks get a number of
+    // their own.
+    pub para: usize,
+    // Whether there is whitespace between the node and the previous
+    // one (in the nodes that we leave out).
+    pub spaced: bool,
+    // Text offset of the beginning of the node.
+    pub text_offset: usize,
+    pub text: String,
+}
+
+// The elements whose text is a paragraph of its own.
+fn is_block(name: &[u8]) -> bool {
+    matches!(name, b"p" | b"v" | b"subtitle" | b"text-author" | b"pre" | b"td" | b"th")
+}
+
+// Collect all the (non-empty) text nodes of the book.
+pub fn text_nodes(data: &[u8]) -> anyhow::Result<Vec<TextNode>> {
+    let mut nodes = Vec::new();
+    let mut text_offset = 0;
+    let mut para = 0;
+    let mut spaced = false;
+    walk(data, |reader, ev, n| {
+        match (ev, n) {
+            (Event::Start(e), _) if is_block(e.name()) => para += 1,
+            (Event::End(e), _) if is_block(e.name()) => para += 1,
+            (Event::Text(e), Some(tag_count)) => {
+                let t = e.unescape_and_decode(reader)?;
+                let len = text_len(&t);
+                if len > 0 {
+                    nodes.push(TextNode { tag_count, para, spaced, text_offset, text: t });
+                    spaced = false;
+                } else if !t.is_empty() {
+                    spaced = true;
+                }
+                text_offset += len;
+            }
+            _ => (),
+        }
+        Ok(())
+    })?;
+    Ok(nodes)
+}
+
+// The byte offset of the character `c` of the word number `w` in the
+// text, counting the words as `OutText::out` does.  Positions past
+// the end of the text give the length of the text.
+fn byte_pos(text: &str, w: usize, c: usize) -> usize {
+    let base = text.as_ptr() as usize;
+    match text.split_whitespace().nth(w) {
+        Some(word) => {
+            let start = word.as_ptr() as usize - base;
+            start + word.char_indices().nth(c).map_or(word.len(), |(i, _)| i)
+        }
+        None => text.len(),
+    }
+}
+
+// The text between the positions `from` and `to` (or until we collect
+// about `n` characters), all on one line.  Text nodes of a paragraph
+// (e.g. a word and the emphasis right after it) are joined as they are
+// in the book, the paragraphs are separated by a space.
+pub fn text_between(nodes: &[TextNode], from: BookState, to: Option<BookState>,
+                    n: usize) -> String {
+    let first = nodes.partition_point(|t| t.tag_count < from.tag_count);
+    let mut res = String::new();
+    let mut prev: Option<&TextNode> = None;
+    for t in &nodes[first..] {
+        if to.is_some_and(|to| t.tag_count > to.tag_count) {
+            break;
+        }
+        if prev.is_some_and(|p| t.para != p.para || t.spaced) {
+            res.push(' ');
+        }
+        let a = if t.tag_count == from.tag_count {
+            byte_pos(&t.text, from.word_offset, from.char_offset)
+        } else { 0 };
+        let b = match to {
+            Some(to) if t.tag_count == to.tag_count => {
+                byte_pos(&t.text, to.word_offset, to.char_offset)
+            }
+            _ => t.text.len(),
+        };
+        res.push_str(&t.text[a..b.max(a)]);
+        prev = Some(t);
+        if res.chars().count() >= n {
+            break;
+        }
+    }
+    // Cut the text at the word boundary.
+    let mut len = 0;
+    res.split_whitespace()
+       .take_while(|w| {
+           let more = len < n;
+           len += w.chars().count() + 1;
+           more
+       })
+       .collect::<Vec<_>>().join(" ")
+}
+
+// About `n` characters of the text starting at the position `s`,
+// all on one line.
+pub fn text_at(nodes: &[TextNode], s: BookState, n: usize) -> String {
+    text_between(nodes, s, None, n)
+}
+
+// The text offset (see `text_len`) of the position `s`.
+pub fn text_offset_at(nodes: &[TextNode], s: BookState) -> usize {
+    let i = nodes.partition_point(|t| t.tag_count < s.tag_count);
+    match nodes.get(i) {
+        Some(t) if t.tag_count == s.tag_count => {
+            t.text_offset + text_len(&t.text[..byte_pos(&t.text, s.word_offset,
+                                                        s.char_offset)])
+        }
+        Some(t) => t.text_offset,
+        None => nodes.last().map_or(0, |t| t.text_offset + text_len(&t.text)),
+    }
+}
+
+// The index of the node with the text at the position `s`.
+fn node_at(nodes: &[TextNode], s: BookState) -> Option<usize> {
+    nodes.binary_search_by_key(&s.tag_count, |t| t.tag_count).ok()
+}
+
+// The word at the position `s`.
+pub fn word_at(nodes: &[TextNode], s: BookState) -> Option<&str> {
+    nodes[node_at(nodes, s)?].text.split_whitespace().nth(s.word_offset)
+}
+
+// The position right after the last character of the word at `s`.
+pub fn word_end(nodes: &[TextNode], s: BookState) -> BookState {
+    let len = word_at(nodes, s).map_or(0, |w| w.chars().count());
+    BookState { char_offset: len, ..s }
+}
+
+// The beginning of the word that follows the one at the position `s`.
+pub fn next_word(nodes: &[TextNode], s: BookState) -> Option<BookState> {
+    let i = nodes.partition_point(|t| t.tag_count < s.tag_count);
+    let t = nodes.get(i)?;
+    if t.tag_count == s.tag_count {
+        if s.word_offset + 1 < t.text.split_whitespace().count() {
+            return Some(BookState { word_offset: s.word_offset + 1,
+                                    char_offset: 0, ..s });
+        }
+        let t = nodes.get(i + 1)?;
+        return Some(BookState { tag_count: t.tag_count, ..BookState::default() });
+    }
+    Some(BookState { tag_count: t.tag_count, ..BookState::default() })
+}
+
+// The beginning of the word that precedes the one at the position `s`.
+pub fn prev_word(nodes: &[TextNode], s: BookState) -> Option<BookState> {
+    if s.word_offset > 0 && node_at(nodes, s).is_some() {
+        return Some(BookState { word_offset: s.word_offset - 1,
+                                char_offset: 0, ..s });
+    }
+    let i = nodes.partition_point(|t| t.tag_count < s.tag_count);
+    let t = &nodes[..i].last()?;
+    Some(BookState { tag_count: t.tag_count,
+                     word_offset: t.text.split_whitespace().count() - 1,
+                     char_offset: 0 })
+}
+
+// Whether the word ends a sentence, e.g. "конец." or "конец?»".
+fn ends_sentence(w: &str) -> bool {
+    w.trim_end_matches(['»', '"', '”', ')', '\''])
+     .ends_with(['.', '!', '?', '…'])
+}
+
+// Whether the words at `a` and `b` belong to the same paragraph.
+fn same_paragraph(nodes: &[TextNode], a: BookState, b: BookState) -> bool {
+    match (node_at(nodes, a), node_at(nodes, b)) {
+        (Some(i), Some(j)) => nodes[i].para == nodes[j].para,
+        _ => false,
+    }
+}
+
+// The first word of the sentence with the word at `s`.  The sentences
+// do not go across paragraphs.
+pub fn sentence_start(nodes: &[TextNode], s: BookState) -> BookState {
+    let mut w = s;
+    while let Some(p) = prev_word(nodes, w) {
+        if !same_paragraph(nodes, p, w) || word_at(nodes, p).is_none_or(ends_sentence) {
+            break;
+        }
+        w = p;
+    }
+    w
+}
+
+// The last word of the sentence with the word at `s`.
+pub fn sentence_end(nodes: &[TextNode], s: BookState) -> BookState {
+    let mut w = s;
+    while word_at(nodes, w).is_some_and(|x| !ends_sentence(x)) {
+        match next_word(nodes, w) {
+            Some(n) if same_paragraph(nodes, w, n) => w = n,
+            _ => break,
+        }
+    }
+    w
+}
+
+// An internal link of the book, e.g. a footnote.
+pub struct Link {
+    // Position of the link text.
+    pub pos: BookState,
+    pub text: String,
+    // The id the link points to (without the leading '#').
+    pub target: String,
+}
+
+#[derive(Default)]
+pub struct Links {
+    // In the order of the book.
+    pub links: Vec<Link>,
+    // Positions of the elements that have an id.
+    pub anchors: std::collections::HashMap<String, BookState>,
+}
+
+impl Links {
+    // The links in the part of the book between `from` and `to`
+    // (not including), and the positions they point to.
+    pub fn between(&self, from: BookState, to: Option<BookState>) -> Vec<(&Link, BookState)> {
+        self.links.iter()
+            .filter(|l| l.pos >= from && to.is_none_or(|t| l.pos < t))
+            .filter_map(|l| self.anchors.get(&l.target).map(|a| (l, *a)))
+            .collect()
+    }
+}
+
+// Collect the internal links of the book and their targets.  The
+// positions point to the first non-empty text node after the start
+// of the element, as the empty ones do not make it to the lines.
+pub fn links(data: &[u8]) -> anyhow::Result<Links> {
+    let mut res = Links::default();
+    // The link whose text we are collecting.
+    let mut link: Option<Link> = None;
+    // Ids of the elements that do not have text yet.
+    let mut ids: Vec<String> = Vec::new();
+    walk(data, |reader, ev, n| {
+        match ev {
+            Event::Start(e) => {
+                for a in e.attributes().flatten() {
+                    let v = a.unescape_and_decode_value(reader)?;
+                    if a.key == b"id" {
+                        ids.push(v);
+                    } else if e.name() == b"a" && a.key.ends_with(b"href") {
+                        if let Some(t) = v.strip_prefix('#') {
+                            link = Some(Link { pos: BookState::default(),
+                                               text: String::new(),
+                                               target: t.to_string() });
+                        }
+                    }
+                }
+            }
+            Event::End(e) if e.name() == b"a" => {
+                if let Some(l) = link.take() {
+                    if !l.text.is_empty() {
+                        res.links.push(l);
+                    }
+                }
+            }
+            Event::Text(e) => {
+                let tag_count = match n {
+                    Some(n) => n,
+                    None => return Ok(()),
+                };
+                let t = e.unescape_and_decode(reader)?;
+                if text_len(&t) == 0 {
+                    return Ok(());
+                }
+                let pos = BookState { tag_count, ..BookState::default() };
+                for id in ids.drain(..) {
+                    res.anchors.insert(id, pos);
+                }
+                if let Some(l) = &mut link {
+                    if l.text.is_empty() {
+                        l.pos = pos;
+                    }
+                    l.text.push_str(t.trim());
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    })?;
+    Ok(res)
+}
@@ -0,0 +1,203 @@
+// Full-text search.  We search in the text nodes of the book rather
+// than in the lines that we have laid out, so that the words broken
+// by hyphenation are found as well as the text that we haven't
+// laid out yet.
+
+use regex::{
+    Regex, RegexBuilder
+};
+use crate::{
+    BookState, scan::{self, TextNode}
+};
+
+// Fold the letters that are used interchangeably, so that searching
+// for "еще" finds "ещё".  Note that the folded string must have the
+// same byte offsets as the original one.
+pub fn fold(s: &str) -> String {
+    s.chars().map(|c| match c {
+        'ё' => 'е',
+        'Ё' => 'Е',
+        c => c,
+    }).collect()
+}
+
+// Compile the search pattern.  The pattern is a plain text, unless
+// it starts with `\v`, in which case it is a regular expression.
+// The search is case-insensitive, unless the pattern contains `\C`.
+pub fn compile(pattern: &str) -> anyhow::Result<Regex> {
+    let case_sensitive = pattern.contains("\\C");
+    let p = pattern.replace("\\C", "");
+    let p = match p.strip_prefix("\\v") {
+        Some(re) => re.to_string(),
+        None => regex::escape(&p),
+    };
+    Ok(RegexBuilder::new(&fold(&p))
+       .case_insensitive(!case_sensitive)
+       .build()?)
+}
+
+#[derive(Debug, Clone)]
+pub struct Hit {
+    // Position of the first character and the position right after
+    // the last character of the match.
+    pub start: BookState,
+    pub end: BookState,
+    pub text_offset: usize,
+    // Index of the text node and the byte range of the match in it.
+    pub node: usize,
+    pub range: (usize, usize),
+}
+
+// Convert the byte offset in the text node into the word number and
+// the character offset in that word, counting the words the same way
+// as `OutText::out` does.  Offsets that point to a whitespace refer
+// to the beginning of the next word.
+fn word_pos(text: &str, b: usize) -> (usize, usize) {
+    let base = text.as_ptr() as usize;
+    let mut n = 0;
+    for (i, w) in text.split_whitespace().enumerate() {
+        let start = w.as_ptr() as usize - base;
+        if b < start + w.len() {
+            return if b <= start {
+                (i, 0)
+            } else {
+                (i, text[start..b].chars().count())
+            };
+        }
+        n = i + 1;
+    }
+    (n, 0)
+}
+
+pub fn find_all(nodes: &[TextNode], re: &Regex) -> Vec<Hit> {
+    let mut hits = Vec::new();
+    for (k, n) in nodes.iter().enumerate() {
+        let t = fold(&n.text);
+        for m in re.find_iter(&t) {
+            // The byte offset of the last non-whitespace character.
+            let last = match m.as_str().char_indices()
+                              .rfind(|(_, c)| !c.is_whitespace()) {
+                Some((i, _)) => m.start() + i,
+                None => continue,
+            };
+            let (w, c) = word_pos(&t, m.start());
+            let (we, ce) = word_pos(&t, last);
+            hits.push(Hit {
+                start: BookState { tag_count: n.tag_count,
+                                   word_offset: w, char_offset: c },
+                end: BookState { tag_count: n.tag_count,
+                                 word_offset: we, char_offset: ce + 1 },
+                text_offset: n.text_offset + scan::text_len(&t[..m.start()]),
+                node: k,
+                range: (m.start(), m.end()),
+            });
+        }
+    }
+    hits
+}
+
+// The text around the hit with `ctx` characters of context on
+// each side, all on one line.
+pub fn snippet(nodes: &[TextNode], h: &Hit, ctx: usize) -> String {
+    let t = &nodes[h.node].text;
+    let before: String = t[..h.range.0].chars().rev().take(ctx).collect();
+    let before: String = before.chars().rev().collect();
+    let after: String = t[h.range.1..].chars().take(ctx).collect();
+    let mut s = String::new();
+    if before.len() < h.range.0 {
+        s.push('…');
+    }
+    s.push_str(&before);
+    s.push_str(&t[h.range.0..h.range.1]);
+    s.push_str(&after);
+    if h.range.1 + after.len() < t.len() {
+        s.push('…');
+    }
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// The last search and its results.
+pub struct Search {
+    pub pattern: String,
+    pub forward: bool,
+    pub hits: Vec<Hit>,
+    // The hit we have jumped to last time.
+    pub current: Option<usize>,
+    // Whether we highlight the hits on the screen.
+    pub visible: bool,
+}
+
+impl Search {
+    pub fn new(pattern: &str, forward: bool, hits: Vec<Hit>) -> Search {
+        Search { pattern: pattern.to_string(), forward, hits,
+                 current: None, visible: true }
+    }
+
+    // The index of the first hit after the position `s` (or before it if
+    // we are searching backwards), wrapping around the book.  The hits
+    // at `s` are included if `inclusive` is set.  The flag in the result
+    // says whether we had to wrap around.
+    pub fn find(&self, s: BookState, forward: bool,
+                inclusive: bool) -> Option<(usize, bool)> {
+        if self.hits.is_empty() {
+            return None;
+        }
+        if forward {
+            let i = self.hits.partition_point(|h| h.start < s
+                                              || (!inclusive && h.start == s));
+            if i < self.hits.len() { Some((i, false)) } else { Some((0, true)) }
+        } else {
+            let i = self.hits.partition_point(|h| h.start < s
+                                              || (inclusive && h.start == s));
+            if i > 0 { Some((i - 1, false)) } else { Some((self.hits.len() - 1, true)) }
+        }
+    }
+
+    // The hits that start in the text nodes between `from` and `to`.
+    pub fn hits_between(&self, from: usize, to: usize) -> &[Hit] {
+        let a = self.hits.partition_point(|h| h.start.tag_count < from);
+        let b = self.hits.partition_point(|h| h.start.tag_count <= to);
+        &self.hits[a..b]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(body: &str) -> Vec<TextNode> {
+        let fb2 = format!("<FictionBook><body><section>{}</section></body></FictionBook>", body);
+        scan::text_nodes(fb2.as_bytes()).unwrap()
+    }
+
+    fn pos(tag_count: usize, word_offset: usize, char_offset: usize) -> BookState {
+        BookState { tag_count, word_offset, char_offset }
+    }
+
+    #[test]
+    fn case_and_yo() {
+        let n = nodes("<p>Ещё раз, и ЕЩЁ, и еще.</p>");
+        let t = n[0].tag_count;
+        let hits = find_all(&n, &compile("еще").unwrap());
+        let starts: Vec<BookState> = hits.iter().map(|h| h.start).collect();
+        assert_eq!(starts, [pos(t, 0, 0), pos(t, 3, 0), pos(t, 5, 0)]);
+        assert_eq!(hits[1].end, pos(t, 3, 3));
+        // `\C` makes the search case-sensitive, and the plain text is
+        // not a regular expression unless it starts with `\v`.
+        assert_eq!(find_all(&n, &compile("\\CЕщё").unwrap()).len(), 1);
+        assert!(find_all(&n, &compile("е.е").unwrap()).is_empty());
+        assert_eq!(find_all(&n, &compile("\\vе.е").unwrap()).len(), 3);
+    }
+
+    // The words are whole in the text nodes, so a word that the layout
+    // breaks at the end of the line is found, and the hit ends in it.
+    #[test]
+    fn hyphenated_word() {
+        let n = nodes("<p>Смотрите: достопримечательности <emphasis>города</emphasis>.</p>");
+        let t = n[0].tag_count;
+        let hits = find_all(&n, &compile("примечательности").unwrap());
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].start, hits[0].end), (pos(t, 1, 5), pos(t, 1, 21)));
+        assert_eq!(snippet(&n, &hits[0], 5), "…достопримечательности");
+    }
+}
@@ -0,0 +1,187 @@
+// Reading speed and reading history.  We measure the speed by timing
+// how long it takes to move through the text: every time the reader
+// moves forward we account the time since the previous move, unless
+// the gap is so long that the reader was most likely doing something
+// else, or so short that the reader was skimming.
+
+use serde::{
+    Serialize, Deserialize
+};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH}
+};
+
+// Gaps between the page turns longer than this are treated as idle time.
+const IDLE_GAP: Duration = Duration::from_secs(5 * 60);
+// Gaps shorter than this are treated as skimming through the text.
+const SKIM_GAP: Duration = Duration::from_secs(2);
+// We don't trust the speed until we measure at least this much reading.
+const MIN_SECS: u64 = 2 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub struct Speed {
+    pub words: usize,
+    pub secs: u64,
+}
+
+impl Speed {
+    // Words per minute, if we have enough measurements.
+    pub fn wpm(&self) -> Option<f64> {
+        if self.secs < MIN_SECS || self.words == 0 {
+            return None;
+        }
+        Some(self.words as f64 * 60.0 / self.secs as f64)
+    }
+
+    fn add(&mut self, words: usize, secs: u64) {
+        self.words += words;
+        self.secs += secs;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    // Book identity and its name at the time of reading.
+    pub book: String,
+    pub name: String,
+    // Unix time of the beginning of the session.
+    pub start: u64,
+    // Time spent reading (excluding idle gaps).
+    pub secs: u64,
+    pub pages: usize,
+    pub words: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ReadingStats {
+    // Overall speed across all the books.
+    #[serde(default)]
+    pub total: Speed,
+    // Speed per book, keyed by the book identity.
+    #[serde(default)]
+    pub books: BTreeMap<String, Speed>,
+    #[serde(default)]
+    pub sessions: Vec<Session>,
+}
+
+impl ReadingStats {
+    // The speed that we use for estimates of the given book: the speed
+    // for this book if we have measured it, otherwise the overall one.
+    pub fn wpm(&self, book: &str) -> Option<f64> {
+        self.books.get(book).and_then(|s| s.wpm())
+            .or_else(|| self.total.wpm())
+    }
+}
+
+// Measurements of the current reading session.
+pub struct Tracker {
+    last: Instant,
+    session: Session,
+    // Lines we moved forward through.
+    lines: usize,
+}
+
+impl Tracker {
+    pub fn new(book: &str, name: &str) -> Tracker {
+        let start = SystemTime::now().duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs());
+        Tracker { last: Instant::now(), lines: 0,
+                  session: Session { book: book.to_string(),
+                                     name: name.to_string(),
+                                     start, secs: 0, pages: 0, words: 0 } }
+    }
+
+    // The reader moved forward by `lines` lines containing `words` words.
+    pub fn advance(&mut self, stats: &mut ReadingStats, lines: usize, words: usize) {
+        let gap = self.last.elapsed();
+        self.last = Instant::now();
+        self.lines += lines;
+        if gap > IDLE_GAP || gap < SKIM_GAP {
+            return;
+        }
+        let secs = gap.as_secs_f64().round() as u64;
+        self.session.secs += secs;
+        self.session.words += words;
+        stats.total.add(words, secs);
+        stats.books.entry(self.session.book.clone()).or_default().add(words, secs);
+    }
+
+    // Finish the session where the screen had `rows` lines of text.
+    pub fn finish(mut self, stats: &mut ReadingStats, rows: usize) {
+        if self.lines == 0 {
+            return;
+        }
+        self.session.pages = self.lines.div_ceil(rows);
+        stats.sessions.push(self.session);
+    }
+}
+
+// Format the duration given in minutes as "3h05m" or "42m".
+pub fn fmt_minutes(m: u64) -> String {
+    if m >= 60 {
+        format!("{}h{:02}m", m / 60, m % 60)
+    } else {
+        format!("{}m", m)
+    }
+}
+
+// Minutes needed to read `words` words with the speed `wpm`.
+pub fn minutes_left(words: f64, wpm: f64) -> u64 {
+    (words / wpm).ceil() as u64
+}
+
+fn fmt_time(t: u64, fmt: &str) -> String {
+    let tm = time::at(time::Timespec::new(t as i64, 0));
+    time::strftime(fmt, &tm).unwrap_or_default()
+}
+
+// Print the reading history for the `stats` subcommand.
+pub fn print_stats(stats: &ReadingStats) {
+    match stats.total.wpm() {
+        Some(w) => println!("Reading speed: {:.0} words per minute", w),
+        None => println!("Reading speed: not enough data yet"),
+    }
+    println!("Total reading time: {}\n", fmt_minutes(stats.total.secs / 60));
+
+    if stats.sessions.is_empty() {
+        println!("No reading sessions yet.");
+        return;
+    }
+
+    // Sessions are appended in chronological order.
+    println!("Sessions:");
+    for s in &stats.sessions {
+        println!("  {}  {:>7}  {:>4} pages  {}",
+                 fmt_time(s.start, "%Y-%m-%d %H:%M"),
+                 fmt_minutes(s.secs / 60), s.pages, s.name);
+    }
+
+    // Group the sessions by day.
+    let mut days: BTreeMap<String, (u64, usize, usize)> = BTreeMap::new();
+    for s in &stats.sessions {
+        let d = days.entry(fmt_time(s.start, "%Y-%m-%d")).or_default();
+        d.0 += s.secs;
+        d.1 += s.pages;
+        d.2 += 1;
+    }
+    println!("\nPer day:");
+    for (day, (secs, pages, n)) in &days {
+        println!("  {}  {:>7}  {:>4} pages  {} session{}",
+                 day, fmt_minutes(secs / 60), pages, n,
+                 if *n == 1 { "" } else { "s" });
+    }
+
+    println!("\nBooks:");
+    // Print every book once, in the order we started reading them.
+    let mut seen: Vec<&str> = Vec::new();
+    for s in &stats.sessions {
+        if seen.contains(&s.book.as_str()) {
+            continue;
+        }
+        seen.push(&s.book);
+        let sp = stats.books.get(&s.book).copied().unwrap_or_default();
+        let wpm = sp.wpm().map_or("-".to_string(), |w| format!("{:.0} wpm", w));
+        println!("  {:>7}  {:>8}  {}", fmt_minutes(sp.secs / 60), wpm, s.name);
+    }
+}
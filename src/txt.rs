@@ -0,0 +1,205 @@
+// Plain text books.  We guess the structure of the text: paragraphs
+// are separated with blank lines or start with an indent, hard-wrapped
+// lines are joined back, and the lines that look like chapter headings
+// ("CHAPTER X", "Глава 5", "IV") start new sections.
+
+use regex::Regex;
+use crate::{
+    fb2, meta
+};
+
+// Decode the text: UTF-8 or UTF-16 with a BOM, UTF-8 without it,
+// or, failing that, Windows-1251 which most of the older Russian
+// texts use.
+pub fn decode(data: &[u8]) -> String {
+    if let Some((enc, _)) = encoding_rs::Encoding::for_bom(data) {
+        return enc.decode_with_bom_removal(data).0.into_owned();
+    }
+    match std::str::from_utf8(data) {
+        Ok(s) => s.to_string(),
+        Err(_) => encoding_rs::WINDOWS_1251.decode(data).0.into_owned(),
+    }
+}
+
+lazy_static! {
+    static ref PART: Regex = Regex::new(
+        r"^(Part|PART|Book|BOOK|Часть|ЧАСТЬ|Книга|КНИГА)\s+(\d+|[IVXLCDM]+)\b").unwrap();
+    static ref CHAPTER: Regex = Regex::new(
+        r"^(Chapter|CHAPTER|Глава|ГЛАВА)\s+(\d+|[IVXLCDM]+|\w+)\b").unwrap();
+    static ref OTHER: Regex = Regex::new(
+        r"^(?i:prologue|epilogue|пролог|эпилог)[.:]?$").unwrap();
+    static ref NUMBER: Regex = Regex::new(r"^([IVXLCDM]+|\d{1,3})[.:]?$").unwrap();
+}
+
+// Whether the line is a heading of a part (level 1) or of a chapter
+// (level 2).  A bare number (e.g. "IV" or "12.") is a heading only if
+// the line is `alone`, with blank lines around it, otherwise it is
+// likely a page number or a line of a list.
+fn heading_level(line: &str, alone: bool) -> Option<usize> {
+    let l = line.trim();
+    if l.chars().count() > 60 {
+        return None;
+    }
+    if PART.is_match(l) {
+        Some(1)
+    } else if (CHAPTER.is_match(l) && l.split_whitespace().count() <= 6)
+              || OTHER.is_match(l) || (alone && NUMBER.is_match(l)) {
+        Some(2)
+    } else {
+        None
+    }
+}
+
+enum Block {
+    Heading(usize, Vec<String>),
+    Para(String),
+}
+
+// Join the hard-wrapped lines of a paragraph.  A word hyphenated at
+// the end of the line is joined back.
+fn unwrap(lines: &[&str]) -> String {
+    let mut s = String::new();
+    for l in lines {
+        let l = l.trim();
+        let hyphenated = s.ends_with('-')
+                         && s.chars().rev().nth(1).is_some_and(char::is_alphabetic)
+                         && l.starts_with(char::is_lowercase);
+        if hyphenated {
+            s.pop();
+        } else if !s.is_empty() {
+            s.push(' ');
+        }
+        s.push_str(l);
+    }
+    s
+}
+
+// Keep only the text of the book from the Project Gutenberg files,
+// which have a license before and after it.
+fn gutenberg_body(text: &str) -> &str {
+    let start = text.find("*** START OF")
+                    .and_then(|i| text[i..].find('\n').map(|n| i + n + 1))
+                    .unwrap_or(0);
+    let end = text[start..].find("*** END OF").map_or(text.len(), |i| start + i);
+    &text[start..end]
+}
+
+// The value of the "Title: ..." line in the header of the file.
+fn header_field<'a>(text: &'a str, name: &str) -> Option<&'a str> {
+    text.lines().take(60)
+        .find_map(|l| l.strip_prefix(name))
+        .map(str::trim).filter(|v| !v.is_empty())
+}
+
+fn blocks(text: &str) -> Vec<Block> {
+    let lines: Vec<&str> = text.lines().map(str::trim_end).collect();
+    let nonempty: Vec<&&str> = lines.iter().filter(|l| !l.is_empty()).collect();
+    // Texts where most of the lines are long have one paragraph per line.
+    let long = nonempty.iter().filter(|l| l.chars().count() > 100).count();
+    let line_per_para = long * 4 > nonempty.len();
+    // The indent that all the lines have is not an indent.
+    let indent = |l: &str| l.chars().take_while(|c| c.is_whitespace()).count();
+    let common = nonempty.iter().map(|l| indent(l)).min().unwrap_or(0);
+
+    // Whether the line has blank lines (or the ends of the text) around it.
+    let alone = |i: usize| (i == 0 || lines[i - 1].is_empty())
+                           && lines.get(i + 1).is_none_or(|l| l.is_empty());
+
+    let mut res = Vec::new();
+    let mut para: Vec<&str> = Vec::new();
+    // Whether the paragraph is a single line with blank lines around it.
+    let mut para_alone = false;
+    let mut flush = |para: &mut Vec<&str>, para_alone: bool| {
+        if para.is_empty() {
+            return;
+        }
+        let level = heading_level(para[0], para_alone && para.len() == 1);
+        match level {
+            Some(n) if para.len() <= 3 && para.iter().all(|l| l.trim().chars().count() <= 60) => {
+                res.push(Block::Heading(n, para.iter().map(|l| l.trim().to_string()).collect()))
+            }
+            _ => res.push(Block::Para(unwrap(para))),
+        }
+        para.clear();
+    };
+    for (i, &l) in lines.iter().enumerate() {
+        if l.is_empty() {
+            flush(&mut para, para_alone);
+            continue;
+        }
+        if line_per_para || indent(l) > common || heading_level(l, alone(i)).is_some() {
+            flush(&mut para, para_alone);
+        }
+        if para.is_empty() {
+            para_alone = alone(i);
+        }
+        para.push(l);
+    }
+    flush(&mut para, para_alone);
+    res
+}
+
+// Convert the text into FB2.  The `name` (e.g. the name of the file)
+// is the title of the book unless the text says otherwise.
+pub fn to_fb2(data: &[u8], name: &str) -> Vec<u8> {
+    let text = decode(data).replace("\r\n", "\n");
+    let title = header_field(&text, "Title:").unwrap_or(name);
+    let authors: Vec<String> = header_field(&text, "Author:")
+                               .map(|a| a.to_string()).into_iter().collect();
+    let blocks = blocks(gutenberg_body(&text));
+
+    // If there are no parts, chapters are the top-level sections.
+    let has_parts = blocks.iter().any(|b| matches!(b, Block::Heading(1, _)));
+    let mut w = fb2::Writer::new(title, &authors,
+                                 &format!("txt:{:016x}", meta::content_hash(data)));
+    for b in blocks {
+        match b {
+            Block::Heading(n, t) => w.section(if has_parts { n } else { 1 }, None, &t),
+            Block::Para(p) => w.p(&p),
+        }
+    }
+    w.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The blocks as `# heading` (one `#` per level) and the paragraphs.
+    fn layout(text: &str) -> Vec<String> {
+        blocks(text).into_iter().map(|b| match b {
+            Block::Heading(n, t) => format!("{} {}", "#".repeat(n), t.join(" / ")),
+            Block::Para(p) => p,
+        }).collect()
+    }
+
+    #[test]
+    fn headings() {
+        assert_eq!(layout("CHAPTER 1\nThe Beginning\n\nIt was a dark night.\n"),
+                   ["## CHAPTER 1 / The Beginning", "It was a dark night."]);
+        assert_eq!(layout("Глава 5\n\nТекст.\n\nЧАСТЬ II\n\nЕщё текст.\n"),
+                   ["## Глава 5", "Текст.", "# ЧАСТЬ II", "Ещё текст."]);
+    }
+
+    // A bare number is a heading only if it stands alone.
+    #[test]
+    fn bare_numbers() {
+        assert_eq!(layout("The end of it.\n\nIV\n\nThe next one.\n"),
+                   ["The end of it.", "## IV", "The next one."]);
+        assert_eq!(layout("The list:\n12\napples in all.\n"),
+                   ["The list: 12 apples in all."]);
+        assert_eq!(layout("Some text\n12.\n\nMore text.\n"),
+                   ["Some text 12.", "More text."]);
+    }
+
+    #[test]
+    fn paragraphs() {
+        // Hard-wrapped lines are joined, hyphenated words too, and the
+        // blank lines separate the paragraphs.
+        assert_eq!(layout("One line\nand an-\nother.\n\nSecond one.\n"),
+                   ["One line and another.", "Second one."]);
+        // Without blank lines, the indent starts a paragraph.
+        assert_eq!(layout("  First para\nwrapped here.\n  Second para\nwrapped too.\n"),
+                   ["First para wrapped here.", "Second para wrapped too."]);
+    }
+}
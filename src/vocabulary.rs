@@ -0,0 +1,77 @@
+// Words that the reader marked as unknown while reading, and their
+// export for the `vocabulary export` subcommand.
+
+use serde::{
+    Serialize, Deserialize
+};
+use crate::BookState;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    // The dictionary form if we found the word in a dictionary,
+    // otherwise the word without punctuation.
+    pub word: String,
+    #[serde(default)]
+    pub definition: String,
+    // The sentence where the word was found.
+    pub context: String,
+    // Identity and name of the book, and the position of the word.
+    pub book: String,
+    pub title: String,
+    pub pos: BookState,
+    // Unix time when the word was added.
+    #[serde(default)]
+    pub added: u64,
+}
+
+// Add the entry unless we already have this word from this book.
+// Returns whether the entry was added.
+pub fn add(list: &mut Vec<Entry>, e: Entry) -> bool {
+    if list.iter().any(|x| x.word == e.word && x.book == e.book) {
+        return false;
+    }
+    list.push(e);
+    true
+}
+
+// Tab-separated values with a header: word, definition, context,
+// book and position.  Tabs and line breaks in the fields are
+// replaced with spaces.
+pub fn to_tsv(list: &[Entry]) -> String {
+    let field = |s: &str| s.split(['\t', '\n', '\r'])
+                           .map(str::trim).filter(|x| !x.is_empty())
+                           .collect::<Vec<_>>().join(" ");
+    let mut s = String::from("word\tdefinition\tcontext\tbook\tposition\n");
+    for e in list {
+        s.push_str(&format!("{}\t{}\t{}\t{}\t{}.{}.{}\n",
+                            field(&e.word), field(&e.definition), field(&e.context),
+                            field(&e.title), e.pos.tag_count, e.pos.word_offset,
+                            e.pos.char_offset));
+    }
+    s
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn csv_field(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\"\""))
+}
+
+// CSV for importing into Anki: the word on the front of the card, and
+// the definition, the context and the book on the back.  The header
+// lines tell Anki how to read the file.
+pub fn to_anki(list: &[Entry]) -> String {
+    let mut s = String::from("#separator:Comma\n#html:true\n#columns:Front,Back\n");
+    for e in list {
+        let mut back = html_escape(e.definition.trim()).replace('\n', "<br>");
+        if !back.is_empty() {
+            back.push_str("<br><br>");
+        }
+        back.push_str(&format!("<i>{}</i><br>— {}", html_escape(&e.context),
+                               html_escape(&e.title)));
+        s.push_str(&format!("{},{}\n", csv_field(&e.word), csv_field(&back)));
+    }
+    s
+}